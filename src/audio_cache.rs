@@ -0,0 +1,212 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::audio_codec::{self, PcmAudio};
+use crate::error::{VoicerError, VoicerResult};
+
+const DEFAULT_MAX_BYTES: u64 = 256 * 1024 * 1024;
+const INDEX_FILE: &str = "index.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CacheEntry {
+    // SHA-256 of the file's contents, which is also its name
+    hash: String,
+    size: u64,
+    last_used: DateTime<Utc>,
+}
+
+// Which cached file holds each note, keyed by the note's hex id.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct CacheIndex {
+    entries: HashMap<String, CacheEntry>,
+}
+
+impl CacheIndex {
+    // Notes with identical audio share a file, so it is only counted once
+    fn total_size(&self) -> u64 {
+        let mut seen = HashSet::new();
+        self.entries.values().filter(|entry| seen.insert(&entry.hash)).map(|entry| entry.size).sum()
+    }
+}
+
+// Playback-ready audio for one user's notes, kept as FLAC files under the OS
+// cache directory. Once it grows past `max_bytes` the least recently played
+// notes are evicted. Set the limit with `VOICER_AUDIO_CACHE_MB`.
+pub struct AudioCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    index: Mutex<CacheIndex>,
+    // Plays only touch the index in memory; it is written with the next
+    // insert or when the cache is dropped
+    dirty: AtomicBool,
+}
+
+impl AudioCache {
+    pub fn open(user_id: ObjectId) -> VoicerResult<Self> {
+        AudioCache::open_in(&dirs::cache_dir().unwrap_or_else(std::env::temp_dir), user_id)
+    }
+
+    // The cache as it would be kept under `root` instead of the OS cache directory.
+    pub fn open_in(root: &Path, user_id: ObjectId) -> VoicerResult<Self> {
+        let dir = root.join("voicer").join(user_id.to_hex()).join("audio");
+        fs::create_dir_all(&dir)?;
+
+        let mut index: CacheIndex = match fs::read(dir.join(INDEX_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|err| {
+                println!("Audio cache index is unreadable, starting afresh: {}", err);
+                CacheIndex::default()
+            }),
+            Err(_) => CacheIndex::default(),
+        };
+
+        // Forget entries whose file has gone, and delete files nothing points to
+        index.entries.retain(|_, entry| dir.join(entry_file(&entry.hash)).exists());
+        let referenced: HashSet<String> = index.entries.values().map(|entry| entry_file(&entry.hash)).collect();
+        for file in fs::read_dir(&dir)?.flatten() {
+            let name = file.file_name().to_string_lossy().to_string();
+            if name != INDEX_FILE && !referenced.contains(&name) {
+                let _ = fs::remove_file(file.path());
+            }
+        }
+
+        let cache = AudioCache { dir, max_bytes: max_bytes_from_env()?, index: Mutex::new(index), dirty: AtomicBool::new(false) };
+        cache.save(&cache.index.lock().unwrap())?;
+        Ok(cache)
+    }
+
+    pub fn contains(&self, v_id: ObjectId) -> bool {
+        self.index.lock().unwrap().entries.contains_key(&v_id.to_hex())
+    }
+
+    // The note's cached file, marking it as recently used.
+    pub fn path(&self, v_id: ObjectId) -> Option<PathBuf> {
+        let mut index = self.index.lock().unwrap();
+        let entry = index.entries.get_mut(&v_id.to_hex())?;
+        entry.last_used = Utc::now();
+        self.dirty.store(true, Ordering::SeqCst);
+        Some(self.dir.join(entry_file(&entry.hash)))
+    }
+
+    // Caches a note's playback audio, evicting older notes to stay under the limit.
+    // Encodes the audio, so call it off the UI thread.
+    pub fn insert(&self, v_id: ObjectId, audio: &PcmAudio) -> VoicerResult<PathBuf> {
+        let bytes = audio_codec::encode_flac(audio)?;
+        let hash = hex::encode(Sha256::digest(&bytes));
+        let path = self.dir.join(entry_file(&hash));
+        if !path.exists() {
+            // Written under a temporary name so a crash never leaves half a file behind
+            let partial = path.with_extension("part");
+            fs::write(&partial, &bytes)?;
+            fs::rename(&partial, &path)?;
+        }
+
+        let mut index = self.index.lock().unwrap();
+        let entry = CacheEntry { hash, size: bytes.len() as u64, last_used: Utc::now() };
+        if let Some(old) = index.entries.insert(v_id.to_hex(), entry) {
+            self.remove_if_unused(&index, &old.hash);
+        }
+        self.evict(&mut index, v_id);
+        self.save(&index)?;
+        Ok(path)
+    }
+
+    // Drops least recently used notes, never `keep`, until the cache fits.
+    fn evict(&self, index: &mut CacheIndex, keep: ObjectId) {
+        let keep = keep.to_hex();
+        while index.total_size() > self.max_bytes {
+            let oldest = index
+                .entries
+                .iter()
+                .filter(|(id, _)| **id != keep)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(id, _)| id.clone());
+            let oldest = match oldest {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if let Some(entry) = index.entries.remove(&oldest) {
+                self.remove_if_unused(index, &entry.hash);
+            }
+        }
+    }
+
+    fn remove_if_unused(&self, index: &CacheIndex, hash: &str) {
+        if !index.entries.values().any(|entry| entry.hash == hash) {
+            let _ = fs::remove_file(self.dir.join(entry_file(hash)));
+        }
+    }
+
+    fn save(&self, index: &CacheIndex) -> VoicerResult<()> {
+        let bytes = serde_json::to_vec(index).map_err(|err| VoicerError::Config(err.to_string()))?;
+        let partial = self.dir.join(INDEX_FILE).with_extension("part");
+        fs::write(&partial, bytes)?;
+        fs::rename(&partial, self.dir.join(INDEX_FILE))?;
+        self.dirty.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl Drop for AudioCache {
+    fn drop(&mut self) {
+        if self.dirty.load(Ordering::SeqCst) {
+            if let Err(err) = self.save(&self.index.lock().unwrap()) {
+                println!("Could not save the audio cache index: {}", err);
+            }
+        }
+    }
+}
+
+fn entry_file(hash: &str) -> String {
+    format!("{}.flac", hash)
+}
+
+fn max_bytes_from_env() -> VoicerResult<u64> {
+    match std::env::var("VOICER_AUDIO_CACHE_MB") {
+        Ok(value) => value
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|mb| *mb > 0)
+            .map(|mb| mb * 1024 * 1024)
+            .ok_or_else(|| VoicerError::Config("VOICER_AUDIO_CACHE_MB must be a whole number of megabytes".to_string())),
+        Err(_) => Ok(DEFAULT_MAX_BYTES),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index_on_disk(cache: &AudioCache) -> CacheIndex {
+        serde_json::from_slice(&fs::read(cache.dir.join(INDEX_FILE)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn plays_are_saved_when_the_cache_is_dropped() {
+        let root = std::env::temp_dir().join(format!("voicer-cache-{}", ObjectId::new().to_hex()));
+        let user_id = ObjectId::new();
+        let cache = AudioCache::open_in(&root, user_id).unwrap();
+        let v_id = ObjectId::new();
+        let audio = PcmAudio { samples: vec![100; 4410], channels: 1, sample_rate: 44100 };
+        let path = cache.insert(v_id, &audio).unwrap();
+        let inserted = index_on_disk(&cache).entries[&v_id.to_hex()].last_used;
+
+        assert_eq!(cache.path(v_id), Some(path));
+        assert_eq!(index_on_disk(&cache).entries[&v_id.to_hex()].last_used, inserted);
+        let played = cache.index.lock().unwrap().entries[&v_id.to_hex()].last_used;
+        drop(cache);
+
+        let reopened = AudioCache::open_in(&root, user_id).unwrap();
+        assert_eq!(reopened.index.lock().unwrap().entries[&v_id.to_hex()].last_used, played);
+        drop(reopened);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,390 @@
+use std::io::{Cursor, Read};
+use std::time::Duration;
+
+use flacenc::component::{BitRepr, MetadataBlockData};
+use flacenc::error::Verify;
+use rubato::{FftFixedIn, Resampler};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{VoicerError, VoicerResult};
+
+// Every note is stored in this format, whatever the microphone produced.
+pub const CANONICAL_CHANNELS: u16 = 1;
+pub const CANONICAL_SAMPLE_RATE: u32 = 44100;
+const RESAMPLE_CHUNK: usize = 1024;
+
+// Samples per FLAC frame (per channel).
+const BLOCK_SIZE: usize = 4096;
+const BITS_PER_SAMPLE: u32 = 16;
+// Metadata block type of a Vorbis comment.
+const VORBIS_COMMENT: u8 = 4;
+
+// Interleaved 16-bit PCM.
+#[derive(Debug, Clone)]
+pub struct PcmAudio {
+    pub samples: Vec<i16>,
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    Int,
+    Float,
+}
+
+// What a recording looked like before it was converted to the canonical format.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioFormat {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub bits_per_sample: u16,
+    pub sample_format: SampleFormat,
+}
+
+impl PcmAudio {
+    pub fn is_canonical(&self) -> bool {
+        self.channels == CANONICAL_CHANNELS && self.sample_rate == CANONICAL_SAMPLE_RATE
+    }
+
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+
+    pub fn duration(&self) -> Duration {
+        if self.sample_rate == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(self.frames() as f64 / self.sample_rate as f64)
+    }
+
+    // Loudest sample in each of `buckets` equal slices, 0.0 to 1.0, for drawing a waveform.
+    pub fn peaks(&self, buckets: usize) -> Vec<f32> {
+        self.ranges(buckets)
+            .into_iter()
+            .map(|(min, max)| (min as i32).abs().max((max as i32).abs()) as f32 / i16::MAX as f32)
+            .collect()
+    }
+
+    // Quietest and loudest sample in each of `buckets` equal slices. With more
+    // buckets than frames, neighbouring buckets repeat the same frame.
+    pub fn ranges(&self, buckets: usize) -> Vec<(i16, i16)> {
+        let channels = self.channels.max(1) as usize;
+        let frames = self.frames();
+        (0..buckets)
+            .map(|bucket| {
+                let start = bucket * frames / buckets.max(1);
+                let end = ((bucket + 1) * frames / buckets.max(1)).max(start + 1).min(frames);
+                let samples = &self.samples[(start * channels).min(self.samples.len())..end * channels];
+                let min = samples.iter().copied().min().unwrap_or(0);
+                let max = samples.iter().copied().max().unwrap_or(0);
+                (min, max)
+            })
+            .collect()
+    }
+
+    // Downmixes to mono and resamples to the canonical rate.
+    pub fn to_canonical(self) -> VoicerResult<PcmAudio> {
+        if self.is_canonical() {
+            return Ok(self);
+        }
+        if self.channels == 0 || self.sample_rate == 0 {
+            return Err(VoicerError::Audio(format!("Invalid audio: {} channels at {} Hz", self.channels, self.sample_rate)));
+        }
+        let channels = self.channels as usize;
+        let mono: Vec<f32> = self.samples
+            .chunks_exact(channels)
+            .map(|frame| frame.iter().map(|sample| *sample as f32).sum::<f32>() / channels as f32)
+            .collect();
+        let resampled = if self.sample_rate == CANONICAL_SAMPLE_RATE {
+            mono
+        } else {
+            resample(&mono, self.sample_rate, CANONICAL_SAMPLE_RATE)?
+        };
+        let samples = resampled
+            .into_iter()
+            .map(|sample| sample.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+            .collect();
+        Ok(PcmAudio { samples: samples, channels: CANONICAL_CHANNELS, sample_rate: CANONICAL_SAMPLE_RATE })
+    }
+}
+
+fn resample(samples: &[f32], from: u32, to: u32) -> VoicerResult<Vec<f32>> {
+    let resample_error = |err: String| VoicerError::Audio(format!("Could not resample {} Hz to {} Hz: {}", from, to, err));
+    let mut resampler = FftFixedIn::<f32>::new(from as usize, to as usize, RESAMPLE_CHUNK, 2, 1)
+        .map_err(|err| resample_error(err.to_string()))?;
+    let delay = resampler.output_delay();
+    let expected = (samples.len() as u64 * to as u64 / from as u64) as usize;
+
+    let mut out = Vec::with_capacity(expected + delay);
+    let mut position = 0;
+    while position < samples.len() {
+        let needed = resampler.input_frames_next();
+        let end = (position + needed).min(samples.len());
+        let chunk = [&samples[position..end]];
+        let frames = if end - position == needed {
+            resampler.process(&chunk, None)
+        } else {
+            resampler.process_partial(Some(&chunk), None)
+        };
+        out.extend_from_slice(&frames.map_err(|err| resample_error(err.to_string()))?[0]);
+        position = end;
+    }
+    // Flush what is still inside the filter, then drop its latency from the front
+    while out.len() < expected + delay {
+        let frames = resampler.process_partial::<&[f32]>(None, None).map_err(|err| resample_error(err.to_string()))?;
+        if frames[0].is_empty() {
+            break;
+        }
+        out.extend_from_slice(&frames[0]);
+    }
+    out.drain(..delay.min(out.len()));
+    out.truncate(expected);
+    Ok(out)
+}
+
+pub fn encode_flac(audio: &PcmAudio) -> VoicerResult<Vec<u8>> {
+    encode_flac_tagged(audio, &[])
+}
+
+// Same as `encode_flac`, with `(field, value)` pairs such as ("ARTIST", ..) in a
+// Vorbis comment block.
+pub fn encode_flac_tagged(audio: &PcmAudio, tags: &[(&str, String)]) -> VoicerResult<Vec<u8>> {
+    let channels = audio.channels as usize;
+    if channels == 0 || channels > 8 {
+        return Err(VoicerError::Audio(format!("FLAC can't store {} channels", channels)));
+    }
+    if audio.sample_rate == 0 || audio.sample_rate >= 1 << 20 {
+        return Err(VoicerError::Audio(format!("FLAC can't store a sample rate of {}", audio.sample_rate)));
+    }
+    let encode_error = |err: String| VoicerError::Audio(format!("Could not encode FLAC: {}", err));
+    let config = flacenc::config::Encoder::default()
+        .into_verified()
+        .map_err(|(_, err)| encode_error(err.to_string()))?;
+    let samples: Vec<i32> = audio.samples.iter().map(|sample| *sample as i32).collect();
+    let source = flacenc::source::MemSource::from_samples(&samples, channels, BITS_PER_SAMPLE as usize, audio.sample_rate as usize);
+    let mut stream = flacenc::encode_with_fixed_block_size(&config, source, BLOCK_SIZE)
+        .map_err(|err| encode_error(err.to_string()))?;
+    // flacenc counts the short last frame as the minimum block size, which the
+    // spec leaves out and claxon refuses below 16
+    stream
+        .stream_info_mut()
+        .set_block_sizes(BLOCK_SIZE, BLOCK_SIZE)
+        .map_err(|err| encode_error(err.to_string()))?;
+    if !tags.is_empty() {
+        let comments = MetadataBlockData::new_unknown(VORBIS_COMMENT, &vorbis_comment(tags))
+            .map_err(|err| encode_error(err.to_string()))?;
+        stream.add_metadata_block(comments);
+    }
+
+    let mut sink = flacenc::bitsink::ByteSink::new();
+    stream.write(&mut sink).map_err(|err| encode_error(err.to_string()))?;
+    Ok(sink.into_inner())
+}
+
+pub fn decode_flac(bytes: &[u8]) -> VoicerResult<PcmAudio> {
+    let mut reader = claxon::FlacReader::new(Cursor::new(bytes)).map_err(flac_error)?;
+    let info = reader.streaminfo();
+    if info.bits_per_sample != BITS_PER_SAMPLE {
+        return Err(VoicerError::Audio(format!("Expected 16-bit FLAC, got {}-bit", info.bits_per_sample)));
+    }
+    let samples = reader
+        .samples()
+        .map(|sample| sample.map(|sample| sample as i16))
+        .collect::<Result<Vec<i16>, claxon::Error>>()
+        .map_err(flac_error)?;
+    Ok(PcmAudio { samples: samples, channels: info.channels as u16, sample_rate: info.sample_rate })
+}
+
+// Decodes FLAC a block at a time as it is read, so playback can start before
+// the whole file has arrived. `on_length` gets the length from the header, then
+// `on_block` each block's interleaved samples. Only used for stored audio, so
+// anything but the canonical format is an error.
+pub fn decode_flac_blocks(reader: impl Read, on_length: impl FnOnce(Duration), mut on_block: impl FnMut(&[i16])) -> VoicerResult<()> {
+    let mut reader = claxon::FlacReader::new(reader).map_err(flac_error)?;
+    let info = reader.streaminfo();
+    if info.bits_per_sample != BITS_PER_SAMPLE || info.channels != CANONICAL_CHANNELS as u32 || info.sample_rate != CANONICAL_SAMPLE_RATE {
+        return Err(VoicerError::Audio(format!(
+            "Expected 16-bit {}-channel FLAC at {} Hz, got {}-bit {}-channel at {} Hz",
+            CANONICAL_CHANNELS, CANONICAL_SAMPLE_RATE, info.bits_per_sample, info.channels, info.sample_rate
+        )));
+    }
+    if let Some(frames) = info.samples {
+        on_length(Duration::from_secs_f64(frames as f64 / info.sample_rate as f64));
+    }
+
+    let channels = info.channels;
+    let mut blocks = reader.blocks();
+    let mut buffer = Vec::new();
+    let mut samples = Vec::new();
+    while let Some(block) = blocks.read_next_or_eof(buffer).map_err(flac_error)? {
+        samples.clear();
+        for i in 0..block.duration() {
+            for channel in 0..channels {
+                samples.push(block.sample(channel, i) as i16);
+            }
+        }
+        on_block(&samples);
+        buffer = block.into_buffer();
+    }
+    Ok(())
+}
+
+// Length of encoded audio, read from the FLAC header without decoding it.
+// A header length of 0 means "unknown", which is also how an empty clip is
+// written, so then the frames are counted instead.
+pub fn flac_duration(bytes: &[u8]) -> VoicerResult<Duration> {
+    let mut reader = claxon::FlacReader::new(Cursor::new(bytes)).map_err(flac_error)?;
+    let info = reader.streaminfo();
+    if info.sample_rate == 0 {
+        return Err(VoicerError::Audio("FLAC header has no sample rate".to_string()));
+    }
+    let frames = match info.samples {
+        Some(frames) => frames,
+        None => {
+            let mut frames = 0;
+            let mut blocks = reader.blocks();
+            let mut buffer = Vec::new();
+            while let Some(block) = blocks.read_next_or_eof(buffer).map_err(flac_error)? {
+                frames += block.duration() as u64;
+                buffer = block.into_buffer();
+            }
+            frames
+        }
+    };
+    Ok(Duration::from_secs_f64(frames as f64 / info.sample_rate as f64))
+}
+
+fn vorbis_comment(tags: &[(&str, String)]) -> Vec<u8> {
+    // Vorbis comment lengths are little-endian, unlike the rest of FLAC
+    let mut body = Vec::new();
+    let vendor = b"Voicer";
+    body.extend((vendor.len() as u32).to_le_bytes());
+    body.extend(vendor);
+    body.extend((tags.len() as u32).to_le_bytes());
+    for (field, value) in tags {
+        let comment = format!("{}={}", field, value);
+        body.extend((comment.len() as u32).to_le_bytes());
+        body.extend(comment.as_bytes());
+    }
+    body
+}
+
+fn flac_error(err: claxon::Error) -> VoicerError {
+    VoicerError::Audio(format!("Could not decode FLAC: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(samples: Vec<i16>, channels: u16) {
+        let audio = PcmAudio { samples, channels, sample_rate: CANONICAL_SAMPLE_RATE };
+        let decoded = decode_flac(&encode_flac(&audio).unwrap()).unwrap();
+        assert_eq!(decoded.channels, channels);
+        assert_eq!(decoded.sample_rate, CANONICAL_SAMPLE_RATE);
+        assert_eq!(decoded.samples, audio.samples);
+    }
+
+    // Deterministic noise, which the fixed predictors can't do much with
+    fn noise(count: usize) -> Vec<i16> {
+        let mut state = 12345u32;
+        (0..count)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as i16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mono_speech_and_noise_survive_encoding() {
+        // Longer than one block, with a partial last block
+        let speech: Vec<i16> = (0..BLOCK_SIZE * 2 + 100).map(|i| ((i as f32 * 0.03).sin() * 12000.0) as i16).collect();
+        round_trip(speech, 1);
+        round_trip(noise(BLOCK_SIZE + 1), 1);
+    }
+
+    #[test]
+    fn stereo_keeps_its_channels_apart() {
+        let samples = (0..5000).flat_map(|i| [(i % 700) as i16 * 40, -((i % 300) as i16) * 90]).collect();
+        round_trip(samples, 2);
+        round_trip(noise(6000), 2);
+    }
+
+    #[test]
+    fn extreme_samples_survive_encoding() {
+        let samples = (0..3000).map(|i| if i % 2 == 0 { i16::MAX } else { i16::MIN }).collect();
+        round_trip(samples, 1);
+        round_trip(vec![i16::MIN; 2000], 1);
+        round_trip(vec![0; 2000], 1);
+    }
+
+    #[test]
+    fn single_sample_survives_encoding() {
+        round_trip(vec![-7], 1);
+        round_trip(vec![i16::MAX], 1);
+    }
+
+    #[test]
+    fn duration_comes_from_the_header() {
+        let audio = PcmAudio { samples: vec![1; 44100 * 3 / 2], channels: 1, sample_rate: CANONICAL_SAMPLE_RATE };
+        assert_eq!(flac_duration(&encode_flac(&audio).unwrap()).unwrap(), Duration::from_millis(1500));
+
+        let stereo = PcmAudio { samples: vec![1; 22050 * 2], channels: 2, sample_rate: 22050 };
+        assert_eq!(flac_duration(&encode_flac(&stereo).unwrap()).unwrap(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn empty_clip_has_zero_duration() {
+        let audio = PcmAudio { samples: Vec::new(), channels: 1, sample_rate: CANONICAL_SAMPLE_RATE };
+        let bytes = encode_flac(&audio).unwrap();
+        assert_eq!(flac_duration(&bytes).unwrap(), Duration::ZERO);
+        assert!(decode_flac(&bytes).unwrap().samples.is_empty());
+    }
+
+    #[test]
+    fn tags_do_not_disturb_the_audio() {
+        let audio = PcmAudio { samples: noise(500), channels: 1, sample_rate: CANONICAL_SAMPLE_RATE };
+        let bytes = encode_flac_tagged(&audio, &[("ARTIST", "Ada".to_string())]).unwrap();
+        assert_eq!(decode_flac(&bytes).unwrap().samples, audio.samples);
+        let reader = claxon::FlacReader::new(Cursor::new(&bytes)).unwrap();
+        assert_eq!(reader.get_tag("ARTIST").collect::<Vec<_>>(), vec!["Ada"]);
+    }
+
+    #[test]
+    fn canonical_audio_is_left_alone() {
+        let audio = PcmAudio { samples: noise(100), channels: CANONICAL_CHANNELS, sample_rate: CANONICAL_SAMPLE_RATE };
+        assert_eq!(audio.clone().to_canonical().unwrap().samples, audio.samples);
+    }
+
+    #[test]
+    fn stereo_is_averaged_to_mono() {
+        let audio = PcmAudio { samples: vec![1000, 3000, -500, 500, i16::MAX, i16::MAX], channels: 2, sample_rate: CANONICAL_SAMPLE_RATE };
+        let canonical = audio.to_canonical().unwrap();
+        assert_eq!(canonical.channels, CANONICAL_CHANNELS);
+        assert_eq!(canonical.samples, vec![2000, 0, i16::MAX]);
+    }
+
+    #[test]
+    fn resampling_keeps_the_length_and_the_tone() {
+        // One second of 440 Hz at 48 kHz
+        let samples = (0..48000).map(|i| ((i as f32 * 440.0 * std::f32::consts::TAU / 48000.0).sin() * 10000.0) as i16).collect();
+        let audio = PcmAudio { samples, channels: 1, sample_rate: 48000 };
+        let canonical = audio.to_canonical().unwrap();
+        assert_eq!(canonical.sample_rate, CANONICAL_SAMPLE_RATE);
+        assert_eq!(canonical.samples.len(), 44100);
+        // Away from the edges the tone keeps its level
+        let middle = &canonical.samples[10000..30000];
+        let peak = middle.iter().map(|sample| sample.unsigned_abs()).max().unwrap();
+        assert!((9500..=10500).contains(&peak), "peak was {}", peak);
+    }
+
+    #[test]
+    fn audio_without_channels_or_rate_is_refused() {
+        let no_channels = PcmAudio { samples: vec![1, 2], channels: 0, sample_rate: 44100 };
+        assert!(no_channels.to_canonical().is_err());
+        let no_rate = PcmAudio { samples: vec![1, 2], channels: 1, sample_rate: 0 };
+        assert!(no_rate.to_canonical().is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use std::sync::mpsc::{self, Sender};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures_util::io::{AsyncReadExt, Cursor};
+use mongodb::bson::{oid::ObjectId, spec::BinarySubtype, Binary, Bson};
+use mongodb::gridfs::GridFsBucket;
+use serde::{Deserialize, Serialize};
+
+use crate::audio_codec::{self, PcmAudio};
+use crate::audio_stream::{self, AudioStream, ChunkReader};
+use crate::error::{VoicerError, VoicerResult};
+use crate::loudness;
+use crate::waveform::{Waveform, WAVEFORM_BUCKETS};
+
+// Encoded blobs up to this size live in the voice note itself; larger ones go
+// to GridFS so long notes stay clear of MongoDB's 16 MB document limit.
+pub const INLINE_LIMIT: usize = 1024 * 1024;
+// GridFS downloads are passed on to the decoder in pieces this size
+const STREAM_CHUNK: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    Flac,
+}
+
+// Where a voice note's encoded audio is kept.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "storage")]
+pub enum AudioBlob {
+    Inline { codec: AudioCodec, bytes: Binary },
+    GridFs { codec: AudioCodec, file_id: ObjectId },
+}
+
+impl AudioBlob {
+    pub fn codec(&self) -> AudioCodec {
+        match self {
+            AudioBlob::Inline { codec, .. } => *codec,
+            AudioBlob::GridFs { codec, .. } => *codec,
+        }
+    }
+}
+
+#[async_trait]
+pub trait AudioStorage: Send + Sync {
+    async fn put(&self, voice_id: ObjectId, codec: AudioCodec, bytes: Vec<u8>) -> VoicerResult<AudioBlob>;
+    async fn fetch(&self, blob: &AudioBlob) -> VoicerResult<Vec<u8>>;
+    async fn remove(&self, blob: &AudioBlob) -> VoicerResult<()>;
+
+    // Sends the blob's bytes in pieces as they arrive. Dropping `chunks` marks the end.
+    async fn fetch_chunks(&self, blob: &AudioBlob, chunks: Sender<Vec<u8>>) -> VoicerResult<()> {
+        let _ = chunks.send(self.fetch(blob).await?);
+        Ok(())
+    }
+}
+
+// Keeps every blob inside the note. Used by the in-memory store.
+pub struct InlineStorage;
+
+#[async_trait]
+impl AudioStorage for InlineStorage {
+    async fn put(&self, _voice_id: ObjectId, codec: AudioCodec, bytes: Vec<u8>) -> VoicerResult<AudioBlob> {
+        Ok(inline_blob(codec, bytes))
+    }
+
+    async fn fetch(&self, blob: &AudioBlob) -> VoicerResult<Vec<u8>> {
+        match blob {
+            AudioBlob::Inline { bytes, .. } => Ok(bytes.bytes.clone()),
+            AudioBlob::GridFs { .. } => Err(VoicerError::Config("GridFS audio needs a MongoDB store".to_string())),
+        }
+    }
+
+    async fn remove(&self, _blob: &AudioBlob) -> VoicerResult<()> {
+        Ok(())
+    }
+}
+
+// Inlines small blobs and streams larger ones into a GridFS bucket.
+pub struct GridFsStorage {
+    bucket: GridFsBucket,
+}
+
+impl GridFsStorage {
+    pub fn new(bucket: GridFsBucket) -> Self {
+        GridFsStorage { bucket }
+    }
+}
+
+#[async_trait]
+impl AudioStorage for GridFsStorage {
+    async fn put(&self, voice_id: ObjectId, codec: AudioCodec, bytes: Vec<u8>) -> VoicerResult<AudioBlob> {
+        if bytes.len() <= INLINE_LIMIT {
+            return Ok(inline_blob(codec, bytes));
+        }
+        let file_id = ObjectId::new();
+        let filename = format!("{}.flac", voice_id.to_hex());
+        self.bucket
+            .upload_from_futures_0_3_reader_with_id(Bson::ObjectId(file_id), filename, Cursor::new(bytes), None)
+            .await?;
+        Ok(AudioBlob::GridFs { codec, file_id })
+    }
+
+    async fn fetch(&self, blob: &AudioBlob) -> VoicerResult<Vec<u8>> {
+        match blob {
+            AudioBlob::Inline { bytes, .. } => Ok(bytes.bytes.clone()),
+            AudioBlob::GridFs { file_id, .. } => {
+                let mut bytes = Vec::new();
+                self.bucket.download_to_futures_0_3_writer(Bson::ObjectId(*file_id), &mut bytes).await?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    async fn remove(&self, blob: &AudioBlob) -> VoicerResult<()> {
+        if let AudioBlob::GridFs { file_id, .. } = blob {
+            self.bucket.delete(Bson::ObjectId(*file_id)).await?;
+        }
+        Ok(())
+    }
+
+    async fn fetch_chunks(&self, blob: &AudioBlob, chunks: Sender<Vec<u8>>) -> VoicerResult<()> {
+        let file_id = match blob {
+            AudioBlob::GridFs { file_id, .. } => *file_id,
+            AudioBlob::Inline { bytes, .. } => {
+                let _ = chunks.send(bytes.bytes.clone());
+                return Ok(());
+            }
+        };
+        let mut download = self.bucket.open_download_stream(Bson::ObjectId(file_id)).await?;
+        let mut buffer = vec![0; STREAM_CHUNK];
+        loop {
+            let read = download.read(&mut buffer).await?;
+            // The decoder only hangs up if it has already failed
+            if read == 0 || chunks.send(buffer[..read].to_vec()).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn inline_blob(codec: AudioCodec, bytes: Vec<u8>) -> AudioBlob {
+    AudioBlob::Inline { codec, bytes: Binary { subtype: BinarySubtype::Generic, bytes } }
+}
+
+// What gets saved on a note for its audio.
+pub struct StoredAudio {
+    pub blob: AudioBlob,
+    // Integrated loudness in LUFS, None if the recording is silent
+    pub loudness: Option<f64>,
+    pub waveform: Waveform,
+    pub duration: Duration,
+}
+
+// Converts a recording to the canonical format, encodes it and hands it to `storage`.
+// Loudness and the waveform are measured along the way.
+pub async fn store_samples(storage: &dyn AudioStorage, voice_id: ObjectId, audio: PcmAudio) -> VoicerResult<StoredAudio> {
+    let (bytes, loudness, waveform, duration) = tokio::task::spawn_blocking(move || -> VoicerResult<_> {
+        let canonical = audio.to_canonical()?;
+        let duration = canonical.duration();
+        let waveform = Waveform::from_audio(&canonical, WAVEFORM_BUCKETS);
+        Ok((audio_codec::encode_flac(&canonical)?, loudness::integrated_loudness(&canonical), waveform, duration))
+    })
+    .await
+    .map_err(|err| VoicerError::Audio(err.to_string()))??;
+    let blob = storage.put(voice_id, AudioCodec::Flac, bytes).await?;
+    Ok(StoredAudio { blob, loudness, waveform, duration })
+}
+
+// Decodes a blob into `stream` while it downloads, at the playback loudness if
+// `loudness` is given. The stream is finished when this returns, even on error.
+pub async fn stream_samples(storage: &dyn AudioStorage, blob: &AudioBlob, loudness: Option<f64>, stream: AudioStream) -> VoicerResult<()> {
+    let (sender, receiver) = mpsc::channel();
+    let writer = stream.clone();
+    let decoder = tokio::task::spawn_blocking(move || audio_stream::decode_into(ChunkReader::new(receiver), loudness, &writer));
+    let fetched = storage.fetch_chunks(blob, sender).await;
+    let decoded = decoder.await.map_err(|err| VoicerError::Audio(err.to_string()));
+    stream.finish();
+    // A failed download also breaks the decode, so report it first
+    fetched?;
+    decoded??;
+    Ok(())
+}
+
+pub async fn load_samples(storage: &dyn AudioStorage, blob: &AudioBlob) -> VoicerResult<PcmAudio> {
+    let bytes = storage.fetch(blob).await?;
+    let audio = match blob.codec() {
+        AudioCodec::Flac => tokio::task::spawn_blocking(move || audio_codec::decode_flac(&bytes))
+            .await
+            .map_err(|err| VoicerError::Audio(err.to_string()))??,
+    };
+    Ok(audio)
+}
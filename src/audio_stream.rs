@@ -0,0 +1,289 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::audio_codec::{self, PcmAudio, CANONICAL_CHANNELS, CANONICAL_SAMPLE_RATE};
+use crate::error::VoicerResult;
+use crate::loudness::Normalizer;
+
+struct StreamState {
+    samples: Vec<i16>,
+    // Length from the file header, until the whole clip has arrived
+    expected: Option<Duration>,
+    finished: bool,
+}
+
+// What a read found at the requested position.
+pub enum StreamRead {
+    Samples(Vec<i16>),
+    // Not arrived yet
+    Pending,
+    End,
+}
+
+// One sample for the audio output, or why there isn't one yet.
+pub enum SampleRead {
+    Sample(i16),
+    Pending,
+    End,
+}
+
+// Samples of a clip that may still be arriving. The player reads from one end
+// while a download or decoder appends to the other; clones share the buffer.
+#[derive(Clone)]
+pub struct AudioStream {
+    state: Arc<Mutex<StreamState>>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl AudioStream {
+    pub fn new(channels: u16, sample_rate: u32) -> Self {
+        let state = StreamState { samples: Vec::new(), expected: None, finished: false };
+        AudioStream { state: Arc::new(Mutex::new(state)), channels: channels.max(1), sample_rate }
+    }
+
+    // Stored and cached notes are always canonical.
+    pub fn for_note() -> Self {
+        AudioStream::new(CANONICAL_CHANNELS, CANONICAL_SAMPLE_RATE)
+    }
+
+    // A stream that has already arrived in full, e.g. a draft being previewed.
+    pub fn from_audio(audio: PcmAudio) -> Self {
+        let stream = AudioStream::new(audio.channels, audio.sample_rate);
+        stream.push(&audio.samples);
+        stream.finish();
+        stream
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_expected(&self, length: Duration) {
+        self.state.lock().unwrap().expected = Some(length);
+    }
+
+    pub fn push(&self, samples: &[i16]) {
+        self.state.lock().unwrap().samples.extend_from_slice(samples);
+    }
+
+    // No more samples are coming, either because the clip is complete or the
+    // download failed; the player plays what arrived and stops.
+    pub fn finish(&self) {
+        self.state.lock().unwrap().finished = true;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().unwrap().finished
+    }
+
+    fn frames_to_duration(&self, samples: usize) -> Duration {
+        let frames = samples / self.channels as usize;
+        Duration::from_secs_f64(frames as f64 / self.sample_rate.max(1) as f64)
+    }
+
+    // Frame count, once the whole clip has arrived.
+    pub fn total_frames(&self) -> Option<usize> {
+        let state = self.state.lock().unwrap();
+        if state.finished { Some(state.samples.len() / self.channels as usize) } else { None }
+    }
+
+    // How much has arrived so far.
+    pub fn buffered(&self) -> Duration {
+        self.frames_to_duration(self.state.lock().unwrap().samples.len())
+    }
+
+    // The full length if it is known yet, otherwise how much has arrived.
+    pub fn duration(&self) -> Duration {
+        let state = self.state.lock().unwrap();
+        match state.expected {
+            Some(expected) if !state.finished => expected,
+            _ => self.frames_to_duration(state.samples.len()),
+        }
+    }
+
+    // Up to `max` samples from `start`.
+    pub fn read(&self, start: usize, max: usize) -> StreamRead {
+        let state = self.state.lock().unwrap();
+        if start < state.samples.len() {
+            let end = (start + max).min(state.samples.len());
+            StreamRead::Samples(state.samples[start..end].to_vec())
+        } else if state.finished {
+            StreamRead::End
+        } else {
+            StreamRead::Pending
+        }
+    }
+
+    // Frames `start..end`, or None until they have all arrived. Past the end
+    // of a finished stream reads as silence.
+    pub fn frames(&self, start: usize, end: usize) -> Option<Vec<i16>> {
+        let channels = self.channels as usize;
+        let state = self.state.lock().unwrap();
+        if end * channels > state.samples.len() && !state.finished {
+            return None;
+        }
+        let available = state.samples.len().min(end * channels);
+        let mut frames = state.samples.get(start * channels..available).unwrap_or(&[]).to_vec();
+        frames.resize((end - start) * channels, 0);
+        Some(frames)
+    }
+
+    // Everything that has arrived, as a clip.
+    pub fn to_audio(&self) -> PcmAudio {
+        let samples = self.state.lock().unwrap().samples.clone();
+        PcmAudio { samples: samples, channels: self.channels, sample_rate: self.sample_rate }
+    }
+}
+
+// Decodes stored FLAC into `stream` as it is read, bringing it to the playback
+// loudness if `loudness` is given. Finishes the stream either way.
+pub fn decode_into(reader: impl Read, loudness: Option<f64>, stream: &AudioStream) -> VoicerResult<()> {
+    let mut normalizer = loudness.map(|loudness| Normalizer::new(loudness, stream.channels(), stream.sample_rate()));
+    let result = audio_codec::decode_flac_blocks(
+        reader,
+        |length| stream.set_expected(length),
+        |block| match normalizer.as_mut() {
+            Some(normalizer) => stream.push(&normalizer.process(block)),
+            None => stream.push(block),
+        },
+    );
+    if let Some(mut normalizer) = normalizer {
+        stream.push(&normalizer.finish());
+    }
+    stream.finish();
+    result
+}
+
+// Streams a cached note from disk, decoding on its own thread so playback
+// starts straight away.
+pub fn stream_file(path: &Path) -> VoicerResult<AudioStream> {
+    let stream = AudioStream::for_note();
+    fill_from_file(path, &stream)?;
+    Ok(stream)
+}
+
+// Same as stream_file, into a stream the player already has.
+pub fn fill_from_file(path: &Path, stream: &AudioStream) -> VoicerResult<()> {
+    let file = File::open(path)?;
+    let writer = stream.clone();
+    thread::spawn(move || {
+        // Cached audio is already at the playback loudness
+        if let Err(err) = decode_into(BufReader::new(file), None, &writer) {
+            println!("Could not decode cached audio: {}", err);
+        }
+    });
+    Ok(())
+}
+
+// Reads bytes as they are sent from a download. Ends when the sender is dropped.
+pub struct ChunkReader {
+    chunks: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl ChunkReader {
+    pub fn new(chunks: Receiver<Vec<u8>>) -> Self {
+        ChunkReader { chunks, chunk: Vec::new(), position: 0 }
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position >= self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let count = buf.len().min(self.chunk.len() - self.position);
+        buf[..count].copy_from_slice(&self.chunk[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::mpsc;
+
+    #[test]
+    fn reads_wait_for_samples_until_the_stream_finishes() {
+        let stream = AudioStream::new(2, 1000);
+        assert!(matches!(stream.read(0, 10), StreamRead::Pending));
+        stream.push(&[1, 2, 3, 4]);
+        assert!(matches!(stream.read(2, 10), StreamRead::Samples(samples) if samples == vec![3, 4]));
+        assert!(matches!(stream.read(4, 10), StreamRead::Pending));
+        assert_eq!(stream.total_frames(), None);
+        stream.finish();
+        assert!(matches!(stream.read(4, 10), StreamRead::End));
+        assert_eq!(stream.total_frames(), Some(2));
+    }
+
+    #[test]
+    fn frame_ranges_are_padded_with_silence_past_the_end() {
+        let stream = AudioStream::new(1, 1000);
+        stream.push(&[1, 2, 3]);
+        assert_eq!(stream.frames(1, 3), Some(vec![2, 3]));
+        assert_eq!(stream.frames(2, 5), None);
+        stream.finish();
+        assert_eq!(stream.frames(2, 5), Some(vec![3, 0, 0]));
+    }
+
+    #[test]
+    fn the_header_length_stands_in_until_everything_arrives() {
+        let stream = AudioStream::new(1, 1000);
+        stream.set_expected(Duration::from_secs(2));
+        stream.push(&[0; 500]);
+        assert_eq!(stream.duration(), Duration::from_secs(2));
+        assert_eq!(stream.buffered(), Duration::from_millis(500));
+        stream.finish();
+        assert_eq!(stream.duration(), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn stored_flac_decodes_into_the_stream() {
+        let samples: Vec<i16> = (0..10000).map(|i| ((i as f32 * 0.01).sin() * 5000.0) as i16).collect();
+        let audio = PcmAudio { samples: samples.clone(), channels: CANONICAL_CHANNELS, sample_rate: CANONICAL_SAMPLE_RATE };
+        let bytes = audio_codec::encode_flac(&audio).unwrap();
+
+        let stream = AudioStream::for_note();
+        decode_into(Cursor::new(bytes), None, &stream).unwrap();
+        assert!(stream.is_finished());
+        assert_eq!(stream.to_audio().samples, samples);
+    }
+
+    #[test]
+    fn broken_downloads_still_finish_the_stream() {
+        let stream = AudioStream::for_note();
+        assert!(decode_into(Cursor::new(b"not flac".to_vec()), None, &stream).is_err());
+        assert!(stream.is_finished());
+    }
+
+    #[test]
+    fn chunks_are_read_in_order() {
+        let (sender, receiver) = mpsc::channel();
+        sender.send(vec![1, 2, 3]).unwrap();
+        sender.send(Vec::new()).unwrap();
+        sender.send(vec![4]).unwrap();
+        drop(sender);
+        let mut bytes = Vec::new();
+        ChunkReader::new(receiver).read_to_end(&mut bytes).unwrap();
+        assert_eq!(bytes, vec![1, 2, 3, 4]);
+    }
+}
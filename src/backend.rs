@@ -1,31 +1,51 @@
-use mongodb::bson::datetime::Error;
-use mongodb::{Client, Collection  , Database};
-use mongodb::options::FindOneAndUpdateOptions;
+use mongodb::{Client, ClientSession, Collection  , Database};
+use mongodb::error::{TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT};
 use mongodb::bson::{self,oid::ObjectId, doc};
-use mongodb::options::UpdateOptions;
+use mongodb::options::{ClientOptions, FindOptions, IndexOptions, UpdateOptions};
+use mongodb::IndexModel;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::io;
+use std::time::Duration;
 use futures_util::StreamExt;
 use dotenv::dotenv;
 
+use crate::audio_codec::{self, AudioFormat, PcmAudio, SampleFormat, CANONICAL_CHANNELS, CANONICAL_SAMPLE_RATE};
+use crate::audio_storage::{self, AudioBlob, AudioStorage};
+use crate::audio_stream::AudioStream;
+use crate::error::{VoicerError, VoicerResult};
+use crate::limits::LengthLimits;
+use crate::loudness;
+use crate::password::{self, PasswordCheck};
+use crate::session::{self, Session};
+use crate::waveform::Waveform;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Users {
     pub _id: bson::oid::ObjectId,
     pub username:String,
-    pub password: String,
+    // None for profiles copied from elsewhere, which can't be logged into
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
     pub name: String,
     pub description: String,
     // pub verified: bool,
     pub followers:Vec<ObjectId>,
     pub following:Vec<ObjectId>,
-    pub voice_notes:Vec<ObjectId>
+    pub voice_notes:Vec<ObjectId>,
+    #[serde(default = "default_playback_speed")]
+    pub playback_speed: f32,
+}
+
+fn default_playback_speed() -> f32 {
+    1.0
 }
 
 impl Users {
-    pub async fn insert_one(&self, collection: Collection<Users>) {
+    pub async fn insert_one(&self, collection: Collection<Users>) -> VoicerResult<()> {
         let new_user = self.clone();
-        collection.insert_one(new_user, None).await.unwrap();
+        collection.insert_one(new_user, None).await?;
+        Ok(())
     }
 }
 
@@ -37,9 +57,9 @@ pub enum ReactionType{
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct Reaction{
-    user_id:ObjectId,
+    pub user_id:ObjectId,
     #[serde(rename = "ReactionType")]
-    reaction: ReactionType
+    pub reaction: ReactionType
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -48,12 +68,74 @@ pub struct VoiceNote {
     pub user_id: ObjectId,
     pub name: String,
     pub is_post: bool,
+    // Raw samples from before audio was encoded; only read to migrate old notes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub data: Vec<i16>,
+    #[serde(default)]
+    pub audio: Option<AudioBlob>,
+    // How the note was recorded; the stored audio is always canonical
+    #[serde(default)]
+    pub source_format: Option<AudioFormat>,
+    // Integrated loudness in LUFS; None for silence and notes saved before it was measured
+    #[serde(default)]
+    pub loudness: Option<f64>,
+    #[serde(default)]
+    pub waveform: Option<Waveform>,
+    // Length in milliseconds; None until backfilled for notes saved before it was stored
+    #[serde(default)]
+    pub duration_ms: Option<u64>,
     pub replies: Vec<ObjectId>,
     pub reactions: Vec<Reaction>,
     #[serde(with = "chrono::serde::ts_seconds")]
     pub timestamp: DateTime<Utc>,
 }
+pub const FEED_PAGE_SIZE: i64 = 20;
+
+// A voice note without its audio, as listed in the feed.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FeedItem {
+    pub _id: ObjectId,
+    pub user_id: ObjectId,
+    pub name: String,
+    pub replies: Vec<ObjectId>,
+    pub reactions: Vec<Reaction>,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub waveform: Option<Waveform>,
+    #[serde(default)]
+    pub duration_ms: Option<u64>,
+}
+
+// Position of the last item of a page. Timestamps only have second precision,
+// so `_id` breaks ties between notes posted in the same second.
+#[derive(Debug, Clone, Copy)]
+pub struct FeedCursor {
+    pub timestamp: DateTime<Utc>,
+    pub _id: ObjectId,
+}
+
+#[derive(Debug, Clone)]
+pub struct FeedPage {
+    pub items: Vec<FeedItem>,
+    // None once the end of the feed has been reached
+    pub next: Option<FeedCursor>,
+}
+
+impl FeedPage {
+    // Builds a page from up to `limit + 1` items; the extra one only tells us there is more.
+    pub fn from_items(mut items: Vec<FeedItem>, limit: i64) -> FeedPage {
+        let has_more = items.len() as i64 > limit;
+        items.truncate(limit.max(0) as usize);
+        let next = if has_more {
+            items.last().map(|item| FeedCursor { timestamp: item.timestamp, _id: item._id })
+        } else {
+            None
+        };
+        FeedPage { items: items, next: next }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct publicUser{
     pub refNo: i32,
@@ -68,16 +150,33 @@ pub struct publicUser{
 }
 
 impl VoiceNote{
-    pub async fn insert_one(&self, collection: Collection<VoiceNote>) {
+    pub async fn insert_one(&self, collection: Collection<VoiceNote>) -> VoicerResult<()> {
         let new_vn = self.clone();
-        collection.insert_one(new_vn, None).await.unwrap();
+        collection.insert_one(new_vn, None).await?;
+        Ok(())
     }
 }
 
+// What a follow actually changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FollowResult {
+    Followed,
+    AlreadyFollowing,
+}
+
+// What an unfollow or a removed follower actually changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnfollowResult {
+    Unfollowed,
+    NotFollowing,
+}
+
 #[derive(Clone)]
 pub struct replies{
     pub _id: ObjectId,
     pub user_id: (ObjectId, String),
+    pub waveform: Option<Waveform>,
+    pub duration_ms: Option<u64>,
 }
 
 #[derive(Clone)]
@@ -88,89 +187,96 @@ pub struct conversation{
     pub replies: Vec<replies>
 }
 
-pub async fn connect_to_mongodb() -> (Collection<Users>, Collection<VoiceNote>, Database, Client) {
+// How long to wait for the server before treating it as unreachable.
+const SERVER_SELECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn connect_to_mongodb() -> VoicerResult<(Collection<Users>, Collection<VoiceNote>, Database, Client)> {
     dotenv().ok();
-    let client = Client::with_uri_str(std::env::var("MONGODB_URL").unwrap()).await.unwrap();
+    let url = std::env::var("MONGODB_URL").map_err(|_| VoicerError::Config("MONGODB_URL is not set".to_string()))?;
+    let mut options = ClientOptions::parse(url).await?;
+    options.app_name = Some("Voicer".to_string());
+    options.min_pool_size = Some(1);
+    options.max_pool_size = Some(10);
+    // Give up quickly when the server can't be reached so the app can switch to its offline replica
+    options.server_selection_timeout = Some(SERVER_SELECTION_TIMEOUT);
+    let client = Client::with_options(options)?;
     let db = client.database("Cluster0");
     let collection = db.collection::<Users>("users");
     let vcollection: Collection<VoiceNote>= db.collection::<VoiceNote>("Voice Notes");
     println!("Connected to MongoDB");
-    (collection, vcollection, db , client)
+    Ok((collection, vcollection, db , client))
+}
+
+pub fn to_public_user(user: Users, ref_no: i32) -> publicUser {
+    publicUser {
+        refNo: ref_no,
+        _id: user._id,
+        username: user.username,
+        name: user.name,
+        description: user.description,
+        followers: user.followers,
+        following: user.following,
+        voice_notes: user.voice_notes,
+    }
 }
 
-pub async fn find_users_by_names(user_collection: Collection<Users> , username: &str, user_id: ObjectId) -> publicUser {
+pub async fn find_users_by_names(user_collection: Collection<Users> , username: &str, user_id: ObjectId) -> VoicerResult<publicUser> {
     let filter = doc! {"username": username};
-    let mut cursor = user_collection.find(filter, None).await.expect("Failed to execute find.");
-    let mut users = publicUser{
-        refNo:0,
-        _id: ObjectId::new(),
-        username: String::new(),
-        name: String::new(),
-        description: String::new(),
-        followers: Vec::new(),
-        following: Vec::new(),
-        voice_notes: Vec::new(),
-    };
+    let mut cursor = user_collection.find(filter, None).await?;
+    let mut users = None;
     let mut var=0;
 
     while let Some(result) = cursor.next().await {
-        if let Ok(user) = result {
-            if user._id != user_id {
-                let pub_user = publicUser {
-                    refNo: var,
-                    _id: user._id,
-                    username: user.username,
-                    name: user.name,
-                    description: user.description,
-                    followers: user.followers,
-                    following: user.following,
-                    voice_notes: user.voice_notes,
-                };
-                users = pub_user;
-                var= var+1;
-            }
+        let user = result?;
+        if user._id != user_id {
+            users = Some(to_public_user(user, var));
+            var= var+1;
         }
-        
     }
 
-    users
+    users.ok_or(VoicerError::NotFound("User"))
 }
 
 
-pub async fn create_user(user_collection: Collection<Users>, username: String, password: String, name: String) -> ObjectId {
+pub async fn create_user(user_collection: Collection<Users>, username: String, password: String, name: String) -> VoicerResult<ObjectId> {
+    // Checked before hashing so a taken name doesn't cost an Argon2 run
+    let filter = doc! { "username": &username };
+    if user_collection.find_one(filter, None).await?.is_some() {
+        return Err(VoicerError::DuplicateUsername);
+    }
+
     let user_id = ObjectId::new();
     let new_user = Users {
         _id: user_id,
-        username: username.clone(),
-        password: password,
+        username: username,
+        password: Some(password::hash_password(&password).await?),
         name: name,
         // verified: false,
         description: String::from(""),
         followers: Vec::new(),
         following: Vec::new(),
         voice_notes: Vec::new(),
+        playback_speed: default_playback_speed(),
     };
-    
-    // Check if a user with the given username exists in the collection
-    let filter = doc! { "username": username };
-    let result = user_collection.find_one(filter, None).await;
-    let user:ObjectId = match result.expect("Error finding user") {
-        Some(_) => { 
-            println!("User with email already exists");
-            ObjectId::parse_str("f0f0f0f0f0f0f0f0f0f0f0f0").unwrap()},
-        None => {
-            println!("Creating new user");
-            new_user.insert_one(user_collection.clone()).await;
-            user_id
-        }
-    };
-        
-    user
+
+    println!("Creating new user");
+    // The unique index catches a sign-up that raced this one past the check
+    match new_user.insert_one(user_collection.clone()).await {
+        Err(err) if err.is_duplicate_key() => Err(VoicerError::DuplicateUsername),
+        Err(err) => Err(err),
+        Ok(()) => Ok(user_id),
+    }
 }
 
 
-pub async fn react_to_quote(voice_collection: Collection<VoiceNote>, v_id: ObjectId, user_id: ObjectId, reaction: ReactionType) {
-    println!("{}",v_id);
+pub async fn react_to_quote(voice_collection: Collection<VoiceNote>, v_id: ObjectId, user_id: ObjectId, reaction: ReactionType) -> VoicerResult<()> {
+    let user_reaction = Reaction {
+        user_id: user_id,
+        reaction: reaction,
+    };
+    let reaction_doc = bson::to_document(&user_reaction)?;
+
+    // Replace the user's earlier reaction if there is one
     let filter = doc! {
         "_id": v_id,
         "reactions": {
@@ -179,554 +285,712 @@ pub async fn react_to_quote(voice_collection: Collection<VoiceNote>, v_id: Objec
             }
         }
     };
-    
-
-    let user_reaction = Reaction {
-        user_id: user_id,
-        reaction: reaction,
-    };
-
-    let reaction_doc = bson::to_document(&user_reaction)
-        .expect("Failed to serialize reaction");
-
     let update = doc! {
-        "$set": { "reactions.$": reaction_doc }
+        "$set": { "reactions.$": reaction_doc.clone() }
     };
-
-    let options = UpdateOptions::builder()
-        .upsert(true) // Add a new document if no match is found
-        .build();
-
-    let result = voice_collection.update_one(filter, update, options).await;
-    if let Ok(result) = result {
-        if result.modified_count == 0 {
-            let filter = doc!{"_id": v_id};
-
-            let user_reaction = Reaction{
-                user_id: user_id,
-                reaction: reaction,
-            };
-
-            let reaction_doc = bson::to_document(&user_reaction)
-            .expect("Failed to serialize reaction");
-
-            let update = doc! { "$push": { "reactions": reaction_doc} };
-
-            let options = UpdateOptions::builder().build();
-
-            let result = voice_collection.update_one(filter, update, options).await; 
-            println!("Reaction inserted");
-        }
-        else{
-            println!("Reaction updated");
-        }
+    let result = voice_collection.update_one(filter, update, None).await?;
+    if result.matched_count > 0 {
+        println!("Reaction updated");
+        return Ok(());
     }
-    else{
-        let filter = doc!{"_id": v_id};
 
-        let user_reaction = Reaction{
-            user_id: user_id,
-            reaction: reaction,
-        };
-
-        let reaction_doc = bson::to_document(&user_reaction)
-        .expect("Failed to serialize reaction");
-
-        let update = doc! { "$push": { "reactions": reaction_doc} };
-
-        let options = UpdateOptions::builder().build();
-
-        let result = voice_collection.update_one(filter, update, options).await; 
-        println!("Reaction inserted");
+    let filter = doc!{"_id": v_id};
+    let update = doc! { "$push": { "reactions": reaction_doc} };
+    let result = voice_collection.update_one(filter, update, None).await?;
+    if result.matched_count == 0 {
+        return Err(VoicerError::NotFound("Quote"));
     }
-     
+    println!("Reaction inserted");
+    Ok(())
 }
 
-pub async fn create_post(voice_collection: Collection<VoiceNote>, user_collection: Collection<Users>, user_id: ObjectId, data: Vec<i16>, voice_id: ObjectId) {
+async fn get_user_by_id(user_collection: Collection<Users>, user_id: ObjectId) -> VoicerResult<Users> {
     let filter = doc! { "_id": user_id };
+    user_collection.find_one(filter, None).await?.ok_or(VoicerError::NotFound("User"))
+}
 
-    let mut user;
+pub async fn create_post(voice_collection: Collection<VoiceNote>, user_collection: Collection<Users>, storage: &dyn AudioStorage, limits: &LengthLimits, user_id: ObjectId, recording: PcmAudio, format: AudioFormat, voice_id: ObjectId) -> VoicerResult<()> {
+    limits.check(recording.duration(), true)?;
+    let user = get_user_by_id(user_collection.clone(), user_id).await?;
+    let stored = audio_storage::store_samples(storage, voice_id, recording).await?;
 
-    match user_collection.find_one(filter, None).await {
-        Ok(result) => match result {
-            Some(doc) => {
-                user = Some(doc);
-            }
-            None => user= None,
-        },
-        Err(e) => {
-            println!("Failed to get user: {}", e);
-            user = None
-        }
-    };
-    
     let new_voice_note = VoiceNote {
         _id: voice_id,
         user_id: user_id,
         is_post: true,
-        data: data,
+        data: Vec::new(),
+        audio: Some(stored.blob.clone()),
+        source_format: Some(format),
+        loudness: stored.loudness,
+        waveform: Some(stored.waveform),
+        duration_ms: Some(stored.duration.as_millis() as u64),
         replies: Vec::new(),
-        name: user.unwrap().name,
+        name: user.name,
         reactions: Vec::new(),
         timestamp: Utc::now()
     };
-    new_voice_note.insert_one(voice_collection.clone()).await;
-    save_voice_note(user_collection, user_id, voice_id).await;
+    if let Err(err) = new_voice_note.insert_one(voice_collection.clone()).await {
+        discard_audio(storage, &stored.blob).await;
+        return Err(err);
+    }
+    save_voice_note(user_collection, user_id, voice_id).await
 }
 
+// Best-effort cleanup of a blob whose note never made it into the database.
+async fn discard_audio(storage: &dyn AudioStorage, audio: &AudioBlob) {
+    if let Err(err) = storage.remove(audio).await {
+        println!("Error removing orphaned audio: {}", err);
+    }
+}
 
-pub async fn delete_post(voice_note_collection: Collection<VoiceNote>,user_collection: Collection<Users>,voice_note_id: ObjectId,user_id: ObjectId,) {
-    let delete_result = voice_note_collection
-        .delete_one(doc! {"_id": voice_note_id}, None)
-        .await;
 
-    if let Err(err) = delete_result {
-        println!("Failed to delete voice note: {}", err);
-        return;
+pub async fn delete_post(voice_note_collection: Collection<VoiceNote>,user_collection: Collection<Users>, storage: &dyn AudioStorage, voice_note_id: ObjectId,user_id: ObjectId,) -> VoicerResult<()> {
+    // Only the author can delete a note; anyone else is told it doesn't exist
+    let deleted = voice_note_collection
+        .find_one_and_delete(doc! {"_id": voice_note_id, "user_id": user_id}, None)
+        .await?
+        .ok_or(VoicerError::NotFound("Quote"))?;
+    if let Some(audio) = &deleted.audio {
+        discard_audio(storage, audio).await;
     }
 
     let filter = doc! {"_id": user_id};
     let update = doc! { "$pull": { "voice_notes": voice_note_id.to_hex() } };
     let options = None;
 
-    let update_result = user_collection.update_one(filter, update, options).await;
-
-    if let Err(err) = update_result {
-        println!("Failed to update user document: {}", err);
-        return;
-    }
+    user_collection.update_one(filter, update, options).await?;
+    Ok(())
 }
 
 
-pub async fn convert_audio_to_vec(filename: &str) -> Vec<i16> {
-    let mut reader = hound::WavReader::open(filename).unwrap();
-    
-    let samples: Vec<i16> = reader.samples::<f32>().map(|x| (x.unwrap() * i16::MAX as f32) as i16).collect();
+// Reads a WAV as it was recorded, scaling whatever sample format it holds to 16 bits.
+pub async fn convert_audio_to_vec(filename: &str) -> VoicerResult<(PcmAudio, AudioFormat)> {
+    let mut reader = hound::WavReader::open(filename)?;
+    let spec = reader.spec();
 
-    samples
+    let samples = match (spec.sample_format, spec.bits_per_sample) {
+        (hound::SampleFormat::Float, 32) => reader.samples::<f32>().map(|x| x.map(|x| (x.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)).collect::<Result<Vec<i16>, hound::Error>>()?,
+        (hound::SampleFormat::Int, 8) => reader.samples::<i8>().map(|x| x.map(|x| (x as i16) << 8)).collect::<Result<Vec<i16>, hound::Error>>()?,
+        (hound::SampleFormat::Int, 16) => reader.samples::<i16>().collect::<Result<Vec<i16>, hound::Error>>()?,
+        (hound::SampleFormat::Int, bits @ 17..=32) => reader.samples::<i32>().map(|x| x.map(|x| (x >> (bits - 16)) as i16)).collect::<Result<Vec<i16>, hound::Error>>()?,
+        (format, bits) => return Err(VoicerError::Audio(format!("Unsupported WAV format: {}-bit {:?}", bits, format))),
+    };
+
+    let format = AudioFormat {
+        channels: spec.channels,
+        sample_rate: spec.sample_rate,
+        bits_per_sample: spec.bits_per_sample,
+        sample_format: match spec.sample_format {
+            hound::SampleFormat::Int => SampleFormat::Int,
+            hound::SampleFormat::Float => SampleFormat::Float,
+        },
+    };
+    Ok((PcmAudio { samples: samples, channels: spec.channels, sample_rate: spec.sample_rate }, format))
 }
 
-pub async fn convert_vec_to_audio(filename:&str, data: Vec<i16>) {
+pub async fn convert_vec_to_audio(filename:&str, audio: PcmAudio) -> VoicerResult<()> {
     let spec = hound::WavSpec {
-        channels: 1,
-        sample_rate: 44100,
+        channels: audio.channels,
+        sample_rate: audio.sample_rate,
         bits_per_sample: 16,
         sample_format: hound::SampleFormat::Int,
     };
-    let mut writer = hound::WavWriter::create(filename, spec).unwrap();
+    let mut writer = hound::WavWriter::create(filename, spec)?;
 
-    for sample in data {
-        writer.write_sample(sample).unwrap();
+    for sample in audio.samples {
+        writer.write_sample(sample)?;
     }
 
-    writer.finalize().unwrap();
+    writer.finalize()?;
+    Ok(())
 }
 
-pub async fn create_comment(voice_collection: Collection<VoiceNote>, user_collection: Collection<Users>, user_id: ObjectId, voice_id: String, comment_id: ObjectId, data: Vec<i16>) {
-    let filter = doc! { "_id": user_id };
-
-    let mut user;
-
-    match user_collection.find_one(filter, None).await {
-        Ok(result) => match result {
-            Some(doc) => {
-                user = Some(doc);
-            }
-            None => user= None,
-        },
-        Err(e) => {
-            println!("Failed to get user: {}", e);
-            user = None
-        }
-    };
+pub async fn create_comment(voice_collection: Collection<VoiceNote>, user_collection: Collection<Users>, storage: &dyn AudioStorage, limits: &LengthLimits, user_id: ObjectId, voice_id: String, comment_id: ObjectId, recording: PcmAudio, format: AudioFormat) -> VoicerResult<()> {
+    limits.check(recording.duration(), false)?;
+    let user = get_user_by_id(user_collection, user_id).await?;
+    let stored = audio_storage::store_samples(storage, comment_id, recording).await?;
 
     let new_voice_note = VoiceNote {
         _id: comment_id,
         user_id: user_id,
         is_post: false,
-        data: data,
-        name: user.unwrap().name,
+        data: Vec::new(),
+        audio: Some(stored.blob.clone()),
+        source_format: Some(format),
+        loudness: stored.loudness,
+        waveform: Some(stored.waveform),
+        duration_ms: Some(stored.duration.as_millis() as u64),
+        name: user.name,
         replies: Vec::new(),
         reactions: Vec::new(),
         timestamp: Utc::now()
     };
-    new_voice_note.insert_one(voice_collection.clone()).await;
+    if let Err(err) = new_voice_note.insert_one(voice_collection.clone()).await {
+        discard_audio(storage, &stored.blob).await;
+        return Err(err);
+    }
     //save_voice_note(user_collection, user_id, comment_id).await;
-    add_reply(voice_collection, voice_id, comment_id).await;
+    add_reply(voice_collection, voice_id, comment_id).await
 }
 
-pub async fn add_reply(voice_collection: Collection<VoiceNote>, voice_id: String, comment_id: ObjectId) {
-    let filter = doc! {"_id": ObjectId::parse_str(voice_id).unwrap()};
+pub async fn add_reply(voice_collection: Collection<VoiceNote>, voice_id: String, comment_id: ObjectId) -> VoicerResult<()> {
+    let parent_id = ObjectId::parse_str(voice_id).map_err(|_| VoicerError::NotFound("Quote"))?;
+    let filter = doc! {"_id": parent_id};
 
-    let update = doc! { "$push": { "replies": comment_id.to_hex()} };
+    let update = doc! { "$addToSet": { "replies": comment_id.to_hex()} };
 
     let options = UpdateOptions::builder().build();
 
-    let result = voice_collection.update_one(filter, update, options).await; 
+    let result = voice_collection.update_one(filter, update, options).await?;
+    if result.matched_count == 0 {
+        return Err(VoicerError::NotFound("Quote"));
+    }
+    Ok(())
 }
 
-pub async fn create_conversation (voice_collection: Collection<VoiceNote>, v_id: ObjectId,) -> conversation {
-    let filter = doc! { "_id": v_id };
-
-    let mut post= VoiceNote { _id: ObjectId::new(), user_id: ObjectId::new(), is_post: false,name: String::new(), replies: vec![],data: vec![], reactions: vec![], timestamp: Utc::now() };
-
-    match voice_collection.find_one(filter, None).await {
-        Ok(result) => match result {
-            Some(doc) => {
-                    post=doc;
-                }
-            None => {}
-        },
-        Err(e) => {
-            println!("Failed to get user: {}", e);
-        }
-    };
+pub async fn create_conversation (voice_collection: Collection<VoiceNote>, v_id: ObjectId,) -> VoicerResult<conversation> {
+    let post = get_voice_note(voice_collection.clone(), v_id).await?;
 
     let mut con_replies = Vec::new();
 
     for item in post.replies{
-        let reply = replies {
-            _id: item,
-            user_id: get_user_of_vn(voice_collection.clone(), item).await.unwrap(),
+        // A reply that has since been deleted is simply left out of the thread
+        let reply = match get_voice_note(voice_collection.clone(), item).await {
+            Ok(reply) => reply,
+            Err(VoicerError::NotFound(_)) => continue,
+            Err(err) => return Err(err),
         };
-        download_voice_notes(voice_collection.clone(), item).await;
-        con_replies.push(reply);
+        con_replies.push(replies {
+            _id: item,
+            user_id: (reply.user_id, reply.name),
+            waveform: reply.waveform,
+            duration_ms: reply.duration_ms,
+        });
     };
 
-    let result = conversation {
+    Ok(conversation {
         v_id: post._id,
         v_user_id: post.user_id,
         reactions: post.reactions,
         replies: con_replies, 
-    };
-
-    result
-
+    })
 }
 
-async fn get_user_of_vn(voice_collection: Collection<VoiceNote>, v_id: ObjectId) -> Option<(ObjectId, String)> {
+pub async fn get_voice_note(voice_collection: Collection<VoiceNote>, v_id: ObjectId) -> VoicerResult<VoiceNote> {
     let filter = doc! {"_id": v_id};
 
-    let mut user:Option<(ObjectId, String)>;
+    voice_collection.find_one(filter, None).await?.ok_or(VoicerError::NotFound("Quote"))
+}
 
-    match voice_collection.find_one(filter, None).await {
-        Ok(result) => match result{
-            Some(doc)=>{
-                user = Some((doc.user_id, doc.name));
-            }
-            None => user=None,
-        }
-        Err(e) => {
-            println!("Failed to get user: {}", e);
-            user = None
+pub async fn get_user_by_username(collection: Collection<Users>, username: String, password: String) -> VoicerResult<Users> {
+    let filter = doc! { "username": username };
+
+    let mut user = collection.find_one(filter, None).await?.ok_or(VoicerError::NotFound("User"))?;
+    let stored = user.password.clone().ok_or(VoicerError::InvalidCredentials)?;
+    match password::verify_password(&password, &stored).await {
+        PasswordCheck::Valid => {}
+        PasswordCheck::ValidNeedsRehash => {
+            // Upgrade a legacy plaintext password now that we know it's correct
+            let hash = password::hash_password(&password).await?;
+            let filter = doc! { "_id": user._id, "password": &stored };
+            collection.update_one(filter, doc! { "$set": { "password": &hash } }, None).await?;
+            user.password = Some(hash);
         }
-    } 
+        PasswordCheck::Invalid => return Err(VoicerError::InvalidCredentials),
+    }
+    Ok(user)
+}
+
+pub async fn create_session(session_collection: Collection<Session>, user_id: ObjectId) -> VoicerResult<String> {
+    // Drop this user's expired sessions while we're here
+    let expired = doc! { "user_id": user_id, "expires_at": { "$lte": Utc::now().timestamp() } };
+    session_collection.delete_many(expired, None).await?;
 
-    user
+    let (new_session, token) = Session::issue(user_id);
+    session_collection.insert_one(new_session, None).await?;
+    Ok(token)
 }
 
-pub async fn get_user_by_username(collection: Collection<Users>, username: String, password: String) -> Option<Users> {
-    let filter = doc! { "username": username };
+pub async fn get_user_by_session(session_collection: Collection<Session>, user_collection: Collection<Users>, token: &str) -> VoicerResult<Users> {
+    let filter = doc! { "token_hash": session::hash_token(token) };
+    let found = session_collection.find_one(filter, None).await?.ok_or(VoicerError::InvalidSession)?;
+    if found.is_expired() {
+        session_collection.delete_one(doc! { "_id": found._id }, None).await?;
+        return Err(VoicerError::InvalidSession);
+    }
+    match get_user_by_id(user_collection, found.user_id).await {
+        Err(VoicerError::NotFound(_)) => Err(VoicerError::InvalidSession),
+        result => result,
+    }
+}
 
-    let mut user;
+pub async fn revoke_session(session_collection: Collection<Session>, token: &str) -> VoicerResult<()> {
+    revoke_session_by_hash(session_collection, &session::hash_token(token)).await
+}
 
-    match collection.find_one(filter, None).await {
-        Ok(result) => match result {
-            Some(doc) => {
-                if doc.password==password {
-                    user=Some(doc)
-                }
-                else{
-                    println!("Wrong password");
-                    user= None
-                }
-            }
-            None => user= None,
-        },
-        Err(e) => {
-            println!("Failed to get user: {}", e);
-            user = None
-        }
-    };
-    user
+pub async fn revoke_session_by_hash(session_collection: Collection<Session>, token_hash: &str) -> VoicerResult<()> {
+    session_collection.delete_one(doc! { "token_hash": token_hash }, None).await?;
+    Ok(())
+}
+
+// Stores a session that was issued while offline.
+pub async fn insert_session(session_collection: Collection<Session>, session: Session) -> VoicerResult<()> {
+    session_collection.insert_one(session, None).await?;
+    Ok(())
 }
 
-pub async fn update_user_name_by_username(user_collection: Collection<Users>, username: &str, new_name: &str) -> bool {
+pub async fn revoke_all_sessions(session_collection: Collection<Session>, user_id: ObjectId) -> VoicerResult<u64> {
+    let result = session_collection.delete_many(doc! { "user_id": user_id }, None).await?;
+    Ok(result.deleted_count)
+}
+
+async fn update_user_field(user_collection: Collection<Users>, username: &str, field: &str, value: &str) -> VoicerResult<()> {
     let filter = doc! { "username": username };
-    let update = doc! { "$set": { "name": new_name } };
-    let options = FindOneAndUpdateOptions::builder().return_document(mongodb::options::ReturnDocument::After).build();
-    if let Ok(updated_user) = user_collection.find_one_and_update(filter, update, options).await {
-        return true;
+    let update = doc! { "$set": { field: value } };
+    let result = user_collection.update_one(filter, update, None).await?;
+    if result.matched_count == 0 {
+        return Err(VoicerError::NotFound("User"));
     }
-    false
+    Ok(())
 }
 
-pub async fn follow(user_collection: Collection<Users>, user_id: ObjectId, fuser_id:ObjectId) {
-    let filter = doc! {"_id": user_id};
-    let update = doc! { "$push": { "following": fuser_id.to_hex()} };
-    let options = UpdateOptions::builder().build();
-    let result = user_collection.update_one(filter, update, options).await; 
+pub async fn update_user_name_by_username(user_collection: Collection<Users>, username: &str, new_name: &str) -> VoicerResult<()> {
+    update_user_field(user_collection, username, "name", new_name).await
+}
 
-    let filter2 = doc!{"_id": fuser_id};
-    let update2= doc! {"$push" : {"followers": user_id.to_hex()}};
-    let options2 = UpdateOptions::builder().build();
-    let result2 = user_collection.update_one(filter2, update2, options2).await;
+// Both sides of a follow are updated in one transaction, so the `following`
+// and `followers` lists can't disagree. `$addToSet` keeps them free of duplicates.
+pub async fn follow(client: &Client, user_collection: Collection<Users>, user_id: ObjectId, fuser_id:ObjectId) -> VoicerResult<FollowResult> {
+    if user_id == fuser_id {
+        return Err(VoicerError::SelfFollow);
+    }
+    let changed = update_follow(client, &user_collection, user_id, fuser_id, true).await?;
+    Ok(if changed { FollowResult::Followed } else { FollowResult::AlreadyFollowing })
 }
 
-pub async fn unfollow(user_collection: Collection<Users>, user_id: ObjectId, fuser_id: ObjectId) -> Vec<publicUser> {
-    let filter = doc! {"_id": user_id};
-    let update = doc! { "$pull": { "following": fuser_id.to_hex()} };
-    let options = UpdateOptions::builder().build();
-    let result = user_collection.update_one(filter, update, options).await;
+pub async fn unfollow(client: &Client, user_collection: Collection<Users>, user_id: ObjectId, fuser_id: ObjectId) -> VoicerResult<UnfollowResult> {
+    let changed = update_follow(client, &user_collection, user_id, fuser_id, false).await?;
+    Ok(if changed { UnfollowResult::Unfollowed } else { UnfollowResult::NotFollowing })
+}
 
-    let filter2 = doc!{"_id": fuser_id};
-    let update2 = doc! {"$pull" : {"followers": user_id.to_hex()}};
-    let options2 = UpdateOptions::builder().build();
-    let result2 = user_collection.update_one(filter2, update2, options2).await;
-    get_all_following_profile(user_collection, user_id).await
+pub async fn update_password_by_username(user_collection: Collection<Users>, username: &str, new_password: &str) -> VoicerResult<()> {
+    let hash = password::hash_password(new_password).await?;
+    update_user_field(user_collection, username, "password", &hash).await
 }
 
-pub async fn update_password_by_username(user_collection: Collection<Users>, username: &str, new_password: &str) -> bool {
-    let filter = doc! { "username": username };
-    let update = doc! { "$set": { "password": new_password } };
-    let options = FindOneAndUpdateOptions::builder().return_document(mongodb::options::ReturnDocument::After).build();
-    if let Ok(updated_user) = user_collection.find_one_and_update(filter, update, options).await {
-        return true;
-    }
-    false
+pub async fn update_description_by_username(user_collection: Collection<Users>, username: &str, new_desc: &str) -> VoicerResult<()> {
+    update_user_field(user_collection, username, "description", new_desc).await
 }
 
-pub async fn update_description_by_username(user_collection: Collection<Users>, username: &str, new_desc: &str) -> bool {
-    let filter = doc! { "username": username };
-    let update = doc! { "$set": { "description": new_desc } };
-    let options = FindOneAndUpdateOptions::builder().return_document(mongodb::options::ReturnDocument::After).build();
-    if let Ok(updated_user) = user_collection.find_one_and_update(filter, update, options).await {
-        return true;
+pub async fn update_playback_speed(user_collection: Collection<Users>, user_id: ObjectId, speed: f32) -> VoicerResult<()> {
+    let filter = doc! { "_id": user_id };
+    let update = doc! { "$set": { "playback_speed": speed as f64 } };
+    let result = user_collection.update_one(filter, update, None).await?;
+    if result.matched_count == 0 {
+        return Err(VoicerError::NotFound("User"));
     }
-    false
+    Ok(())
+}
+
+fn read_line() -> VoicerResult<String> {
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_string())
 }
 
-pub async fn sign_up(user_collection: Collection<Users>) -> ObjectId {
+pub async fn sign_up(user_collection: Collection<Users>) -> VoicerResult<ObjectId> {
     println!("Please enter your email:");
-    let mut email = String::new();
-    io::stdin().read_line(&mut email).expect("Failed to read input.");
-    email = email.trim().to_string();
+    let email = read_line()?;
 
     println!("Please enter your name:");
-    let mut name = String::new();
-    io::stdin().read_line(&mut name).expect("Failed to read input.");
-    name = name.trim().to_string();
+    let name = read_line()?;
 
     println!("Please enter your password:");
-    let mut password = String::new();
-    io::stdin().read_line(&mut password).expect("Failed to read input.");
-    password = password.trim().to_string();
-    
+    let password = read_line()?;
 
-    let mut new_user_id = create_user(user_collection, email, password, name).await;
-    
-    new_user_id
+    create_user(user_collection, email, password, name).await
 }
 
-pub async fn login(user_collection: Collection<Users>) -> Option<Users> {
+pub async fn login(user_collection: Collection<Users>) -> VoicerResult<Users> {
     println!("Please enter your username:");
-    let mut username = String::new();
-    io::stdin().read_line(&mut username).expect("Failed to read input.");
-    username = username.trim().to_string();
+    let username = read_line()?;
 
     println!("Please enter your password:");
-    let mut password = String::new();
-    io::stdin().read_line(&mut password).expect("Failed to read input.");
-    password = password.trim().to_string();
+    let password = read_line()?;
 
     get_user_by_username(user_collection, username, password).await
 }
 
-async fn save_voice_note(collection: Collection<Users> ,userid: ObjectId, v_id: ObjectId) {
+pub async fn save_voice_note(collection: Collection<Users> ,userid: ObjectId, v_id: ObjectId) -> VoicerResult<()> {
 
     let filter = doc! { "_id": userid };
 
-    let update = doc! { "$push": { "voice_notes": v_id.to_hex()} };
+    let update = doc! { "$addToSet": { "voice_notes": v_id.to_hex()} };
 
     let options = UpdateOptions::builder().build();
 
-    let result = collection.update_one(filter, update, options).await;
+    collection.update_one(filter, update, options).await?;
+    Ok(())
 }
 
-async fn get_all_following(user_collection: Collection<Users> , user_id: ObjectId) -> Vec<ObjectId> {
-    let filter = doc! {"_id": user_id};
-    let mut cursor = user_collection.find(filter, None).await.expect("Failed to execute find.");
-    let mut following = Vec::new();
+async fn get_all_following(user_collection: Collection<Users> , user_id: ObjectId) -> VoicerResult<Vec<ObjectId>> {
+    Ok(get_user_by_id(user_collection, user_id).await?.following)
+}
 
-    while let Some(result) = cursor.next().await {
-        if let Ok(user) = result {
-            for i in user.following {
-                following.push(i);
+async fn get_all_followers(user_collection: Collection<Users> , user_id: ObjectId) -> VoicerResult<Vec<ObjectId>> {
+    Ok(get_user_by_id(user_collection, user_id).await?.followers)
+}
+
+async fn get_profiles(user_collection: Collection<Users>, ids: Vec<ObjectId>, user_id: ObjectId) -> VoicerResult<Vec<publicUser>> {
+    let mut users =Vec::new();
+    let mut var=0;
+
+    for id in ids {
+        let filter = doc! { "_id": id };
+        let mut cursor = user_collection.find(filter, None).await?;
+        while let Some(result) = cursor.next().await {
+            let user = result?;
+            if user._id != user_id {
+                users.push(to_public_user(user, var));
+                var= var+1;
             }
-        }        
+        }
     }
-    
 
-    following
+    Ok(users)
 }
 
-async fn get_all_followers(user_collection: Collection<Users> , user_id: ObjectId) -> Vec<ObjectId> {
-    let filter = doc! {"_id": user_id};
-    let mut cursor = user_collection.find(filter, None).await.expect("Failed to execute find.");
-    let mut followers = Vec::new();
+pub async fn get_all_following_profile(user_collection: Collection<Users>, user_id: ObjectId) -> VoicerResult<Vec<publicUser>> {
+    let following_ids = get_all_following(user_collection.clone(), user_id).await?;
+    get_profiles(user_collection, following_ids, user_id).await
+}
 
-    while let Some(result) = cursor.next().await {
-        if let Ok(user) = result {
-            for i in user.followers {
-                followers.push(i);
-            }
-        }        
-    }
-    
+pub async fn get_all_followers_profile(user_collection: Collection<Users>, user_id: ObjectId) -> VoicerResult<Vec<publicUser>> {
+    let follower_ids = get_all_followers(user_collection.clone(), user_id).await?;
+    get_profiles(user_collection, follower_ids, user_id).await
+}
 
-    followers
+pub async fn remove_follower(client: &Client, user_collection: Collection<Users>, user_id: ObjectId, follower_id: ObjectId) -> VoicerResult<UnfollowResult> {
+    let changed = update_follow(client, &user_collection, follower_id, user_id, false).await?;
+    Ok(if changed { UnfollowResult::Unfollowed } else { UnfollowResult::NotFollowing })
 }
 
-pub async fn get_all_following_profile(user_collection: Collection<Users>, user_id: ObjectId) -> Vec<publicUser> {
-    let following_ids = get_all_following(user_collection.clone(), user_id.clone()).await;
-    let mut users =Vec::new();
-    let mut var=0;
+// How many times a transaction is retried after a transient error.
+const TRANSACTION_ATTEMPTS: u32 = 5;
 
-    for i in 0..following_ids.len() {
-        let filter = doc! { "_id": following_ids[i] };
-        let mut cursor = user_collection.find(filter, None).await.expect("Failed to execute find.");
-        while let Some(result) = cursor.next().await {
-            if let Ok(user) = result {
-                if user._id != user_id {
-                    let pub_user = publicUser {
-                        refNo: var,
-                        _id: user._id,
-                        username: user.username,
-                        name: user.name,
-                        description: user.description,
-                        followers: user.followers,
-                        following: user.following,
-                        voice_notes: user.voice_notes,
-                    };
-                    users.push(pub_user);
-                    var= var+1;
+// Adds or removes `follower` -> `followed` in a transaction, retrying it on
+// transient errors and the commit when its outcome is unknown. Returns false
+// if the follower's list already said so.
+async fn update_follow(client: &Client, user_collection: &Collection<Users>, follower: ObjectId, followed: ObjectId, add: bool) -> VoicerResult<bool> {
+    let mut session = client.start_session(None).await?;
+    let mut attempt = 1;
+    loop {
+        let result = match session.start_transaction(None).await {
+            Err(err) => Err(err.into()),
+            Ok(()) => match follow_edge(&mut session, user_collection, follower, followed, add).await {
+                Ok(changed) => commit(&mut session).await.map(|()| changed),
+                Err(err) => {
+                    // The server may already have aborted it
+                    let _ = session.abort_transaction().await;
+                    Err(err)
                 }
+            },
+        };
+        match result {
+            Err(err) if err.is_transactions_unsupported() => {
+                return update_follow_without_transaction(user_collection, follower, followed, add).await;
+            }
+            Err(VoicerError::Database(err)) if err.contains_label(TRANSIENT_TRANSACTION_ERROR) && attempt < TRANSACTION_ATTEMPTS => {
+                println!("Retrying follow update: {}", err);
+                attempt += 1;
             }
+            result => return result,
         }
     }
-
-    users
 }
 
-pub async fn get_all_followers_profile(user_collection: Collection<Users>, user_id: ObjectId) -> Vec<publicUser> {
-    let follower_ids = get_all_followers(user_collection.clone(), user_id.clone()).await;
+// For a standalone server, the usual dev setup, which has no transactions. The
+// two sides are updated one after the other, and the followed user's side is
+// put back if the follower's can't be changed.
+async fn update_follow_without_transaction(user_collection: &Collection<Users>, follower: ObjectId, followed: ObjectId, add: bool) -> VoicerResult<bool> {
+    let (operator, undo) = if add { ("$addToSet", "$pull") } else { ("$pull", "$addToSet") };
 
-    let mut users =Vec::new();
-    let mut var=0;
+    let update = doc! { operator: { "followers": follower.to_hex() } };
+    let result = user_collection.update_one(doc! { "_id": followed }, update, None).await?;
+    // Unfollowing a deleted account still tidies up the follower's side
+    if add && result.matched_count == 0 {
+        return Err(VoicerError::NotFound("User"));
+    }
+    let followers_changed = result.modified_count > 0;
 
-    for i in 0..follower_ids.len() {
-        let filter = doc! { "_id": follower_ids[i] };
-        let mut cursor = user_collection.find(filter, None).await.expect("Failed to execute find.");
-        while let Some(result) = cursor.next().await {
-            if let Ok(user) = result {
-                if user._id != user_id {
-                    let pub_user = publicUser {
-                        refNo: var,
-                        _id: user._id,
-                        username: user.username,
-                        name: user.name,
-                        description: user.description,
-                        followers: user.followers,
-                        following: user.following,
-                        voice_notes: user.voice_notes,
-                    };
-                    users.push(pub_user);
-                    var= var+1;
-                }
-            }
+    let update = doc! { operator: { "following": followed.to_hex() } };
+    let err = match user_collection.update_one(doc! { "_id": follower }, update, None).await {
+        Ok(result) if result.matched_count > 0 => return Ok(result.modified_count > 0),
+        Ok(_) => VoicerError::NotFound("User"),
+        Err(err) => err.into(),
+    };
+    if followers_changed {
+        let update = doc! { undo: { "followers": follower.to_hex() } };
+        if let Err(err) = user_collection.update_one(doc! { "_id": followed }, update, None).await {
+            println!("Could not undo a half-made follow change: {}", err);
         }
     }
-    users
+    Err(err)
 }
 
-pub async fn remove_follower(user_collection: Collection<Users>, user_id: ObjectId, follower_id: ObjectId) -> Vec<publicUser> {
-    let filter = doc! {"_id": user_id};
-    let update = doc! { "$pull": { "followers": follower_id.to_hex() } };
-    let options = UpdateOptions::builder().build();
-    let result = user_collection.update_one(filter, update, options).await;
-
-    let filter2 = doc!{"_id": follower_id};
-    let update2 = doc! {"$pull" : {"following": user_id.to_hex() } };
-    let options2 = UpdateOptions::builder().build();
-    let result2 = user_collection.update_one(filter2, update2, options2).await;
-    
-    get_all_followers_profile(user_collection, user_id).await
-}
-
-pub async fn get_all_voice_ids_from_following(user_collection:Collection<Users> , voice_collection:Collection<VoiceNote> , user_id:ObjectId) -> Vec<VoiceNote>{
-    let following = get_all_following(user_collection.clone(), user_id).await;
-    println!("You follow {:?}", following);
-    let mut voice_ids = Vec::new();
-    for i in following {
-        let filter = doc! {"_id": i};
-        let mut cursor = user_collection.find(filter, None).await.expect("Failed to execute find.");
-        while let Some(result) = cursor.next().await {
-            if let Ok(user) = result {
-                for k in user.voice_notes {
-                    let filter = doc! {"_id": k, "is_post" : true};
-                    let mut cursor = voice_collection.find(filter, None).await.expect("Failed to execute find.");            
-                    while let Some(result) = cursor.next().await {
-                        if let Ok(voice) = result {
-                            if(voice.is_post == true) {
-                                voice_ids.push(voice);
-                            }
-                        }
-                    }
-                }
-            }        
+async fn commit(session: &mut ClientSession) -> VoicerResult<()> {
+    let mut attempt = 1;
+    loop {
+        match session.commit_transaction().await {
+            Err(err) if err.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT) && attempt < TRANSACTION_ATTEMPTS => attempt += 1,
+            result => return Ok(result?),
         }
     }
+}
+
+async fn follow_edge(session: &mut ClientSession, user_collection: &Collection<Users>, follower: ObjectId, followed: ObjectId, add: bool) -> VoicerResult<bool> {
+    let operator = if add { "$addToSet" } else { "$pull" };
+
+    let update = doc! { operator: { "followers": follower.to_hex() } };
+    let result = user_collection.update_one_with_session(doc! { "_id": followed }, update, None, session).await?;
+    // Unfollowing a deleted account still tidies up the follower's side
+    if add && result.matched_count == 0 {
+        return Err(VoicerError::NotFound("User"));
+    }
 
-    sort_voice_notes_by_timestamp_desc(&mut voice_ids);
-    for i in &voice_ids {
-        download_voice_notes(voice_collection.clone(), i._id).await;
+    let update = doc! { operator: { "following": followed.to_hex() } };
+    let result = user_collection.update_one_with_session(doc! { "_id": follower }, update, None, session).await?;
+    if result.matched_count == 0 {
+        return Err(VoicerError::NotFound("User"));
     }
-    voice_ids
+    Ok(result.modified_count > 0)
+}
+
+// One page of posts by the people `user_id` follows, newest first. Pass the
+// previous page's `next` as `after` to continue.
+pub async fn get_feed_page(user_collection: Collection<Users>, voice_collection: Collection<VoiceNote>, user_id: ObjectId, after: Option<FeedCursor>, limit: i64) -> VoicerResult<FeedPage> {
+    let following = get_all_following(user_collection, user_id).await?;
+    let mut filter = doc! { "user_id": { "$in": following }, "is_post": true };
+    if let Some(cursor) = after {
+        let timestamp = cursor.timestamp.timestamp();
+        filter.insert("$or", vec![
+            doc! { "timestamp": { "$lt": timestamp } },
+            doc! { "timestamp": timestamp, "_id": { "$lt": cursor._id } },
+        ]);
+    }
+    let options = FindOptions::builder()
+        .sort(doc! { "timestamp": -1, "_id": -1 })
+        .projection(doc! { "data": 0, "audio": 0 })
+        .limit(limit + 1)
+        .build();
+
+    let mut cursor = voice_collection.clone_with_type::<FeedItem>().find(filter, options).await?;
+    let mut items = Vec::new();
+    while let Some(result) = cursor.next().await {
+        items.push(result?);
+    }
+    Ok(FeedPage::from_items(items, limit))
 }
 
-pub fn sort_voice_notes_by_timestamp_desc(notes : &mut Vec<VoiceNote>) {
-    
-    notes.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+// What a feed card shows of each of the given notes, leaving out any that no longer exist.
+pub async fn get_note_summaries(voice_collection: Collection<VoiceNote>, ids: Vec<ObjectId>) -> VoicerResult<Vec<FeedItem>> {
+    let options = FindOptions::builder().projection(doc! { "data": 0, "audio": 0 }).build();
+    let mut cursor = voice_collection.clone_with_type::<FeedItem>().find(doc! { "_id": { "$in": ids } }, options).await?;
+    let mut items = Vec::new();
+    while let Some(result) = cursor.next().await {
+        items.push(result?);
+    }
+    Ok(items)
 }
 
-pub async fn download_voice_notes(voice_collection : Collection<VoiceNote> , v_id : ObjectId) -> bool{
-    let filter = doc! {"_id": v_id.clone()};
-    let result: Result<Option<VoiceNote>, mongodb::error::Error> = voice_collection.find_one(filter, None).await;
-    let mut voice:Vec<i16> = Vec::new();
-    if let Ok(value) = result {
-        voice = match value {
-            Some(value) => { 
-                value.data
-            },
-            None => {
-                println!("No voice data found");
-                Vec::new()
-            }
-        };
-    
-        let mut filename =  v_id.to_string() + ".wav";
-    
-        convert_vec_to_audio(&filename , voice).await; 
+// Backs the feed query's filter and sort. Creating an index that already exists is a no-op.
+pub async fn ensure_feed_index(voice_collection: Collection<VoiceNote>) -> VoicerResult<()> {
+    let index = IndexModel::builder()
+        .keys(doc! { "user_id": 1, "is_post": 1, "timestamp": -1, "_id": -1 })
+        .options(IndexOptions::builder().name("feed".to_string()).build())
+        .build();
+    voice_collection.create_index(index, None).await?;
+    Ok(())
+}
+
+// Makes usernames unique in the database itself, not just in create_user.
+pub async fn ensure_username_index(user_collection: Collection<Users>) -> VoicerResult<()> {
+    let index = IndexModel::builder()
+        .keys(doc! { "username": 1 })
+        .options(IndexOptions::builder().name("username".to_string()).unique(true).build())
+        .build();
+    user_collection.create_index(index, None).await?;
+    Ok(())
+}
 
-        return true;
-    } else if let Err(error) = result {
-        println!("Error: {}", error);
-        return false;
+// A note's audio, brought to the playback loudness.
+pub async fn playback_audio(voice_collection : Collection<VoiceNote> , storage: &dyn AudioStorage, v_id : ObjectId) -> VoicerResult<PcmAudio> {
+    let voice = get_voice_note(voice_collection.clone(), v_id).await?;
+    let loudness = voice.loudness;
+    let audio = load_voice_audio(voice_collection, storage, voice).await?;
+    normalize_for_playback(audio, loudness).await
+}
+
+// Decodes a note's audio into `stream` while it downloads, at the playback loudness.
+pub async fn stream_voice_audio(voice_collection: Collection<VoiceNote>, storage: &dyn AudioStorage, v_id: ObjectId, stream: AudioStream) -> VoicerResult<()> {
+    let voice = get_voice_note(voice_collection.clone(), v_id).await?;
+    if let (Some(blob), Some(loudness)) = (voice.audio.clone(), voice.loudness) {
+        return audio_storage::stream_samples(storage, &blob, Some(loudness), stream).await;
     }
-    else{
-        return false;
+    // Legacy notes and unmeasured ones need the whole clip first
+    let loudness = voice.loudness;
+    let result = match load_voice_audio(voice_collection, storage, voice).await {
+        Ok(audio) => normalize_for_playback(audio, loudness).await,
+        Err(err) => Err(err),
+    };
+    if let Ok(audio) = &result {
+        stream.push(&audio.samples);
     }
+    stream.finish();
+    result.map(|_| ())
 }
 
+pub async fn normalize_for_playback(mut audio: PcmAudio, loudness: Option<f64>) -> VoicerResult<PcmAudio> {
+    tokio::task::spawn_blocking(move || {
+        loudness::normalize(&mut audio, loudness);
+        audio
+    })
+    .await
+    .map_err(|err| VoicerError::Audio(err.to_string()))
+}
+
+pub async fn get_voice_audio(voice_collection: Collection<VoiceNote>, storage: &dyn AudioStorage, v_id: ObjectId) -> VoicerResult<PcmAudio> {
+    let voice = get_voice_note(voice_collection.clone(), v_id).await?;
+    load_voice_audio(voice_collection, storage, voice).await
+}
+
+async fn load_voice_audio(voice_collection: Collection<VoiceNote>, storage: &dyn AudioStorage, voice: VoiceNote) -> VoicerResult<PcmAudio> {
+    match &voice.audio {
+        Some(audio) => audio_storage::load_samples(storage, audio).await,
+        None => migrate_voice_note(voice_collection, storage, voice).await,
+    }
+}
+
+// Moves a note's legacy `data` array into encoded storage and returns its audio.
+async fn migrate_voice_note(voice_collection: Collection<VoiceNote>, storage: &dyn AudioStorage, voice: VoiceNote) -> VoicerResult<PcmAudio> {
+    // Legacy notes were always written back as mono 44.1 kHz
+    let legacy = PcmAudio { samples: voice.data, channels: CANONICAL_CHANNELS, sample_rate: CANONICAL_SAMPLE_RATE };
+    if legacy.samples.is_empty() {
+        return Ok(legacy);
+    }
+    let stored = audio_storage::store_samples(storage, voice._id, legacy.clone()).await?;
 
-fn main() {}
+    // Only the first migration of a note wins; a concurrent one drops its copy
+    let filter = doc! { "_id": voice._id, "audio": { "$exists": false } };
+    let update = doc! {
+        "$set": {
+            "audio": bson::to_bson(&stored.blob)?,
+            "loudness": stored.loudness,
+            "waveform": bson::to_bson(&stored.waveform)?,
+            "duration_ms": stored.duration.as_millis() as i64,
+        },
+        "$unset": { "data": "" },
+    };
+    match voice_collection.update_one(filter, update, None).await {
+        Ok(result) if result.modified_count == 1 => println!("Migrated audio of {}", voice._id),
+        Ok(_) => discard_audio(storage, &stored.blob).await,
+        Err(err) => {
+            println!("Error migrating audio of {}: {}", voice._id, err);
+            discard_audio(storage, &stored.blob).await;
+        }
+    }
+    Ok(legacy)
+}
+
+// Migrates every note still storing raw samples. Safe to run repeatedly.
+pub async fn migrate_legacy_audio(voice_collection: Collection<VoiceNote>, storage: &dyn AudioStorage) -> VoicerResult<u64> {
+    let filter = doc! { "audio": { "$exists": false }, "data.0": { "$exists": true } };
+    let options = FindOptions::builder().projection(doc! { "_id": 1 }).build();
+    let mut cursor = voice_collection.clone_with_type::<bson::Document>().find(filter, options).await?;
+    let mut ids = Vec::new();
+    while let Some(result) = cursor.next().await {
+        if let Ok(id) = result?.get_object_id("_id") {
+            ids.push(id);
+        }
+    }
+
+    let mut migrated = 0;
+    for id in ids {
+        let voice = get_voice_note(voice_collection.clone(), id).await?;
+        if voice.audio.is_none() {
+            migrate_voice_note(voice_collection.clone(), storage, voice).await?;
+            migrated += 1;
+        }
+    }
+    Ok(migrated)
+}
+
+// Stores the length of notes saved before durations were recorded. Safe to run repeatedly.
+pub async fn backfill_durations(voice_collection: Collection<VoiceNote>, storage: &dyn AudioStorage) -> VoicerResult<u64> {
+    let filter = doc! { "duration_ms": { "$exists": false }, "audio": { "$exists": true } };
+    let options = FindOptions::builder().projection(doc! { "_id": 1 }).build();
+    let mut cursor = voice_collection.clone_with_type::<bson::Document>().find(filter, options).await?;
+    let mut ids = Vec::new();
+    while let Some(result) = cursor.next().await {
+        if let Ok(id) = result?.get_object_id("_id") {
+            ids.push(id);
+        }
+    }
+
+    let mut filled = 0;
+    for id in ids {
+        let audio = match get_voice_note(voice_collection.clone(), id).await?.audio {
+            Some(audio) => audio,
+            None => continue,
+        };
+        // The length is in the FLAC header, so nothing needs decoding
+        let duration = match storage.fetch(&audio).await.and_then(|bytes| audio_codec::flac_duration(&bytes)) {
+            Ok(duration) => duration,
+            Err(err) => {
+                println!("Could not read the length of {}: {}", id, err);
+                continue;
+            }
+        };
+        let update = doc! { "$set": { "duration_ms": duration.as_millis() as i64 } };
+        voice_collection.update_one(doc! { "_id": id }, update, None).await?;
+        filled += 1;
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(count: usize) -> Vec<FeedItem> {
+        (0..count)
+            .map(|i| FeedItem {
+                _id: ObjectId::new(),
+                user_id: ObjectId::new(),
+                name: format!("user {}", i),
+                replies: Vec::new(),
+                reactions: Vec::new(),
+                timestamp: Utc::now(),
+                waveform: None,
+                duration_ms: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_extra_item_only_marks_more_to_come() {
+        let fetched = items(4);
+        let page = FeedPage::from_items(fetched.clone(), 3);
+        assert_eq!(page.items.len(), 3);
+        let next = page.next.unwrap();
+        assert_eq!(next._id, fetched[2]._id);
+        assert_eq!(next.timestamp, fetched[2].timestamp);
+    }
+
+    #[test]
+    fn a_short_page_is_the_last() {
+        assert!(FeedPage::from_items(items(3), 3).next.is_none());
+        assert!(FeedPage::from_items(items(1), 3).next.is_none());
+        assert!(FeedPage::from_items(Vec::new(), 3).next.is_none());
+    }
+}
@@ -0,0 +1,220 @@
+use std::time::Duration;
+
+use crate::audio_codec::PcmAudio;
+
+// Kept either side of the speech so trimming doesn't clip the first syllable.
+const TRIM_PADDING: Duration = Duration::from_millis(100);
+// The gate looks at the level in windows this long
+const GATE_WINDOW: Duration = Duration::from_millis(10);
+// Stays open this long after speech so word endings aren't chopped
+const GATE_HOLD: Duration = Duration::from_millis(150);
+// How quickly the gate fades in and out
+const GATE_FADE: Duration = Duration::from_millis(20);
+
+// What the automatic clean-up does to a fresh recording.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CleanupSettings {
+    pub trim_silence: bool,
+    // Anything with peaks below this (dBFS) counts as silence when trimming
+    pub silence_threshold_db: f32,
+    pub noise_gate: bool,
+    // Windows quieter than this (RMS, dBFS) are muted by the gate
+    pub gate_threshold_db: f32,
+}
+
+impl Default for CleanupSettings {
+    fn default() -> Self {
+        CleanupSettings { trim_silence: true, silence_threshold_db: -36.0, noise_gate: false, gate_threshold_db: -50.0 }
+    }
+}
+
+// How much the clean-up removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CleanupReport {
+    pub trimmed_start: Duration,
+    pub trimmed_end: Duration,
+    pub gated: Duration,
+    // Nothing crossed the silence threshold, so nothing was trimmed
+    pub silent: bool,
+}
+
+impl CleanupReport {
+    pub fn summary(&self) -> String {
+        if self.silent {
+            return "The recording sounds silent, so it was left as it is".to_string();
+        }
+        let mut parts = Vec::new();
+        if self.trimmed_start + self.trimmed_end > Duration::ZERO {
+            parts.push(format!(
+                "trimmed {:.1} s of silence from the start and {:.1} s from the end",
+                self.trimmed_start.as_secs_f32(),
+                self.trimmed_end.as_secs_f32()
+            ));
+        }
+        if self.gated > Duration::ZERO {
+            parts.push(format!("muted {:.1} s of background noise", self.gated.as_secs_f32()));
+        }
+        if parts.is_empty() {
+            return "Nothing needed cleaning up".to_string();
+        }
+        let summary = parts.join(", ");
+        summary[..1].to_uppercase() + &summary[1..]
+    }
+}
+
+fn db_to_amplitude(db: f32) -> f32 {
+    10f32.powf(db / 20.0) * i16::MAX as f32
+}
+
+fn frames_to_duration(frames: usize, sample_rate: u32) -> Duration {
+    Duration::from_secs_f64(frames as f64 / sample_rate.max(1) as f64)
+}
+
+// Runs the enabled clean-up steps on a copy of `audio`.
+pub fn clean_up(audio: &PcmAudio, settings: &CleanupSettings) -> (PcmAudio, CleanupReport) {
+    let mut cleaned = audio.clone();
+    let mut report = CleanupReport::default();
+
+    if settings.trim_silence {
+        match content_range(&cleaned, settings.silence_threshold_db) {
+            Some((start, end)) => {
+                let channels = cleaned.channels.max(1) as usize;
+                report.trimmed_start = frames_to_duration(start, cleaned.sample_rate);
+                report.trimmed_end = frames_to_duration(cleaned.frames() - end, cleaned.sample_rate);
+                cleaned.samples = cleaned.samples[start * channels..end * channels].to_vec();
+            }
+            None => report.silent = true,
+        }
+    }
+    if settings.noise_gate && !report.silent {
+        let gated = noise_gate(&mut cleaned, settings.gate_threshold_db);
+        report.gated = frames_to_duration(gated, cleaned.sample_rate);
+    }
+    (cleaned, report)
+}
+
+// The frames between the first and last sound above `threshold_db`, padded a
+// little either side. None if the whole clip is quieter than that.
+pub fn content_range(audio: &PcmAudio, threshold_db: f32) -> Option<(usize, usize)> {
+    let channels = audio.channels.max(1) as usize;
+    let threshold = db_to_amplitude(threshold_db);
+    let loud = |frame: &[i16]| frame.iter().any(|sample| sample.unsigned_abs() as f32 > threshold);
+    let frames: Vec<&[i16]> = audio.samples.chunks_exact(channels).collect();
+    let first = frames.iter().position(|f| loud(f))?;
+    let last = frames.iter().rposition(|f| loud(f))?;
+
+    let padding = (TRIM_PADDING.as_secs_f64() * audio.sample_rate as f64) as usize;
+    Some((first.saturating_sub(padding), (last + 1 + padding).min(frames.len())))
+}
+
+// Mutes the quiet stretches between words. Returns how many frames were muted.
+fn noise_gate(audio: &mut PcmAudio, threshold_db: f32) -> usize {
+    let channels = audio.channels.max(1) as usize;
+    let rate = audio.sample_rate as f64;
+    let window = ((GATE_WINDOW.as_secs_f64() * rate) as usize).max(1);
+    let hold = (GATE_HOLD.as_secs_f64() * rate) as usize / window;
+    let threshold = db_to_amplitude(threshold_db);
+
+    // Open or closed for each window, held open for a while after anything loud
+    let mut open: Vec<bool> = audio
+        .samples
+        .chunks(window * channels)
+        .map(|chunk| {
+            let power = chunk.iter().map(|s| (*s as f32).powi(2)).sum::<f32>() / chunk.len() as f32;
+            power.sqrt() > threshold
+        })
+        .collect();
+    let loud = open.clone();
+    let mut since_loud = usize::MAX;
+    for (state, &loud) in open.iter_mut().zip(&loud) {
+        since_loud = if loud { 0 } else { since_loud.saturating_add(1) };
+        *state = since_loud <= hold;
+    }
+    // Open early enough that the fade in is done when speech starts
+    let lead = (GATE_FADE.as_secs_f64() * rate) as usize / window + 1;
+    for i in 0..open.len() {
+        if loud[i..(i + lead + 1).min(loud.len())].contains(&true) {
+            open[i] = true;
+        }
+    }
+
+    // Fade the gain rather than switching it, so the gate doesn't click
+    let step = 1.0 / (GATE_FADE.as_secs_f64() * rate).max(1.0) as f32;
+    let mut gain = if open.first() == Some(&true) { 1.0 } else { 0.0 };
+    let mut muted = 0;
+    for (i, frame) in audio.samples.chunks_exact_mut(channels).enumerate() {
+        let target = if open[i / window] { 1.0 } else { 0.0 };
+        if target > gain {
+            gain = (gain + step).min(1.0);
+        } else if target < gain {
+            gain = (gain - step).max(0.0);
+        }
+        if gain == 0.0 {
+            muted += 1;
+        }
+        for sample in frame {
+            *sample = (*sample as f32 * gain) as i16;
+        }
+    }
+    muted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 1000;
+
+    // Silence, then a loud stretch, then silence, in seconds
+    fn speech_between(before: f32, speech: f32, after: f32) -> PcmAudio {
+        let mut samples = vec![0i16; (before * RATE as f32) as usize];
+        samples.extend((0..(speech * RATE as f32) as usize).map(|i| if i % 2 == 0 { 10000 } else { -10000 }));
+        samples.extend(vec![0i16; (after * RATE as f32) as usize]);
+        PcmAudio { samples, channels: 1, sample_rate: RATE }
+    }
+
+    #[test]
+    fn trimming_keeps_a_little_padding() {
+        let audio = speech_between(1.0, 0.5, 2.0);
+        assert_eq!(content_range(&audio, -36.0), Some((900, 1600)));
+
+        let (cleaned, report) = clean_up(&audio, &CleanupSettings::default());
+        assert_eq!(cleaned.samples.len(), 700);
+        assert_eq!(report.trimmed_start, Duration::from_millis(900));
+        assert_eq!(report.trimmed_end, Duration::from_millis(1900));
+        assert!(!report.silent);
+    }
+
+    #[test]
+    fn silent_recordings_are_left_alone() {
+        let audio = speech_between(1.0, 0.0, 0.0);
+        let (cleaned, report) = clean_up(&audio, &CleanupSettings::default());
+        assert_eq!(cleaned.samples, audio.samples);
+        assert!(report.silent);
+        assert_eq!(report.summary(), "The recording sounds silent, so it was left as it is");
+    }
+
+    #[test]
+    fn the_gate_mutes_hiss_between_words() {
+        let mut audio = speech_between(0.0, 0.5, 0.0);
+        // A second of quiet hiss, then more speech
+        audio.samples.extend((0..1000).map(|i| if i % 2 == 0 { 20 } else { -20 }));
+        audio.samples.extend(speech_between(0.0, 0.5, 0.0).samples);
+        let settings = CleanupSettings { trim_silence: false, noise_gate: true, ..CleanupSettings::default() };
+
+        let (cleaned, report) = clean_up(&audio, &settings);
+        // Held open a while after the first word and opened ahead of the second
+        assert!(report.gated > Duration::from_millis(600) && report.gated < Duration::from_millis(900), "{:?}", report.gated);
+        assert!(cleaned.samples[1000..1100].iter().all(|sample| *sample == 0));
+        // Speech passes through untouched once the gate is open
+        assert_eq!(cleaned.samples[100..400], audio.samples[100..400]);
+        assert_eq!(cleaned.samples[1600..1900], audio.samples[1600..1900]);
+    }
+
+    #[test]
+    fn the_summary_says_what_was_done() {
+        let report = CleanupReport { trimmed_start: Duration::from_millis(1500), trimmed_end: Duration::from_millis(200), ..Default::default() };
+        assert_eq!(report.summary(), "Trimmed 1.5 s of silence from the start and 0.2 s from the end");
+        assert_eq!(CleanupReport::default().summary(), "Nothing needed cleaning up");
+    }
+}
@@ -0,0 +1,69 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::runtime::{Handle, Runtime};
+
+use crate::error::VoicerResult;
+use crate::limits::LengthLimits;
+use crate::memory_store::MemoryStore;
+use crate::store::{self, VoicerStore};
+
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// App-wide state created once in `Gui::new()`: the tokio runtime every page runs
+// its async work on, and the store whose connection pool lives on that runtime.
+pub struct AppContext {
+    runtime: Runtime,
+    pub store: Arc<dyn VoicerStore>,
+}
+
+impl AppContext {
+    pub fn new() -> VoicerResult<Self> {
+        let runtime = Runtime::new()?;
+        let store = runtime.block_on(store::open_store())?;
+
+        let health_store = store.clone();
+        runtime.spawn(async move {
+            let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                if !health_store.health_check().await {
+                    println!("Store is unreachable, will retry in {:?}", HEALTH_CHECK_INTERVAL);
+                }
+            }
+        });
+
+        // Notes saved before audio was compressed are also migrated lazily when played
+        let migrate_store = store.clone();
+        runtime.spawn(async move {
+            match migrate_store.migrate_legacy_audio().await {
+                Ok(0) => {}
+                Ok(count) => println!("Migrated audio of {} voice notes", count),
+                Err(err) => println!("Audio migration failed: {}", err),
+            }
+            match migrate_store.backfill_durations().await {
+                Ok(0) => {}
+                Ok(count) => println!("Stored the length of {} voice notes", count),
+                Err(err) => println!("Duration backfill failed: {}", err),
+            }
+        });
+
+        Ok(AppContext { runtime, store })
+    }
+
+    // Stands in while the real store can't be opened, so the window can still
+    // show why. Nothing is ever saved to it.
+    pub fn placeholder() -> VoicerResult<Self> {
+        let store = Arc::new(MemoryStore::new(LengthLimits::default()));
+        Ok(AppContext { runtime: Runtime::new()?, store })
+    }
+
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.runtime.handle().clone()
+    }
+}
@@ -0,0 +1,165 @@
+use std::time::Duration;
+
+use crate::audio_codec::{AudioFormat, PcmAudio};
+use crate::cleanup::{self, CleanupReport, CleanupSettings};
+
+// A finished recording the user can review and edit before publishing.
+pub struct Draft {
+    pub audio: PcmAudio,
+    pub format: AudioFormat,
+    // The recording as captured, so every edit can be undone until publishing
+    original: PcmAudio,
+    edited: bool,
+    // What the automatic clean-up did, if it ran
+    pub cleanup: Option<CleanupReport>,
+    // Selected range as fractions of the clip, start <= end
+    pub selection: Option<(f32, f32)>,
+}
+
+impl Draft {
+    pub fn new(audio: PcmAudio, format: AudioFormat) -> Self {
+        Draft { original: audio.clone(), audio, format, edited: false, cleanup: None, selection: None }
+    }
+
+    // Starts from the recording cleaned up with `settings`.
+    pub fn cleaned(audio: PcmAudio, format: AudioFormat, settings: &CleanupSettings) -> Self {
+        let mut draft = Draft::new(audio, format);
+        draft.clean_up(settings);
+        draft
+    }
+
+    // Re-runs the clean-up on the original recording, dropping other edits.
+    pub fn clean_up(&mut self, settings: &CleanupSettings) {
+        let (audio, report) = cleanup::clean_up(&self.original, settings);
+        self.edited = audio.samples != self.original.samples;
+        self.audio = audio;
+        self.cleanup = Some(report);
+        self.selection = None;
+    }
+
+    pub fn is_edited(&self) -> bool {
+        self.edited
+    }
+
+    pub fn reset(&mut self) {
+        self.audio = self.original.clone();
+        self.edited = false;
+        self.cleanup = None;
+        self.selection = None;
+    }
+
+    // Drops leading and trailing silence. Returns false if the whole clip is silent.
+    pub fn trim_silence(&mut self, threshold_db: f32) -> bool {
+        let (start, end) = match cleanup::content_range(&self.audio, threshold_db) {
+            Some(range) => range,
+            None => return false,
+        };
+        self.keep_frames(start, end);
+        true
+    }
+
+    // Removes the selected range.
+    pub fn cut_selection(&mut self) {
+        let (start, end) = match self.selected_frames() {
+            Some(range) => range,
+            None => return,
+        };
+        let channels = self.audio.channels.max(1) as usize;
+        self.audio.samples.drain(start * channels..end * channels);
+        self.edited = true;
+        self.selection = None;
+    }
+
+    // Keeps only the selected range.
+    pub fn crop_to_selection(&mut self) {
+        if let Some((start, end)) = self.selected_frames() {
+            self.keep_frames(start, end);
+        }
+    }
+
+    pub fn selected_duration(&self) -> Duration {
+        match self.selected_frames() {
+            Some((start, end)) => Duration::from_secs_f64((end - start) as f64 / self.audio.sample_rate.max(1) as f64),
+            None => Duration::ZERO,
+        }
+    }
+
+    fn keep_frames(&mut self, start: usize, end: usize) {
+        let channels = self.audio.channels.max(1) as usize;
+        self.audio.samples = self.audio.samples[start * channels..end * channels].to_vec();
+        self.edited = true;
+        self.selection = None;
+    }
+
+    fn selected_frames(&self) -> Option<(usize, usize)> {
+        let (start, end) = self.selection?;
+        let frames = self.audio.frames();
+        let start = ((start.clamp(0.0, 1.0) * frames as f32) as usize).min(frames);
+        let end = ((end.clamp(0.0, 1.0) * frames as f32) as usize).min(frames);
+        if end > start {
+            Some((start, end))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_codec::SampleFormat;
+
+    // One second at 1 kHz, so fractions of the clip are easy to count in frames
+    fn draft(samples: Vec<i16>) -> Draft {
+        let audio = PcmAudio { samples, channels: 1, sample_rate: 1000 };
+        let format = AudioFormat { channels: 1, sample_rate: 1000, bits_per_sample: 16, sample_format: SampleFormat::Int };
+        Draft::new(audio, format)
+    }
+
+    #[test]
+    fn cutting_removes_the_selection() {
+        let mut draft = draft((0..1000).map(|i| i as i16).collect());
+        draft.selection = Some((0.25, 0.5));
+        assert_eq!(draft.selected_duration(), Duration::from_millis(250));
+        draft.cut_selection();
+        assert_eq!(draft.audio.samples.len(), 750);
+        assert_eq!(draft.audio.samples[249..251], [249, 500]);
+        assert!(draft.is_edited());
+        assert!(draft.selection.is_none());
+    }
+
+    #[test]
+    fn cropping_keeps_only_the_selection() {
+        let mut draft = draft((0..1000).map(|i| i as i16).collect());
+        // The end is clamped to the clip
+        draft.selection = Some((0.9, 1.5));
+        draft.crop_to_selection();
+        assert_eq!(draft.audio.samples, (900..1000).collect::<Vec<i16>>());
+    }
+
+    #[test]
+    fn an_empty_selection_changes_nothing() {
+        let mut draft = draft(vec![1; 1000]);
+        draft.selection = Some((0.5, 0.5));
+        draft.cut_selection();
+        assert_eq!(draft.audio.samples.len(), 1000);
+        assert!(!draft.is_edited());
+    }
+
+    #[test]
+    fn reset_undoes_every_edit() {
+        let mut draft = draft((0..1000).map(|i| i as i16).collect());
+        draft.selection = Some((0.0, 0.5));
+        draft.cut_selection();
+        draft.reset();
+        assert_eq!(draft.audio.samples.len(), 1000);
+        assert!(!draft.is_edited());
+    }
+
+    #[test]
+    fn trimming_a_silent_draft_fails() {
+        let mut draft = draft(vec![0; 1000]);
+        assert!(!draft.trim_silence(-36.0));
+        assert_eq!(draft.audio.samples.len(), 1000);
+    }
+}
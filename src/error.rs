@@ -0,0 +1,156 @@
+use std::fmt;
+use std::time::Duration;
+
+use mongodb::error::{ErrorKind, WriteFailure};
+
+// MongoDB's error code for a unique index violation
+const DUPLICATE_KEY: i32 = 11000;
+// What a standalone server answers a transaction with
+const ILLEGAL_OPERATION: i32 = 20;
+
+#[derive(Debug)]
+pub enum VoicerError {
+    DuplicateUsername,
+    InvalidCredentials,
+    InvalidSession,
+    NotFound(&'static str),
+    Config(String),
+    Hashing(String),
+    Database(mongodb::error::Error),
+    Audio(String),
+    TooLong { what: &'static str, length: Duration, limit: Duration },
+    Io(std::io::Error),
+    SelfFollow,
+    // Needs MongoDB, which can't be reached right now
+    Offline,
+}
+
+pub type VoicerResult<T> = Result<T, VoicerError>;
+
+impl fmt::Display for VoicerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoicerError::DuplicateUsername => write!(f, "That username is already taken"),
+            VoicerError::InvalidCredentials => write!(f, "Wrong password"),
+            VoicerError::InvalidSession => write!(f, "Your session has expired, please log in again"),
+            VoicerError::NotFound(what) => write!(f, "{} not found", what),
+            VoicerError::Config(msg) => write!(f, "Configuration error: {}", msg),
+            VoicerError::Hashing(msg) => write!(f, "Could not hash password: {}", msg),
+            VoicerError::Database(err) => write!(f, "Database error: {}", err),
+            VoicerError::Audio(msg) => write!(f, "Audio error: {}", msg),
+            VoicerError::TooLong { what, length, limit } => write!(
+                f,
+                "{} is {:.0} seconds long, the most allowed is {} seconds",
+                what,
+                length.as_secs_f64().ceil(),
+                limit.as_secs()
+            ),
+            VoicerError::Io(err) => write!(f, "File error: {}", err),
+            VoicerError::SelfFollow => write!(f, "You can't follow yourself"),
+            VoicerError::Offline => write!(f, "You're offline; try again once you're reconnected"),
+        }
+    }
+}
+
+impl std::error::Error for VoicerError {}
+
+impl VoicerError {
+    // True when the database couldn't be reached at all, as opposed to refusing the request.
+    pub fn is_unreachable(&self) -> bool {
+        match self {
+            VoicerError::Offline => true,
+            VoicerError::Database(err) => matches!(
+                err.kind.as_ref(),
+                ErrorKind::ServerSelection { .. } | ErrorKind::Io(_) | ErrorKind::ConnectionPoolCleared { .. } | ErrorKind::DnsResolve { .. }
+            ),
+            _ => false,
+        }
+    }
+
+    // True when a write was refused by a unique index.
+    pub fn is_duplicate_key(&self) -> bool {
+        match self {
+            VoicerError::Database(err) => matches!(
+                err.kind.as_ref(),
+                ErrorKind::Write(WriteFailure::WriteError(write)) if write.code == DUPLICATE_KEY
+            ),
+            _ => false,
+        }
+    }
+
+    // True when the deployment can't run multi-document transactions.
+    pub fn is_transactions_unsupported(&self) -> bool {
+        match self {
+            VoicerError::Database(err) => match err.kind.as_ref() {
+                ErrorKind::Transaction { message, .. } => message.contains("not supported"),
+                ErrorKind::Command(command) => command.code == ILLEGAL_OPERATION,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for VoicerError {
+    fn from(err: mongodb::error::Error) -> Self {
+        VoicerError::Database(err)
+    }
+}
+
+impl From<mongodb::bson::ser::Error> for VoicerError {
+    fn from(err: mongodb::bson::ser::Error) -> Self {
+        VoicerError::Database(err.into())
+    }
+}
+
+impl From<hound::Error> for VoicerError {
+    fn from(err: hound::Error) -> Self {
+        VoicerError::Audio(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for VoicerError {
+    fn from(err: std::io::Error) -> Self {
+        VoicerError::Io(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lost_connections_are_unreachable() {
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused");
+        assert!(VoicerError::Database(mongodb::error::Error::from(io)).is_unreachable());
+        assert!(VoicerError::Offline.is_unreachable());
+        assert!(!VoicerError::InvalidCredentials.is_unreachable());
+        assert!(!VoicerError::Io(std::io::Error::new(std::io::ErrorKind::NotFound, "missing")).is_unreachable());
+    }
+
+    #[test]
+    fn only_database_errors_can_be_duplicate_keys() {
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused");
+        assert!(!VoicerError::Database(mongodb::error::Error::from(io)).is_duplicate_key());
+        assert!(!VoicerError::DuplicateUsername.is_duplicate_key());
+    }
+
+    #[test]
+    fn too_long_rounds_the_length_up() {
+        let err = VoicerError::TooLong { what: "Your quote", length: Duration::from_millis(30_200), limit: Duration::from_secs(30) };
+        assert_eq!(err.to_string(), "Your quote is 31 seconds long, the most allowed is 30 seconds");
+    }
+
+    #[test]
+    fn standalone_servers_refuse_transactions() {
+        let refused: mongodb::error::CommandError = mongodb::bson::from_document(mongodb::bson::doc! {
+            "code": ILLEGAL_OPERATION,
+            "codeName": "IllegalOperation",
+            "errmsg": "Transaction numbers are only allowed on a replica set member or mongos",
+        })
+        .unwrap();
+        let err = VoicerError::Database(mongodb::error::Error::from(ErrorKind::Command(refused)));
+        assert!(err.is_transactions_unsupported());
+        assert!(!VoicerError::Offline.is_transactions_unsupported());
+    }
+}
@@ -0,0 +1,245 @@
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use mongodb::bson::oid::ObjectId;
+
+use crate::audio_codec::{self, PcmAudio, CANONICAL_CHANNELS, CANONICAL_SAMPLE_RATE};
+use crate::backend::{self, VoiceNote};
+use crate::error::{VoicerError, VoicerResult};
+use crate::store::VoicerStore;
+
+// Silence between the post and each reply in an exported thread.
+pub const THREAD_GAP: Duration = Duration::from_millis(750);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Wav,
+    Flac,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Wav => "wav",
+            ExportFormat::Flac => "flac",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ExportFormat::Wav => "WAV",
+            ExportFormat::Flac => "FLAC (smaller)",
+        }
+    }
+}
+
+// What gets written into the file's tags.
+pub struct ExportTags {
+    pub artist: String,
+    pub title: String,
+    pub date: DateTime<Utc>,
+}
+
+impl ExportTags {
+    fn for_note(note: &VoiceNote, title: &str) -> Self {
+        ExportTags { artist: note.name.clone(), title: title.to_string(), date: note.timestamp }
+    }
+
+    fn date(&self) -> String {
+        self.date.format("%Y-%m-%dT%H:%M:%S").to_string()
+    }
+}
+
+// Where the save dialog suggests writing an export.
+pub fn default_path(v_id: ObjectId, thread: bool, format: ExportFormat) -> PathBuf {
+    let dir = dirs::download_dir().or_else(dirs::home_dir).unwrap_or_else(|| PathBuf::from("."));
+    let kind = if thread { "thread" } else { "quote" };
+    dir.join(format!("voicer-{}-{}.{}", kind, v_id.to_hex(), format.extension()))
+}
+
+// Writes one note, at playback loudness, to `path`.
+pub async fn export_note(store: &dyn VoicerStore, v_id: ObjectId, path: PathBuf, format: ExportFormat) -> VoicerResult<()> {
+    let note = store.get_voice_note(v_id).await?;
+    let audio = store.get_voice_audio(v_id).await?;
+    let audio = backend::normalize_for_playback(audio, note.loudness).await?;
+    let title = format!("Quote by {}", note.name);
+    write_export(path, audio, format, ExportTags::for_note(&note, &title)).await
+}
+
+// Writes a post followed by its replies, oldest first, with a short gap between each.
+pub async fn export_thread(store: &dyn VoicerStore, v_id: ObjectId, path: PathBuf, format: ExportFormat) -> VoicerResult<()> {
+    let post = store.get_voice_note(v_id).await?;
+    let mut notes = vec![post.clone()];
+    for reply in &post.replies {
+        // Replies deleted since the thread was loaded are left out
+        match store.get_voice_note(*reply).await {
+            Ok(note) => notes.push(note),
+            Err(VoicerError::NotFound(_)) => println!("Skipping deleted reply {}", reply),
+            Err(err) => return Err(err),
+        }
+    }
+    notes[1..].sort_by_key(|note| note.timestamp);
+
+    let mut parts = Vec::with_capacity(notes.len());
+    for note in &notes {
+        let audio = store.get_voice_audio(note._id).await?;
+        parts.push(backend::normalize_for_playback(audio, note.loudness).await?);
+    }
+    let audio = join_with_gaps(parts, THREAD_GAP)?;
+    let title = format!("Conversation started by {}", post.name);
+    write_export(path, audio, format, ExportTags::for_note(&post, &title)).await
+}
+
+// Stored notes are already canonical, so they share one format and can be joined directly.
+fn join_with_gaps(parts: Vec<PcmAudio>, gap: Duration) -> VoicerResult<PcmAudio> {
+    let mut joined = PcmAudio { samples: Vec::new(), channels: CANONICAL_CHANNELS, sample_rate: CANONICAL_SAMPLE_RATE };
+    let silence = (gap.as_secs_f64() * CANONICAL_SAMPLE_RATE as f64) as usize * CANONICAL_CHANNELS as usize;
+    for (i, part) in parts.into_iter().enumerate() {
+        if i > 0 {
+            joined.samples.extend(std::iter::repeat(0).take(silence));
+        }
+        joined.samples.extend(part.to_canonical()?.samples);
+    }
+    Ok(joined)
+}
+
+async fn write_export(path: PathBuf, audio: PcmAudio, format: ExportFormat, tags: ExportTags) -> VoicerResult<()> {
+    match format {
+        ExportFormat::Wav => {
+            let filename = path.to_string_lossy().to_string();
+            backend::convert_vec_to_audio(&filename, audio).await?;
+            append_wav_chunk(&path, b"id3 ", &id3_tag(&tags))?;
+        }
+        ExportFormat::Flac => {
+            let comments = [("ARTIST", tags.artist.clone()), ("TITLE", tags.title.clone()), ("DATE", tags.date())];
+            let bytes = tokio::task::spawn_blocking(move || audio_codec::encode_flac_tagged(&audio, &comments))
+                .await
+                .map_err(|err| VoicerError::Audio(err.to_string()))??;
+            tokio::fs::write(&path, bytes).await?;
+        }
+    }
+    println!("Exported {}", path.display());
+    Ok(())
+}
+
+// An ID3v2.4 tag with UTF-8 text frames.
+fn id3_tag(tags: &ExportTags) -> Vec<u8> {
+    let mut frames = Vec::new();
+    for (id, text) in [(b"TPE1", &tags.artist), (b"TIT2", &tags.title), (b"TDRC", &tags.date())] {
+        // Encoding byte 3 is UTF-8
+        let mut body = vec![3];
+        body.extend(text.as_bytes());
+        frames.extend(id);
+        frames.extend(syncsafe(body.len() as u32));
+        frames.extend([0, 0]);
+        frames.extend(body);
+    }
+    let mut tag = b"ID3".to_vec();
+    tag.extend([4, 0, 0]);
+    tag.extend(syncsafe(frames.len() as u32));
+    tag.extend(frames);
+    tag
+}
+
+// ID3 sizes use seven bits per byte.
+fn syncsafe(size: u32) -> [u8; 4] {
+    [(size >> 21) as u8 & 0x7f, (size >> 14) as u8 & 0x7f, (size >> 7) as u8 & 0x7f, size as u8 & 0x7f]
+}
+
+// Adds a chunk to the end of a WAV file and fixes up the RIFF size.
+fn append_wav_chunk(path: &Path, id: &[u8; 4], data: &[u8]) -> VoicerResult<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut header = [0u8; 4];
+    file.read_exact(&mut header)?;
+    if &header != b"RIFF" {
+        return Err(VoicerError::Audio("Not a WAV file".to_string()));
+    }
+
+    file.seek(SeekFrom::End(0))?;
+    file.write_all(id)?;
+    file.write_all(&(data.len() as u32).to_le_bytes())?;
+    file.write_all(data)?;
+    // Chunks are padded to an even length
+    if data.len() % 2 == 1 {
+        file.write_all(&[0])?;
+    }
+    let length = file.stream_position()?;
+    file.seek(SeekFrom::Start(4))?;
+    file.write_all(&((length - 8) as u32).to_le_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn tags() -> ExportTags {
+        ExportTags { artist: "Ada".to_string(), title: "Quote by Ada".to_string(), date: Utc.with_ymd_and_hms(2024, 3, 1, 12, 30, 0).unwrap() }
+    }
+
+    fn clip(value: i16, frames: usize) -> PcmAudio {
+        PcmAudio { samples: vec![value; frames], channels: CANONICAL_CHANNELS, sample_rate: CANONICAL_SAMPLE_RATE }
+    }
+
+    fn temp_path(extension: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("voicer-export-{}.{}", ObjectId::new().to_hex(), extension))
+    }
+
+    #[test]
+    fn syncsafe_sizes_skip_the_top_bit() {
+        assert_eq!(syncsafe(0), [0, 0, 0, 0]);
+        assert_eq!(syncsafe(127), [0, 0, 0, 127]);
+        assert_eq!(syncsafe(128), [0, 0, 1, 0]);
+        assert_eq!(syncsafe(0x0fff_ffff), [127, 127, 127, 127]);
+    }
+
+    #[test]
+    fn the_id3_tag_holds_each_frame() {
+        let tag = id3_tag(&tags());
+        assert_eq!(&tag[..6], b"ID3\x04\x00\x00");
+        assert_eq!(syncsafe(tag.len() as u32 - 10), tag[6..10]);
+        // ID, size, flags, UTF-8 marker, then the text
+        let artist = &tag[10..];
+        assert_eq!(&artist[..4], b"TPE1");
+        assert_eq!(artist[4..8], syncsafe(4));
+        assert_eq!(&artist[10..14], b"\x03Ada");
+        assert!(tag.windows(19).any(|window| window == b"2024-03-01T12:30:00"));
+    }
+
+    #[test]
+    fn threads_are_joined_with_silence_between() {
+        let joined = join_with_gaps(vec![clip(1, 10), clip(2, 5)], Duration::from_millis(10)).unwrap();
+        let gap = CANONICAL_SAMPLE_RATE as usize / 100;
+        assert_eq!(joined.samples.len(), 10 + gap + 5);
+        assert!(joined.samples[10..10 + gap].iter().all(|sample| *sample == 0));
+        assert_eq!(joined.samples[10 + gap], 2);
+    }
+
+    #[tokio::test]
+    async fn wav_exports_carry_the_tag_in_a_valid_riff() {
+        let path = temp_path("wav");
+        write_export(path.clone(), clip(500, 1001), ExportFormat::Wav, tags()).await.unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let (audio, _) = backend::convert_audio_to_vec(path.to_str().unwrap()).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize, bytes.len() - 8);
+        assert!(bytes.windows(4).any(|window| window == b"id3 "));
+        assert_eq!(audio.samples, clip(500, 1001).samples);
+    }
+
+    #[tokio::test]
+    async fn flac_exports_decode_back_to_the_audio() {
+        let path = temp_path("flac");
+        write_export(path.clone(), clip(-300, 2000), ExportFormat::Flac, tags()).await.unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(audio_codec::decode_flac(&bytes).unwrap().samples, clip(-300, 2000).samples);
+        assert!(bytes.windows(10).any(|window| window == b"ARTIST=Ada"));
+    }
+}
@@ -1,17 +1,35 @@
-use std::{fs::{self, File}, path::Path, vec};
+use std::{collections::{HashMap, HashSet}, fs::{self, File}, path::Path, vec};
 use chrono::{DateTime, Utc, TimeZone};
 pub use eframe::{run_native, egui, App};
 use egui::{Ui, Color32, Vec2};
-use crate::backend::{self, Users, publicUser, get_user_by_username, find_users_by_names};
+use crate::backend::{self, Users, publicUser};
+use crate::audio_cache::AudioCache;
+use crate::audio_codec::{AudioFormat, PcmAudio};
+use crate::audio_stream::{self, AudioStream};
+use crate::cleanup::CleanupSettings;
+use crate::context::AppContext;
+use crate::draft::Draft;
+use crate::error::{VoicerError, VoicerResult};
+use crate::export::{self, ExportFormat};
+use crate::import;
+use crate::player::Player;
+use crate::queue::{self, PlayQueue, QueueSource};
+use crate::recorder::{self, Recorder};
+use crate::session;
+use crate::store::SyncStatus;
+use crate::time_stretch;
+use crate::waveform::Waveform;
+use crate::tasks::{Task, TaskExecutor, TaskKind, TaskResult};
 use mongodb::{Client, Collection  , Database};
 use mongodb::bson::{self,oid::ObjectId};
-use tokio::{io, time::Instant, runtime::Runtime};
+use tokio::{io, time::Instant};
+use std::time::Duration;
+use std::path::PathBuf;
+use std::sync::Arc;
 use tokio;
 use std::io::BufReader;
-use rodio::{OutputStream, Sink, source::Buffered};
 use std::{process::Command};
-use backend::{connect_to_mongodb, VoiceNote};
-use record_audio::audio_clip::AudioClip as ac;
+use backend::VoiceNote;
 use egui::TextStyle;
 use egui::RichText;
 use egui::widgets::Button;
@@ -25,18 +43,74 @@ pub struct Gui {
     current_page: Page,
     error_message: Option<String>,
     userslist: Option<backend::publicUser>,
-    voicenote_vec: Option<Vec<backend::VoiceNote>>,
+    voicenote_vec: Option<Vec<backend::FeedItem>>,
+    // Where the next feed page starts; None once everything is loaded
+    feed_cursor: Option<backend::FeedCursor>,
+    // Set when loading a page fails, so scrolling doesn't retry every frame
+    feed_paused: bool,
     username: String,
     followuser: String,
     password: String,
     confirm_pass: String,
     email: String,
     user: Option<backend::Users>,
+    // Waveform and length of the logged-in user's notes, for their profile
+    profile_notes: HashMap<ObjectId, backend::FeedItem>,
     conversation: Option<backend::conversation>,
     theme: Theme,
     following: Option<Vec<backend::publicUser>>,
     followers: Option<Vec<backend::publicUser>>,
     window_style: egui::Style,
+    context: AppContext,
+    // Why the store couldn't be opened. Logging in and signing up wait for a
+    // retry to succeed
+    store_error: Option<String>,
+    tasks: TaskExecutor,
+    post_saved: bool,
+    remember_me: bool,
+    recorder: Option<Recorder>,
+    max_recording_secs: u64,
+    import_path: String,
+    draft: Option<Draft>,
+    cleanup: CleanupSettings,
+    player: Player,
+    queue: Option<PlayQueue>,
+    export: Option<ExportDialog>,
+    // Downloaded audio of the logged-in user's notes
+    audio_cache: Option<Arc<AudioCache>>,
+    // Notes whose audio is being downloaded, and those to request this frame
+    fetching: HashSet<ObjectId>,
+    fetch_requests: Vec<ObjectId>,
+    // A note played while its download was already running; the player waits
+    // on this stream until the download lands in the cache
+    awaiting_fetch: Option<(ObjectId, AudioStream)>,
+    // Failed downloads are only retried when the note is played
+    fetch_failed: HashSet<ObjectId>,
+    // Whether the store is working offline, as of the last frame
+    sync_status: SyncStatus,
+    // Offline changes that were dropped while syncing, until dismissed
+    sync_conflicts: Vec<String>,
+}
+
+// The export window, while it is open
+struct ExportDialog {
+    v_id: ObjectId,
+    // The whole conversation rather than just the note
+    thread: bool,
+    path: String,
+    format: ExportFormat,
+    // Where the last export from this window was written
+    saved: Option<PathBuf>,
+}
+
+// What the user picked on the draft review screen
+enum DraftAction {
+    Play,
+    StopPlayback,
+    Edited,
+    Discard,
+    Rerecord,
+    Publish,
 }
 
 enum Page {
@@ -80,8 +154,19 @@ impl Gui {
         }
     }
 
-    pub fn new() -> Self {
-        Self {
+    pub fn new() -> VoicerResult<Self> {
+        let (context, store_error) = match AppContext::new() {
+            Ok(context) => (context, None),
+            Err(err) => (AppContext::placeholder()?, Some(format!("Could not open the store: {}", err))),
+        };
+        let mut tasks = TaskExecutor::new(&context);
+        // Reopen logged in if a previous run asked to be remembered
+        if store_error.is_none() {
+            if let Some(token) = session::load_local_token() {
+                tasks.spawn(Task::ResumeSession { token });
+            }
+        }
+        Ok(Self {
             current_page: Page::Login,
             error_message: None,
             username: String::new(),
@@ -91,15 +176,67 @@ impl Gui {
             confirm_pass: String::new(),
             theme: Theme::default(),
             voicenote_vec: None,
+            feed_cursor: None,
+            feed_paused: false,
             userslist : None,
             user: None,
+            profile_notes: HashMap::new(),
             conversation: None,
             following: None,
             followers: None,
             window_style: egui::Style::default(),  
+            context,
+            store_error,
+            tasks,
+            post_saved: false,
+            remember_me: false,
+            recorder: None,
+            max_recording_secs: recorder::DEFAULT_MAX_DURATION.as_secs(),
+            import_path: String::new(),
+            draft: None,
+            cleanup: CleanupSettings::default(),
+            player: Player::new(),
+            queue: None,
+            export: None,
+            audio_cache: None,
+            fetching: HashSet::new(),
+            fetch_requests: Vec::new(),
+            awaiting_fetch: None,
+            fetch_failed: HashSet::new(),
+            sync_status: SyncStatus::default(),
+            sync_conflicts: Vec::new(),
+        })
+    }
+
+    // Tries opening the store again after it failed at startup.
+    fn retry_store(&mut self) {
+        match AppContext::new() {
+            Ok(context) => {
+                self.context = context;
+                self.tasks = TaskExecutor::new(&self.context);
+                self.store_error = None;
+                if let Some(token) = session::load_local_token() {
+                    self.tasks.spawn(Task::ResumeSession { token });
+                }
+            }
+            Err(err) => self.store_error = Some(format!("Could not open the store: {}", err)),
         }
     }
 
+    // The store error with a retry button, if there is one.
+    fn store_error_bar(&mut self, ui: &mut egui::Ui) {
+        let error = match &self.store_error {
+            Some(error) => error.clone(),
+            None => return,
+        };
+        ui.horizontal(|ui| {
+            ui.label(RichText::new(error).color(Color32::RED));
+            if ui.button("Retry").clicked() {
+                self.retry_store();
+            }
+        });
+    }
+
 
 fn signup_page(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
     let mut password_visible = true;
@@ -108,6 +245,7 @@ fn signup_page(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
         ui.heading("Sign up");
 
         ui.add_space(10.0);
+        self.store_error_bar(ui);
     });
     ui.vertical_centered(|ui| {
 
@@ -174,7 +312,7 @@ fn signup_page(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
                 ui.add_space(5.0);
                 ui.horizontal(|ui| {
                 ui.add_space(850.0);
-                if ui.button("Sign up").clicked() {
+                if ui.add_enabled(self.store_error.is_none(), egui::Button::new("Sign up")).clicked() {
                     // Handle signup button click
                     if self.password != self.confirm_pass {
                         // Show an error message if the passwords don't match
@@ -183,25 +321,17 @@ fn signup_page(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
                         // Show an error message if either the username or password is empty
                         self.error_message = Some("All fields are required".to_string()); // Store error message in a variable
                     } else {
-                        let runtime = Runtime::new().unwrap();
-                        let username = self.username.clone();
-                        let email = self.email.clone();
-                        let pass = self.password.clone();
-                        let (response) = runtime.block_on(async move {
-                            let response = tokio::spawn(async move {
-                                let (user_collection, voice_note_collection, db, client) =
-                                    backend::connect_to_mongodb().await;
-                                let response = backend::create_user(user_collection, email, pass, username).await;
-    
-                                response
-                            })
-                            .await
-                            .unwrap();
-                            response
+                        self.error_message = None;
+                        self.tasks.spawn(Task::SignUp {
+                            username: self.email.clone(),
+                            password: self.password.clone(),
+                            name: self.username.clone(),
                         });
-                        self.current_page = Page::Login;
                     }
                 }
+                if self.tasks.is_running(TaskKind::SignUp) {
+                    ui.spinner();
+                }
                 if let Some(error_message) = &self.error_message {
                     // Display error message if it exists
                     ui.add(egui::Label::new(error_message));
@@ -238,6 +368,7 @@ fn signup_page(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
             ui.heading(RichText::new(("Login")));
             //ui.label(RichText::new(("Test")).color(egui::Color32::DARK_RED));
             ui.add_space(10.0);
+            self.store_error_bar(ui);
             //ui.add(egui::Button::new("Test").fill(Color32::RED)).clicked();
         });
         ui.vertical_centered(|ui| {
@@ -282,35 +413,24 @@ fn signup_page(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
                     ui.add_space(510.0-(column_width-current_width));   
                     ui.add(egui::TextEdit::singleline(&mut self.password).password(true));
                 });
+                ui.horizontal(|ui| {
+                    ui.add_space(400.0);
+                    ui.checkbox(&mut self.remember_me, "Remember me");
+                });
                 ui.add_space(5.0);
                 ui.horizontal(|ui| {
                     ui.add_space(755.0);
-                    if ui.add(egui::Button::new("Login").fill(Color32::DARK_GRAY)).clicked(){
-        
-                        let runtime= Runtime::new().unwrap();
-                        let username= self.username.clone();
-                        let pass= self.password.clone();
-                        let (response) = runtime.block_on( async move
-                            {
-                                let response = tokio::spawn
-                                ( async move
-                                    {
-                                        let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                                        let response = backend::get_user_by_username(user_collection.clone(), username, pass).await;
-                                        let user_iddd= response.clone().unwrap()._id;
-                                        let response2 = backend::get_all_voice_ids_from_following(user_collection ,voice_note_collection , user_iddd).await;
-                
-                                        (response,response2)
-                                    }
-                                ).await.unwrap();
-                                response
-                            });
-
-                        self.user = response.0.clone();
-                        self.voicenote_vec = Some(response.1.clone());
-                        //println!("login successful : {:?}",response.0.clone() );
-                        println!("LOGIN SUCCESSFUL");
-                        self.current_page = Page::Home;
+                    let logging_in = self.tasks.is_running(TaskKind::Login) || self.tasks.is_running(TaskKind::ResumeSession);
+                    if ui.add_enabled(!logging_in && self.store_error.is_none(), egui::Button::new("Login").fill(Color32::DARK_GRAY)).clicked(){
+                        if self.username.is_empty() || self.password.is_empty() {
+                            self.error_message = Some("Enter your username and password".to_string());
+                        } else {
+                            self.error_message = None;
+                            self.tasks.spawn(Task::Login { username: self.username.clone(), password: self.password.clone() });
+                        }
+                    }
+                    if logging_in {
+                        ui.spinner();
                     }
                 });
                 ui.horizontal(|ui|{ ui.add_space(1170.0);
@@ -364,32 +484,23 @@ fn home_page(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
     ui.heading(format!("Welcome, {}!", self.username));
 
     // Count the number of voicenotes
-    let voicenote_count = self.voicenote_vec.clone().unwrap().len();
-    ui.label(format!("You have {} voicenotes.", voicenote_count));
+    let voicenote_count = self.voicenote_vec.as_ref().map_or(0, |notes| notes.len());
+    if self.voicenote_vec.is_none() && self.tasks.is_running(TaskKind::LoadFeed) {
+        ui.horizontal(|ui| {
+            ui.spinner();
+            ui.label("Loading your feed...");
+        });
+    } else {
+        ui.label(format!("You have {} voicenotes.", voicenote_count));
+    }
 
     ui.add_space(10.0);
 
     ui.horizontal(|ui| {
         ui.add_space(300.0);
         if ui.button("▶️ Play All").clicked() {
-            let directory = ".";
-            let files = fs::read_dir(directory).unwrap();
-            let mut filenames: Vec<String> = Vec::new(); 
-
-            for file in files {
-                if let Ok(file) = file {
-                    if let Some(extension) = file.path().extension() {
-                        if extension == "wav" {
-                            let filename = file.path().to_str().unwrap().to_owned();
-                            filenames.push(filename);
-                        }
-                    }
-                }
-            }
-
-            for filename in filenames {
-                play_audio(&filename);
-            }
+            let notes = self.voicenote_vec.iter().flatten().map(|item| item._id).collect();
+            self.play_queue(QueueSource::Feed, notes);
         }
 
         ui.horizontal(|ui|{ui.add_space(20.0);
@@ -412,66 +523,18 @@ fn home_page(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
         ui.horizontal(|ui|{ui.add_space(20.0);
         });
         if ui.button("Profile").clicked() {
-            let user = self.user.clone().unwrap();
-            let runtime = Runtime::new().unwrap();
-            let username= self.username.clone();
-            let pass= self.password.clone();
-            let (response) = runtime.block_on( async move
-                {
-                    let response = tokio::spawn
-                    ( async move
-                        {
-                            let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                            let response = backend::get_user_by_username(user_collection.clone(), username, pass).await;
-                            let user_iddd= response.clone().unwrap()._id;
-                            let response2 = backend::get_all_voice_ids_from_following(user_collection ,voice_note_collection , user_iddd).await;
-    
-                            (response,response2)
-                        }
-                    ).await.unwrap();
-                    response
-                });
-
-            self.user = response.0.clone();
-            self.voicenote_vec = Some(response.1.clone());
-            let mut delete:Vec<ObjectId> = runtime.block_on( async move
-                {
-                    let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                    let mut delete:Vec<ObjectId> = Vec::new();
-
-                    for quote in user.voice_notes {
-                        let response = backend::download_voice_notes(voice_note_collection.clone(), quote).await;
-                        if response == false {
-                            delete.push(quote);
-                        }
-                    }
-                    delete
-                }
-            );
-            for quote in &mut delete{
-                self.user.as_mut().unwrap().voice_notes.retain(|voiceid| (voiceid.to_hex())!=(quote.to_hex()));
-            }
-            self.current_page = Page::UserProfile;
+            self.tasks.spawn(Task::LoadProfile);
+        }
+        if self.tasks.is_running(TaskKind::LoadProfile) {
+            ui.spinner();
         }
 
         ui.horizontal(|ui|{ui.add_space(20.0);
         });
         if ui.button("Logout").clicked() {
-            if let Err(err) = delete_wav_files() {
-                eprintln!("Error deleting .wav files: {}", err);
-            } else {
-                println!("Successfully deleted .wav files");
-            }  
-            self.followuser.clear();
-            self.email.clear();
-            self.user= None;
-            self.conversation= None;       
-            self.error_message = None;
-            self.username.clear();
-            self.password.clear();
-            self.userslist= None;
-            self.voicenote_vec= None;
-            self.current_page = Page::Login;
+            self.tasks.cancel_all();
+            self.tasks.spawn(Task::Logout);
+            self.end_session();
         }
     });
 
@@ -481,24 +544,22 @@ fn home_page(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
 
     let mut vec_vc = self.voicenote_vec.clone();
     egui::ScrollArea::vertical().show(ui, |ui| {
-        let userid = self.user.clone().unwrap()._id;
         if let Some(vec_vc) = vec_vc{
             let voice = vec_vc;
             for i in 0..voicenote_count {
                 let voice_obj = voice[i].clone();
                 ui.horizontal(|ui|{
                     ui.add_space(350.0);
-                    ui.group(|ui| {
+                    let card = ui.group(|ui| {
                         ui.horizontal(|ui|{
                             ui.add_space(150.0);
                             ui.vertical(|ui|{
                                 ui.label(format!("Voicenote {} by {}",i+1, voice_obj.name));
+                                self.note_summary(ui, voice_obj._id, voice_obj.waveform.as_ref(), voice_obj.duration_ms);
                                 ui.horizontal(|ui| {
                                     ui.add_space(75.0);
-                                    if ui.add(egui::Button::new("▶️ Play").fill(Color32::TRANSPARENT)).clicked() {
-                                        let filename = voice_obj._id.to_hex()+".wav";
-                                        let x = play_audio(&filename);
-                                    }
+                                    self.play_button(ui, voice_obj._id);
+                                    self.export_button(ui, voice_obj._id, false);
                                 });
             
                                 let time = Utc.timestamp(voice_obj.timestamp.timestamp(), 0);
@@ -510,35 +571,14 @@ fn home_page(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
                                     ui.horizontal(|ui| {
                                         if ui.add(egui::Button::new(RichText::new(("Shut Up")).color(egui::Color32::WHITE)).fill(Color32::LIGHT_RED)).clicked() {
                                             reaction = backend::ReactionType::ShutUp;
-                                            let runtimet= Runtime::new().unwrap();
-                                            let (response) = runtimet.block_on( async move
-                                                {
-                                                    let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                                                    backend::react_to_quote(voice_note_collection, voice_obj._id,userid,reaction.clone()).await
-                                                }
-                                            );
+                                            self.tasks.spawn(Task::ReactToQuote { v_id: voice_obj._id, reaction });
                                         }
                                         if ui.add(egui::Button::new(RichText::new(("Speak Up")).color(egui::Color32::WHITE)).fill(Color32::LIGHT_GREEN)).clicked() {
                                             reaction = backend::ReactionType::SpeakUp;
-                                            let runtime= Runtime::new().unwrap();
-                                            let (response) = runtime.block_on( async move
-                                                {
-                                                    let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                                                    backend::react_to_quote(voice_note_collection, voice_obj._id,userid,reaction.clone()).await;
-                                                }
-                                            );
+                                            self.tasks.spawn(Task::ReactToQuote { v_id: voice_obj._id, reaction });
                                         }
                                         if ui.add(egui::Button::new(RichText::new(("Reply")).color(egui::Color32::WHITE)).fill(Color32::LIGHT_BLUE)).clicked() {
-                                            let runtime= Runtime::new().unwrap();
-                                            let (response) = runtime.block_on( async move
-                                                {
-                                                    let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                                                    let replies = backend::create_conversation(voice_note_collection, voice_obj._id).await;
-                            
-                                                    (replies)
-                                                });
-                                            self.conversation = Some(response);
-                                            self.current_page= Page::Conversation;
+                                            self.tasks.spawn(Task::LoadConversation { v_id: voice_obj._id });
                                         }
                                     });
                                 });
@@ -547,6 +587,26 @@ fn home_page(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
                         });
                         
                     });
+                    if ui.is_rect_visible(card.response.rect) {
+                        self.want_audio(voice_obj._id);
+                    }
+                });
+            }
+
+            // Reaching the bottom of the list fetches the next page
+            if let Some(cursor) = self.feed_cursor {
+                ui.horizontal(|ui| {
+                    ui.add_space(350.0);
+                    if self.feed_paused {
+                        if ui.button("Load more").clicked() {
+                            self.feed_paused = false;
+                        }
+                    } else {
+                        let more = ui.label("Loading more...");
+                        if ui.is_rect_visible(more.rect) && !self.tasks.is_running(TaskKind::LoadFeed) {
+                            self.tasks.spawn(Task::LoadFeed { after: Some(cursor) });
+                        }
+                    }
                 });
             }
         }
@@ -558,47 +618,34 @@ fn home_page(&mut self, ctx: &egui::Context, ui: &mut egui::Ui) {
 fn conversation(&mut self, ctx: &egui::Context, ui: &mut egui::Ui){
     ui.heading("Conversation");
     ui.add_space(10.0);
-    let folder_name = format!("{}", self.user.clone().unwrap()._id);
-    fs::create_dir_all(&folder_name).unwrap();
-    let mut file_name = ObjectId::new();
-    let directory = format!("{}/{}.wav", folder_name, file_name.to_hex());
     let mut reply = self.conversation.clone().unwrap();
-    let userid = self.user.clone().unwrap()._id;;
     
     if ui.button("Back").clicked() {
+        self.discard_recording();
         self.current_page = Page::Home;
     }
 
+    ui.horizontal(|ui| {
+        if ui.button("▶️ Play thread").clicked() {
+            let notes = std::iter::once(reply.v_id).chain(reply.replies.iter().map(|r| r._id)).collect();
+            self.play_queue(QueueSource::Conversation, notes);
+        }
+        self.speed_control(ui, "conversation");
+        self.export_button(ui, reply.v_id, true);
+    });
+
     ui.add_space(10.0);
 
-    if ui.button("Add reply").clicked() {
-        match ac::record(None) {
-            Ok(clip) => {
-                match clip.export(format!("{}" , directory).as_str()) {
-                    Ok(_) => {
-                        println!("Successfully saved!");
-                    }
-                    Err(err) => println!("Error {}", err),
-                }
-            }
-            Err(err) => println!("Error {}", err),
-        }
-        let runtime= Runtime::new().unwrap();
-        let response = runtime.block_on( async move
-            {
-                let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                let data = backend::convert_audio_to_vec(&directory).await;
-                backend::create_comment(voice_note_collection.clone(),user_collection, userid, reply.v_id.to_hex() , file_name, data).await;
-                match fs::remove_dir_all(&(Path::new(&folder_name))) {
-                    Ok(_) => println!("Directory deleted successfully"),
-                    Err(err) => println!("Error deleting directory: {}", err),
-                }
-                let replies = backend::create_conversation(voice_note_collection, reply.v_id).await;
-        
-                (replies)
-            });
-        self.conversation = Some(response);
-        self.current_page= Page::Conversation;   
+    ui.label("Add a reply:");
+    let limit = self.context.store.length_limits().max_reply;
+    if let Some((recording, format)) = self.record_and_review(ui, limit) {
+        self.tasks.spawn(Task::CreateComment { v_id: reply.v_id, recording, format });
+    }
+    if self.tasks.is_running(TaskKind::CreateComment) {
+        ui.horizontal(|ui| {
+            ui.spinner();
+            ui.label("Publishing reply...");
+        });
     }
 
     let mut reply_count = reply.replies.len();
@@ -608,14 +655,13 @@ fn conversation(&mut self, ctx: &egui::Context, ui: &mut egui::Ui){
         // Display voicenote posts
         for i in 0..reply_count {
             let voice = voice_obj[i].clone();
-            ui.group(|ui| {
+            let card = ui.group(|ui| {
                 ui.label(format!("Reply {} by {}",i+1, voice.user_id.1));
+                self.note_summary(ui, voice._id, voice.waveform.as_ref(), voice.duration_ms);
 
                 ui.horizontal(|ui| {
-                    if ui.button("▶️ Play").clicked() {
-                        let filename = voice._id.to_hex()+".wav";
-                        let x = play_audio(&filename);
-                    }
+                    self.play_button(ui, voice._id);
+                    self.export_button(ui, voice._id, false);
                 });
 
                 let mut reaction = backend::ReactionType::SpeakUp;
@@ -623,40 +669,27 @@ fn conversation(&mut self, ctx: &egui::Context, ui: &mut egui::Ui){
                     ui.horizontal(|ui| {
                         if ui.add(egui::Button::new("Shut Up").fill(Color32::LIGHT_RED)).clicked() {
                             reaction = backend::ReactionType::ShutUp;
-                            let runtime= Runtime::new().unwrap();
-                            let (response) = runtime.block_on( async move
-                                {
-                                    let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                                    backend::react_to_quote(voice_note_collection, voice._id,userid,reaction.clone()).await;
-                                }
-                            );
+                            self.tasks.spawn(Task::ReactToQuote { v_id: voice._id, reaction });
                         }
                         if ui.add(egui::Button::new("Shut Up").fill(Color32::LIGHT_GREEN)).clicked() {
                             reaction = backend::ReactionType::SpeakUp;
-                            let runtime= Runtime::new().unwrap();
-                            let (response) = runtime.block_on( async move
-                                {
-                                    let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                                    backend::react_to_quote(voice_note_collection, voice._id,userid,reaction.clone()).await;
-                                }
-                            );
+                            self.tasks.spawn(Task::ReactToQuote { v_id: voice._id, reaction });
                         }
                     });
                 });
 
                 
             });
+            if ui.is_rect_visible(card.response.rect) {
+                self.want_audio(voice._id);
+            }
         }
     });
 }
 
 fn tweet_page(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
     let column_width = ui.available_width();
-    let folder_name = format!("{}", self.user.clone().unwrap()._id);
-    fs::create_dir_all(&folder_name).unwrap();
-    let mut file_name: ObjectId = ObjectId::new();
-    let directory = format!("{}/{}.wav", folder_name, file_name.to_hex());
-    let mut is_saved = false; // Flag to indicate if voicenote is successfully saved
+    let publishing = self.tasks.is_running(TaskKind::CreatePost);
 
     ui.add_space(40.0);
     ui.horizontal(|ui| {
@@ -665,41 +698,31 @@ fn tweet_page(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
     ui.horizontal(|ui|{
         ui.heading(RichText::new("Your voicenote can now be recorded..."));
         ui.add_space(200.0);
-        if ui.button("Record").clicked() {
-            match ac::record(None) {
-                Ok(clip) => {
-                    match clip.export(format!("{}" , directory).as_str()) {
-                        Ok(_) => {
-                            println!("Successfully saved!");
-                        }
-                        Err(err) => println!("Error {}", err),
-                    }
-                }
-                Err(err) => println!("Error {}", err),
-            }
-            let userid =self.user.clone().unwrap()._id;;
-            let runtime= Runtime::new().unwrap();
-            let response = runtime.block_on( async move
-                {
-                    let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                    let data = backend::convert_audio_to_vec(&directory).await;
-                    backend::create_post(voice_note_collection,user_collection, userid, data, file_name).await;
-                    match fs::remove_dir_all(&(Path::new(&folder_name))) {
-                        Ok(_) => println!("Directory deleted successfully"),
-                        Err(err) => println!("Error deleting directory: {}", err),
-                    }
-                    is_saved = true;
-                });        
-        }
-        ui.add_space(100.0);
         if ui.button("Back").clicked() {
+            self.post_saved = false;
+            self.discard_recording();
             self.current_page = Page::Home;
         }
     
     });
-    // Record button
+    ui.add_space(10.0);
+
+    let limit = self.context.store.length_limits().max_post;
+    if let Some((recording, format)) = self.record_and_review(ui, limit) {
+        self.post_saved = false;
+        self.tasks.spawn(Task::CreatePost { recording, format });
+    }
+    if self.draft.is_none() && self.recorder.is_none() {
+        self.import_controls(ui);
+    }
+    if publishing {
+        ui.horizontal(|ui| {
+            ui.spinner();
+            ui.label("Publishing...");
+        });
+    }
     
-    if is_saved {
+    if self.post_saved {
         ui.label("Voicenote saved successfully!");
     }
 
@@ -725,17 +748,10 @@ fn FollowPage(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
             });
             if ui.button("Search").clicked() 
             {   
-                let user2=self.followuser.clone();
-                let userid2= self.user.clone().unwrap()._id;
-                let runtime= Runtime::new().unwrap();
-                    let (userlistr) = runtime.block_on( async move
-                        {
-                            let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                            let userlistr=find_users_by_names(user_collection, & user2, userid2).await;
-                            userlistr
-                        });
-                self.userslist=Some(userlistr);
-                self.current_page=Page::FollowerProfile;
+                self.tasks.spawn(Task::SearchUser { username: self.followuser.clone() });
+            }
+            if self.tasks.is_running(TaskKind::SearchUser) {
+                ui.spinner();
             }
             if ui.button("Home").clicked() {
                 self.current_page = Page::Home;
@@ -756,18 +772,9 @@ fn FollowPage(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
         ui.label(format!("Followers: {}", user.followers.len()));
         ui.label(format!("Following: {}", user.following.len()));
         
-        if ui.button("Follow").clicked() {
-            let mut myuser=self.user.clone().unwrap()._id;
+        if ui.add_enabled(!self.tasks.is_running(TaskKind::Follow), egui::Button::new("Follow")).clicked() {
             let mut myfol=self.userslist.clone().unwrap()._id;
-            let runtime= Runtime::new().unwrap();
-                let (userlistr) = runtime.block_on( async move
-                    {
-                        let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                        backend::follow(user_collection.clone(), myuser, myfol).await;
-
-                    });
-            ui.label(format!("Successfull"));
-            self.current_page=Page::Home;
+            self.tasks.spawn(Task::Follow { fuser_id: myfol });
         }
         if ui.button("Back").clicked() {
             self.current_page = Page::Home;
@@ -789,71 +796,59 @@ fn FollowPage(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
             let current_width = ui.available_width();
             ui.text_edit_singleline(&mut your_info.description);
             if ui.button("Update Bio").clicked() {
-                let runtime= Runtime::new().unwrap();
-                let (userlistr) = runtime.block_on( async move
-                    {
-                        let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                        backend::update_description_by_username(user_collection, &your_info.username, &your_info.description).await;
-                    });
-                    ui.label(format!("Successfull"));
-                    self.current_page=Page::Home;
+                self.tasks.spawn(Task::UpdateBio { description: your_info.description.clone() });
             }
         });
         let followers_count = your_info.followers.len();
         let following_count = your_info.following.len();
         
         if ui.add(egui::Button::new(format!("Followers: {}", followers_count))).clicked() {
-            let runtime= Runtime::new().unwrap();
-            let (userlistr) = runtime.block_on( async move
-                {
-                    let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                    backend::get_all_followers_profile(user_collection, your_info._id).await
-                });
-            self.followers = Some(userlistr);
-            self.current_page=Page::Followers;
+            self.tasks.spawn(Task::LoadFollowers);
         };
 
         if ui.add(egui::Button::new(format!("Following: {}", following_count))).clicked() {
-            let runtime= Runtime::new().unwrap();
-            let (userlistr) = runtime.block_on( async move
-                {
-                    let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                    backend::get_all_following_profile(user_collection, your_info._id).await
-                });
-            self.following = Some(userlistr);
-            self.current_page=Page::Following;
+            self.tasks.spawn(Task::LoadFollowing);
         };
         
         let mut quotes_count = your_info.voice_notes.len();
-
+        if ui.add_enabled(quotes_count > 0, egui::Button::new("▶️ Play All")).clicked() {
+            self.play_queue(QueueSource::Profile, your_info.voice_notes.clone());
+        }
 
         egui::ScrollArea::vertical().show(ui, |ui| {
             for i in 0..quotes_count {
-                ui.group(|ui| {
+                let card = ui.group(|ui| {
                     ui.label(format!("Quote {}",i+1));
+                    let summary = self.profile_notes.get(&your_info.voice_notes[i]).map(|note| (note.waveform.clone(), note.duration_ms));
+                    if let Some((waveform, duration_ms)) = summary {
+                        self.note_summary(ui, your_info.voice_notes[i], waveform.as_ref(), duration_ms);
+                    }
 
                     ui.horizontal(|ui| {
-                        if ui.button("▶️ Play").clicked() {
-                            let filename = your_info.voice_notes[i].to_hex()+".wav";
-                            let x = play_audio(&filename);
-                        }
+                        self.play_button(ui, your_info.voice_notes[i]);
+                        self.export_button(ui, your_info.voice_notes[i], false);
                         let post = your_info.voice_notes[i].clone();
                         if ui.add(egui::Button::new(RichText::new(("Delete")).color(egui::Color32::WHITE)).fill(Color32::RED)).clicked() {
-                            let runtime= Runtime::new().unwrap();
-                            let (userlistr) = runtime.block_on( async move
-                            {
-                                let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                                backend::delete_post(voice_note_collection, user_collection, post, your_info._id).await
-                            });
+                            self.tasks.spawn(Task::DeletePost { v_id: post });
                         }
                     });
                 });
+                if ui.is_rect_visible(card.response.rect) {
+                    self.want_audio(your_info.voice_notes[i]);
+                }
             }
         });
 
-        if ui.button("HOME").clicked() {
-            self.current_page= Page::Home;
-        }
+        ui.horizontal(|ui| {
+            if ui.button("HOME").clicked() {
+                self.current_page= Page::Home;
+            }
+            // Revokes every session for this account, including other devices
+            if ui.button("Log out everywhere").clicked() {
+                self.tasks.cancel_all();
+                self.tasks.spawn(Task::LogoutEverywhere);
+            }
+        });
 
     }
 
@@ -873,16 +868,8 @@ fn FollowPage(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
                     ui.label(format!("Following: {}", user.following.len()));
                     
                     if ui.add(egui::Button::new(RichText::new(("Unfollow")).color(egui::Color32::RED))).clicked() {
-                        let mut myuser=self.user.clone().unwrap()._id;
-                        let mut following=user._id.clone();
-                        let runtime= Runtime::new().unwrap();
-                        let (userlistr) = runtime.block_on( async move
-                        {
-                            let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                            backend::unfollow(user_collection.clone(), myuser, following).await
-                        });
-                        self.following = Some(userlistr);
-                        ui.label(format!("Successfull"));
+                                    let mut following=user._id.clone();
+                        self.tasks.spawn(Task::Unfollow { fuser_id: following });
                     }
                 });
             }
@@ -908,16 +895,8 @@ fn FollowPage(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
                     ui.label(format!("Following: {}", user.following.len()));
                     
                     if ui.add(egui::Button::new(RichText::new(("Remove")).color(egui::Color32::WHITE)).fill(Color32::RED)).clicked() {
-                        let mut myuser=self.user.clone().unwrap()._id;
-                        let mut follower=user._id.clone();
-                        let runtime= Runtime::new().unwrap();
-                        let (userlistr) = runtime.block_on( async move
-                        {
-                            let (user_collection, voice_note_collection, db, client) = backend::connect_to_mongodb().await;
-                            backend::remove_follower(user_collection.clone(), myuser, follower).await
-                        });
-                        self.following = Some(userlistr);
-                        ui.label(format!("Successfull"));
+                                    let mut follower=user._id.clone();
+                        self.tasks.spawn(Task::RemoveFollower { follower_id: follower });
                     }
                 });
             }
@@ -926,12 +905,845 @@ fn FollowPage(&mut self, _ctx: &egui::Context, ui: &mut egui::Ui) {
             self.current_page = Page::Home;
         } 
     }
+
+    fn handle_task_result(&mut self, result: TaskResult) {
+        match result {
+            TaskResult::SignedUp(_) => {
+                self.current_page = Page::Login;
+            }
+            TaskResult::LoggedIn(user, token) => {
+                println!("LOGIN SUCCESSFUL");
+                if self.remember_me {
+                    if let Err(err) = session::save_local_token(&token) {
+                        println!("Could not remember session: {}", err);
+                    }
+                }
+                self.tasks.set_session(Some(token));
+                match AudioCache::open(user._id) {
+                    Ok(cache) => self.audio_cache = Some(Arc::new(cache)),
+                    Err(err) => self.error_message = Some(format!("Could not open the audio cache: {}", err)),
+                }
+                self.tasks.set_cache(self.audio_cache.clone());
+                self.tasks.spawn(Task::LoadFeed { after: None });
+                self.username = user.username.clone();
+                self.password.clear();
+                let _ = self.player.set_speed(user.playback_speed);
+                self.user = Some(user);
+                self.voicenote_vec = None;
+                self.current_page = Page::Home;
+            }
+            TaskResult::FeedLoaded { page, append } => {
+                self.feed_cursor = page.next;
+                match self.voicenote_vec.as_mut() {
+                    Some(notes) if append => {
+                        // Keep feed listening going into the newly loaded page
+                        if let Some(queue) = self.queue.as_mut().filter(|q| q.source == QueueSource::Feed) {
+                            queue.extend(page.items.iter().map(|item| item._id));
+                        }
+                        notes.extend(page.items)
+                    }
+                    _ => self.voicenote_vec = Some(page.items),
+                }
+            }
+            TaskResult::SessionRevoked => {}
+            TaskResult::LoggedOut => {
+                self.end_session();
+            }
+            TaskResult::ProfileLoaded(user, notes) => {
+                self.tasks.spawn(Task::LoadFeed { after: None });
+                self.profile_notes = notes.into_iter().map(|note| (note._id, note)).collect();
+                self.user = Some(user);
+                self.current_page = Page::UserProfile;
+            }
+            TaskResult::Reacted => {}
+            TaskResult::ConversationLoaded(conversation) => {
+                self.conversation = Some(conversation);
+                self.current_page = Page::Conversation;
+            }
+            TaskResult::AudioImported(audio, format) => {
+                // Imported clips get the same clean-up and review as recordings
+                if let Page::MyTweet = self.current_page {
+                    self.recorder = None;
+                    self.draft = Some(Draft::cleaned(audio, format, &self.cleanup));
+                }
+            }
+            TaskResult::PostCreated => {
+                self.post_saved = true;
+            }
+            TaskResult::PostDeleted(v_id) => {
+                if let Some(user) = self.user.as_mut() {
+                    user.voice_notes.retain(|id| *id != v_id);
+                }
+            }
+            TaskResult::UserFound(user) => {
+                self.userslist = Some(user);
+                self.current_page = Page::FollowerProfile;
+            }
+            TaskResult::Followed(result) => {
+                if result == backend::FollowResult::AlreadyFollowing {
+                    self.error_message = Some("You already follow that user".to_string());
+                }
+                self.current_page = Page::Home;
+            }
+            TaskResult::FollowersLoaded(users) => {
+                self.followers = Some(users);
+                self.current_page = Page::Followers;
+            }
+            TaskResult::FollowingLoaded(users) => {
+                self.following = Some(users);
+                self.current_page = Page::Following;
+            }
+            TaskResult::BioUpdated => {
+                self.current_page = Page::Home;
+            }
+            TaskResult::PlaybackSpeedSaved => {}
+            TaskResult::AudioFetched { fetched, failed } => {
+                for v_id in fetched.iter().chain(&failed) {
+                    self.fetching.remove(v_id);
+                }
+                self.fetch_failed.extend(&failed);
+                for &v_id in &fetched {
+                    self.fetch_arrived(v_id, true);
+                }
+                for &v_id in &failed {
+                    self.fetch_arrived(v_id, false);
+                }
+            }
+            TaskResult::Exported(path) => {
+                if let Some(dialog) = self.export.as_mut() {
+                    dialog.saved = Some(path);
+                }
+            }
+            TaskResult::Failed(kind, err) => {
+                println!("{} failed: {}", kind.label(), err);
+                if kind == TaskKind::LoadFeed {
+                    self.feed_paused = true;
+                }
+                if kind == TaskKind::FetchAudio {
+                    self.forget_fetches();
+                }
+                match err {
+                    // A stale remembered token just means logging in again
+                    VoicerError::InvalidSession if kind == TaskKind::ResumeSession => session::clear_local_token(),
+                    VoicerError::InvalidSession => {
+                        self.tasks.cancel_all();
+                        self.end_session();
+                        self.error_message = Some(err.to_string());
+                    }
+                    _ => self.error_message = Some(err.to_string()),
+                }
+            }
+        }
+    }
+
+    // Forgets the logged-in user locally; revoking the token is up to the caller.
+    fn end_session(&mut self) {
+        session::clear_local_token();
+        self.tasks.set_session(None);
+        // Cached audio stays on disk for the next login
+        self.tasks.set_cache(None);
+        self.audio_cache = None;
+        self.discard_recording();
+        self.player.stop();
+        let _ = self.player.set_speed(1.0);
+        self.queue = None;
+        self.awaiting_fetch = None;
+        self.forget_fetches();
+        self.fetch_requests.clear();
+        self.fetch_failed.clear();
+        self.export = None;
+        self.followuser.clear();
+        self.email.clear();
+        self.user= None;
+        self.conversation= None;
+        self.error_message = None;
+        self.username.clear();
+        self.password.clear();
+        self.userslist= None;
+        self.voicenote_vec= None;
+        self.feed_cursor = None;
+        self.feed_paused = false;
+        self.current_page = Page::Login;
+    }
+
+    // Picks an audio file by path, or by dropping it on the window.
+    fn import_controls(&mut self, ui: &mut egui::Ui) {
+        let importing = self.tasks.is_running(TaskKind::ImportAudio);
+        let mut path = None;
+        ui.add_space(10.0);
+        ui.label(format!("Or upload a file ({}), or drop one on this window:", import::SUPPORTED_EXTENSIONS.join(", ")));
+        ui.horizontal(|ui| {
+            ui.text_edit_singleline(&mut self.import_path);
+            if ui.add_enabled(!importing && !self.import_path.trim().is_empty(), egui::Button::new("Upload file")).clicked() {
+                path = Some(PathBuf::from(self.import_path.trim()));
+            }
+            if importing {
+                ui.spinner();
+            }
+        });
+        let dropped = ui.ctx().input(|input| input.raw.dropped_files.iter().find_map(|file| file.path.clone()));
+        if let Some(dropped) = dropped.filter(|_| !importing) {
+            self.import_path = dropped.display().to_string();
+            path = Some(dropped);
+        }
+        if let Some(path) = path {
+            self.tasks.spawn(Task::ImportAudio { path });
+        }
+    }
+
+    // Recording controls, then the review screen once something was recorded.
+    // Returns the clip when the user publishes it. `limit` is the longest note
+    // the store accepts here.
+    fn record_and_review(&mut self, ui: &mut egui::Ui, limit: Duration) -> Option<(PcmAudio, AudioFormat)> {
+        if self.draft.is_some() {
+            return self.draft_review(ui, limit);
+        }
+        if let Some((audio, format)) = self.recording_controls(ui, limit) {
+            self.draft = Some(Draft::cleaned(audio, format, &self.cleanup));
+        }
+        None
+    }
+
+    fn draft_review(&mut self, ui: &mut egui::Ui, limit: Duration) -> Option<(PcmAudio, AudioFormat)> {
+        // The draft is the only thing the player holds without a note id
+        let playing = self.player.is_active() && self.player.now_playing().is_none();
+        let mut action = None;
+        let mut silent = false;
+        let mut settings = self.cleanup;
+        let draft = self.draft.as_mut()?;
+
+        ui.group(|ui| {
+            ui.label(format!("Your recording: {}", format_duration(draft.audio.duration())));
+            if let Some(report) = &draft.cleanup {
+                ui.label(report.summary());
+            }
+            if draw_waveform(ui, &draft.audio, &mut draft.selection) {
+                action = Some(DraftAction::Edited);
+            }
+            ui.horizontal(|ui| {
+                if playing {
+                    if ui.button("⏹ Stop").clicked() {
+                        action = Some(DraftAction::StopPlayback);
+                    }
+                } else if ui.button("▶️ Play").clicked() {
+                    action = Some(DraftAction::Play);
+                }
+                if ui.button("Trim silence").clicked() {
+                    silent = !draft.trim_silence(settings.silence_threshold_db);
+                    action = Some(DraftAction::Edited);
+                }
+                let selected = draft.selected_duration() > Duration::ZERO;
+                if ui.add_enabled(selected, egui::Button::new("Cut selection")).clicked() {
+                    draft.cut_selection();
+                    action = Some(DraftAction::Edited);
+                }
+                if ui.add_enabled(selected, egui::Button::new("Keep selection")).clicked() {
+                    draft.crop_to_selection();
+                    action = Some(DraftAction::Edited);
+                }
+                if ui.add_enabled(draft.is_edited(), egui::Button::new("Reset")).clicked() {
+                    draft.reset();
+                    action = Some(DraftAction::Edited);
+                }
+            });
+            if draft.selected_duration() > Duration::ZERO {
+                ui.label(format!("Selected {}", format_duration(draft.selected_duration())));
+            }
+            egui::CollapsingHeader::new("Clean-up settings").show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut settings.trim_silence, "Trim silence quieter than");
+                    ui.add(egui::Slider::new(&mut settings.silence_threshold_db, -60.0..=-12.0).suffix(" dB"));
+                });
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut settings.noise_gate, "Mute background noise quieter than");
+                    ui.add(egui::Slider::new(&mut settings.gate_threshold_db, -70.0..=-20.0).suffix(" dB"));
+                });
+                // Starts again from the original, so any manual edits are lost
+                if ui.button("Re-apply to original").clicked() {
+                    draft.clean_up(&settings);
+                    action = Some(DraftAction::Edited);
+                }
+            });
+            ui.horizontal(|ui| {
+                if ui.add(egui::Button::new(RichText::new("Discard").color(Color32::WHITE)).fill(Color32::RED)).clicked() {
+                    action = Some(DraftAction::Discard);
+                }
+                if ui.button("Re-record").clicked() {
+                    action = Some(DraftAction::Rerecord);
+                }
+                let too_long = draft.audio.duration() > limit;
+                if ui.add_enabled(draft.audio.frames() > 0 && !too_long, egui::Button::new("Publish")).clicked() {
+                    action = Some(DraftAction::Publish);
+                }
+            });
+            if draft.audio.duration() > limit {
+                ui.label(RichText::new(format!("Too long to publish: cut it down to {} or less", format_duration(limit))).color(Color32::RED));
+            }
+        });
+
+        self.cleanup = settings;
+        if silent {
+            self.error_message = Some("The recording is silent, nothing to keep".to_string());
+        }
+        match action? {
+            DraftAction::Play => self.play_draft(),
+            DraftAction::StopPlayback => self.player.stop(),
+            DraftAction::Edited => {
+                if playing {
+                    self.player.stop();
+                }
+            }
+            DraftAction::Discard => self.discard_recording(),
+            DraftAction::Rerecord => {
+                self.discard_recording();
+                match Recorder::start(self.recording_length(limit)) {
+                    Ok(recorder) => self.recorder = Some(recorder),
+                    Err(err) => self.error_message = Some(err.to_string()),
+                }
+            }
+            DraftAction::Publish => {
+                if playing {
+                    self.player.stop();
+                }
+                let draft = self.draft.take()?;
+                return Some((draft.audio, draft.format));
+            }
+        }
+        None
+    }
+
+    fn play_draft(&mut self) {
+        let audio = match &self.draft {
+            Some(draft) => draft.audio.clone(),
+            None => return,
+        };
+        if let Err(err) = self.player.play(None, audio) {
+            self.error_message = Some(err.to_string());
+        }
+    }
+
+    // Drops any recording in progress or waiting for review.
+    fn discard_recording(&mut self) {
+        if self.draft.is_some() && self.player.is_active() && self.player.now_playing().is_none() {
+            self.player.stop();
+        }
+        self.recorder = None;
+        self.draft = None;
+    }
+
+    // Replaces the queue with `notes` and starts the first one.
+    fn play_queue(&mut self, source: QueueSource, notes: Vec<ObjectId>) {
+        if notes.is_empty() {
+            return;
+        }
+        let gap = self.queue.as_ref().map_or(queue::DEFAULT_GAP, |queue| queue.gap);
+        let mut queue = PlayQueue::new(source, notes, gap);
+        let first = queue.start();
+        self.queue = Some(queue);
+        if let Some(first) = first {
+            self.play_note(first);
+        }
+    }
+
+    // Starts the note the queue just moved to.
+    fn play_queued(&mut self, next: Option<ObjectId>) {
+        if let Some(next) = next {
+            self.play_note(next);
+        }
+    }
+
+    // Auto-advances the queue once its note finishes.
+    fn advance_queue(&mut self) {
+        let queue = match self.queue.as_mut() {
+            Some(queue) => queue,
+            None => return,
+        };
+        if self.player.is_active() {
+            if self.player.now_playing() != queue.current() {
+                queue.interrupt();
+            }
+            return;
+        }
+        let next = queue.on_idle();
+        self.play_queued(next);
+    }
+
+    fn play_note(&mut self, v_id: ObjectId) {
+        // Picking a queued note from its card moves the queue there
+        if let Some(queue) = self.queue.as_mut().filter(|queue| queue.contains(v_id)) {
+            queue.start_at(v_id);
+        }
+        // Cached notes play from disk; anything else streams in as it downloads
+        let stream = match self.audio_cache.as_ref().and_then(|cache| cache.path(v_id)) {
+            Some(path) => audio_stream::stream_file(&path),
+            // Downloading it twice would only race the prefetch into the cache
+            None if self.fetching.contains(&v_id) => {
+                let stream = AudioStream::for_note();
+                self.awaiting_fetch = Some((v_id, stream.clone()));
+                Ok(stream)
+            }
+            None => {
+                let stream = AudioStream::for_note();
+                self.fetch_failed.remove(&v_id);
+                self.tasks.spawn(Task::StreamAudio { v_id, stream: stream.clone() });
+                Ok(stream)
+            }
+        };
+        if let Err(err) = stream.and_then(|stream| self.player.play_stream(Some(v_id), stream)) {
+            self.error_message = Some(err.to_string());
+        }
+        if let Some(queue) = self.queue.as_ref().filter(|queue| queue.current() == Some(v_id)) {
+            for next in queue.upcoming(queue::PREFETCH_AHEAD) {
+                self.want_audio(next);
+            }
+        }
+    }
+
+    // Asks for a note's audio to be downloaded, unless it is cached or on its way.
+    fn want_audio(&mut self, v_id: ObjectId) {
+        let cached = self.audio_cache.as_ref().map_or(false, |cache| cache.contains(v_id));
+        if !cached && !self.fetch_failed.contains(&v_id) && self.fetching.insert(v_id) {
+            self.fetch_requests.push(v_id);
+        }
+    }
+
+    // Downloads everything asked for this frame in one task.
+    fn request_fetches(&mut self) {
+        if !self.fetch_requests.is_empty() {
+            let notes = std::mem::take(&mut self.fetch_requests);
+            self.tasks.spawn(Task::FetchAudio { notes });
+        }
+    }
+
+    // Forgets downloads that will never report back, so they can be asked for again.
+    fn forget_fetches(&mut self) {
+        self.fetching.clear();
+        if let Some((v_id, _)) = self.awaiting_fetch {
+            self.fetch_arrived(v_id, false);
+        }
+    }
+
+    // Hands a finished download to the player if it is still waiting on it,
+    // streaming the note instead if the download didn't make it to the cache.
+    fn fetch_arrived(&mut self, v_id: ObjectId, cached: bool) {
+        let stream = match self.awaiting_fetch.take() {
+            Some((waiting, stream)) if waiting == v_id => stream,
+            other => {
+                self.awaiting_fetch = other;
+                return;
+            }
+        };
+        if self.player.now_playing() != Some(v_id) {
+            return;
+        }
+        let path = if cached { self.audio_cache.as_ref().and_then(|cache| cache.path(v_id)) } else { None };
+        match path {
+            Some(path) => {
+                if let Err(err) = audio_stream::fill_from_file(&path, &stream) {
+                    stream.finish();
+                    self.error_message = Some(err.to_string());
+                }
+            }
+            None => {
+                self.fetch_failed.remove(&v_id);
+                self.tasks.spawn(Task::StreamAudio { v_id, stream });
+            }
+        }
+    }
+
+    // Play/pause toggle and speed for a note's card, marking the note that is playing.
+    fn play_button(&mut self, ui: &mut egui::Ui, v_id: ObjectId) {
+        let playing = self.player.now_playing() == Some(v_id);
+        if !playing {
+            if ui.button("▶️ Play").clicked() {
+                self.play_note(v_id);
+            }
+        } else if self.player.is_paused() {
+            if ui.button("▶️ Resume").clicked() {
+                self.player.resume();
+            }
+        } else if ui.button("⏸ Pause").clicked() {
+            self.player.pause();
+        }
+        self.speed_control(ui, v_id);
+        if playing && self.player.is_buffering() {
+            ui.spinner();
+            ui.label("Buffering...");
+        } else if playing {
+            ui.label(RichText::new("🔊 Now playing").color(Color32::LIGHT_BLUE));
+        }
+    }
+
+    // A note's length and stored waveform, with the part already played highlighted.
+    fn note_summary(&mut self, ui: &mut egui::Ui, v_id: ObjectId, waveform: Option<&Waveform>, duration_ms: Option<u64>) {
+        ui.horizontal(|ui| {
+            if let Some(waveform) = waveform.filter(|waveform| waveform.len() > 0) {
+                self.note_waveform(ui, v_id, waveform);
+            }
+            if let Some(ms) = duration_ms {
+                ui.label(format_duration(Duration::from_millis(ms)));
+            }
+        });
+    }
+
+    // Clicking the waveform while the note plays seeks there.
+    fn note_waveform(&mut self, ui: &mut egui::Ui, v_id: ObjectId, waveform: &Waveform) {
+        let playing = self.player.now_playing() == Some(v_id);
+        let total = self.player.duration().as_secs_f32();
+        let progress = if playing && total > 0.0 { Some(self.player.position().as_secs_f32() / total) } else { None };
+
+        let (rect, response) = ui.allocate_exact_size(Vec2::new(200.0, 32.0), egui::Sense::click());
+        let painter = ui.painter_at(rect);
+        let bar = rect.width() / waveform.len() as f32;
+        let played = Color32::LIGHT_BLUE;
+        let unplayed = ui.visuals().weak_text_color();
+        for (i, (min, max)) in waveform.buckets().enumerate() {
+            let x = rect.left() + (i as f32 + 0.5) * bar;
+            let color = match progress {
+                Some(progress) if (i as f32 + 0.5) / waveform.len() as f32 <= progress => played,
+                _ => unplayed,
+            };
+            let top = rect.center().y - max * rect.height() / 2.0;
+            let bottom = (rect.center().y - min * rect.height() / 2.0).max(top + 1.0);
+            painter.line_segment([egui::pos2(x, top), egui::pos2(x, bottom)], egui::Stroke::new(bar.max(1.0), color));
+        }
+        if let Some(progress) = progress {
+            let x = rect.left() + progress * rect.width();
+            painter.line_segment([egui::pos2(x, rect.top()), egui::pos2(x, rect.bottom())], egui::Stroke::new(1.0, played));
+        }
+
+        if let (true, Some(pointer)) = (playing && response.clicked(), response.interact_pointer_pos()) {
+            let fraction = ((pointer.x - rect.left()) / rect.width()).clamp(0.0, 1.0);
+            if let Err(err) = self.player.seek(self.player.duration().mul_f32(fraction)) {
+                self.error_message = Some(err.to_string());
+            }
+        }
+    }
+
+    // Playback speed picker. The speed is one preference for the user, saved with their account.
+    fn speed_control(&mut self, ui: &mut egui::Ui, id_source: impl std::hash::Hash) {
+        const SPEEDS: [f32; 8] = [0.5, 0.75, 1.0, 1.25, 1.5, 2.0, 2.5, 3.0];
+        let mut speed = self.player.speed();
+        egui::ComboBox::from_id_source(("speed", id_source))
+            .width(60.0)
+            .selected_text(format!("{}x", speed))
+            .show_ui(ui, |ui| {
+                for option in SPEEDS {
+                    ui.selectable_value(&mut speed, option, format!("{}x", option));
+                }
+            });
+        if speed != self.player.speed() {
+            self.set_playback_speed(speed);
+        }
+    }
+
+    fn set_playback_speed(&mut self, speed: f32) {
+        let speed = speed.clamp(time_stretch::MIN_SPEED, time_stretch::MAX_SPEED);
+        if let Err(err) = self.player.set_speed(speed) {
+            self.error_message = Some(err.to_string());
+        }
+        if let Some(user) = self.user.as_mut() {
+            user.playback_speed = speed;
+        }
+        self.tasks.spawn(Task::SetPlaybackSpeed { speed });
+    }
+
+    // Start/Pause/Stop controls with a timer and input level meter. Returns the
+    // recording once it has been stopped, or has hit the max length.
+    fn recording_length(&self, limit: Duration) -> Duration {
+        Duration::from_secs(self.max_recording_secs).min(limit)
+    }
+
+    fn recording_controls(&mut self, ui: &mut egui::Ui, limit: Duration) -> Option<(PcmAudio, AudioFormat)> {
+        let mut stop = false;
+        if let Some(recorder) = &self.recorder {
+            ui.horizontal(|ui| {
+                if recorder.is_paused() {
+                    if ui.button("⏺ Resume").clicked() {
+                        recorder.resume();
+                    }
+                } else if ui.button("⏸ Pause").clicked() {
+                    recorder.pause();
+                }
+                stop = ui.button("⏹ Stop").clicked() || recorder.reached_limit();
+                ui.label(format!("{} / {}", format_duration(recorder.elapsed()), format_duration(recorder.max_duration())));
+                ui.add(egui::ProgressBar::new(recorder.level()).desired_width(150.0));
+            });
+        } else {
+            let mut start = false;
+            ui.horizontal(|ui| {
+                start = ui.button("⏺ Start").clicked();
+                ui.label("Max length (seconds):");
+                ui.add(egui::DragValue::new(&mut self.max_recording_secs).clamp_range(5..=limit.as_secs().max(5)));
+            });
+            if start {
+                match Recorder::start(self.recording_length(limit)) {
+                    Ok(recorder) => self.recorder = Some(recorder),
+                    Err(err) => self.error_message = Some(err.to_string()),
+                }
+            }
+        }
+
+        if !stop {
+            return None;
+        }
+        match self.recorder.take()?.stop() {
+            Ok(recording) => Some(recording),
+            Err(err) => {
+                self.error_message = Some(err.to_string());
+                None
+            }
+        }
+    }
+
+    // Transport controls for whatever the player has loaded.
+    fn player_bar(&mut self, ctx: &egui::Context) {
+        let mut seek_to = None;
+        let mut skip_to = None;
+        let mut clear_queue = false;
+        egui::TopBottomPanel::bottom("player").show(ctx, |ui| {
+            if let Some(queue) = self.queue.as_mut() {
+                ui.horizontal(|ui| {
+                    ui.label(format!("{}: {} of {}", queue.source.label(), queue.place(), queue.len()));
+                    if ui.button("⏮").clicked() {
+                        skip_to = Some(queue.previous());
+                    }
+                    if ui.button("⏭").clicked() {
+                        skip_to = Some(queue.next());
+                    }
+                    let mut shuffled = queue.is_shuffled();
+                    if ui.checkbox(&mut shuffled, "Shuffle").changed() {
+                        queue.set_shuffled(shuffled);
+                    }
+                    let mut gap = queue.gap.as_secs_f32();
+                    ui.label("Gap:");
+                    if ui.add(egui::DragValue::new(&mut gap).clamp_range(0.0..=10.0).speed(0.1).suffix(" s")).changed() {
+                        queue.gap = Duration::from_secs_f32(gap);
+                    }
+                    if queue.is_waiting() {
+                        ui.label("Up next...");
+                    }
+                    clear_queue = ui.small_button("Clear queue").clicked();
+                });
+            }
+            if !self.player.is_active() {
+                return;
+            }
+            ui.horizontal(|ui| {
+                if self.player.is_paused() {
+                    if ui.button("▶️").clicked() {
+                        self.player.resume();
+                    }
+                } else if ui.button("⏸").clicked() {
+                    self.player.pause();
+                }
+                if ui.button("⏹").clicked() {
+                    self.player.stop();
+                    // Stopping means stop, not move on to the next note
+                    if let Some(queue) = self.queue.as_mut() {
+                        queue.interrupt();
+                    }
+                }
+
+                let total = self.player.duration();
+                let mut position = self.player.position().as_secs_f32();
+                let slider = egui::Slider::new(&mut position, 0.0..=total.as_secs_f32().max(0.001)).show_value(false);
+                if ui.add(slider).changed() {
+                    seek_to = Some(Duration::from_secs_f32(position));
+                }
+                ui.label(format!("{} / {}", format_duration(self.player.position()), format_duration(total)));
+            });
+        });
+        if let Some(position) = seek_to {
+            if let Err(err) = self.player.seek(position) {
+                self.error_message = Some(err.to_string());
+            }
+        }
+        if let Some(next) = skip_to {
+            self.play_queued(next);
+        }
+        if clear_queue {
+            self.queue = None;
+        }
+    }
+
+    fn error_bar(&mut self, ctx: &egui::Context) {
+        let mut dismissed = false;
+        if let Some(error_message) = &self.error_message {
+            egui::TopBottomPanel::bottom("error").show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label(RichText::new(error_message).color(Color32::RED));
+                    dismissed = ui.small_button("Dismiss").clicked();
+                });
+            });
+        }
+        if dismissed {
+            self.error_message = None;
+        }
+    }
+
+    // Says when the app is working from its offline replica, and lists offline
+    // changes that couldn't be synced.
+    fn sync_bar(&mut self, ctx: &egui::Context) {
+        let status = self.context.store.sync_status();
+        if self.sync_status.offline && !status.offline {
+            // Downloads that failed while offline may work now
+            self.fetch_failed.clear();
+        }
+        self.sync_status = status;
+        self.sync_conflicts.extend(self.context.store.take_conflicts());
+        if status.offline || status.pending > 0 {
+            // Notice promptly when the connection comes back
+            ctx.request_repaint_after(Duration::from_secs(1));
+        }
+        if !status.offline && status.pending == 0 && self.sync_conflicts.is_empty() {
+            return;
+        }
+
+        let mut dismissed = false;
+        egui::TopBottomPanel::top("sync").show(ctx, |ui| {
+            if status.offline {
+                let mut text = String::from("⚠ Offline: showing what was saved on this device.");
+                if status.pending > 0 {
+                    text.push_str(&format!(" {} change(s) will sync when you reconnect.", status.pending));
+                }
+                ui.label(RichText::new(text).color(Color32::from_rgb(200, 120, 0)));
+            } else if status.pending > 0 {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label(format!("Syncing {} offline change(s)", status.pending));
+                });
+            }
+            for conflict in &self.sync_conflicts {
+                ui.label(RichText::new(conflict).color(Color32::RED));
+            }
+            if !self.sync_conflicts.is_empty() {
+                dismissed = ui.small_button("Dismiss").clicked();
+            }
+        });
+        if dismissed {
+            self.sync_conflicts.clear();
+        }
+    }
+
+    // Opens the export window for a note, or for the whole conversation if `thread`.
+    fn export_button(&mut self, ui: &mut egui::Ui, v_id: ObjectId, thread: bool) {
+        let label = if thread { "💾 Export thread" } else { "💾 Export" };
+        if ui.button(label).clicked() {
+            let format = self.export.as_ref().map_or(ExportFormat::Wav, |dialog| dialog.format);
+            let path = export::default_path(v_id, thread, format).display().to_string();
+            self.export = Some(ExportDialog { v_id, thread, path, format, saved: None });
+        }
+    }
+
+    fn export_window(&mut self, ctx: &egui::Context) {
+        let exporting = self.tasks.is_running(TaskKind::Export);
+        let dialog = match self.export.as_mut() {
+            Some(dialog) => dialog,
+            None => return,
+        };
+        let mut open = true;
+        let mut task = None;
+        let title = if dialog.thread { "Export conversation" } else { "Export quote" };
+        egui::Window::new(title).collapsible(false).resizable(false).open(&mut open).show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Save to:");
+                ui.add(egui::TextEdit::singleline(&mut dialog.path).desired_width(360.0));
+            });
+            ui.horizontal(|ui| {
+                for format in [ExportFormat::Wav, ExportFormat::Flac] {
+                    if ui.radio_value(&mut dialog.format, format, format.label()).changed() {
+                        // Keep the extension in step with the format
+                        dialog.path = Path::new(&dialog.path).with_extension(format.extension()).display().to_string();
+                    }
+                }
+            });
+            ui.horizontal(|ui| {
+                let path = dialog.path.trim();
+                if ui.add_enabled(!exporting && !path.is_empty(), egui::Button::new("Save")).clicked() {
+                    let path = PathBuf::from(path);
+                    dialog.saved = None;
+                    task = Some(if dialog.thread {
+                        Task::ExportThread { v_id: dialog.v_id, path, format: dialog.format }
+                    } else {
+                        Task::ExportNote { v_id: dialog.v_id, path, format: dialog.format }
+                    });
+                }
+                if exporting {
+                    ui.spinner();
+                }
+            });
+            if let Some(saved) = &dialog.saved {
+                ui.label(format!("Saved to {}", saved.display()));
+            }
+        });
+        if let Some(task) = task {
+            self.tasks.spawn(task);
+        }
+        if !open {
+            self.export = None;
+        }
+    }
+
+    // Lists in-flight background work with its progress and a way to cancel it.
+    fn task_bar(&mut self, ctx: &egui::Context) {
+        let mut cancelled = Vec::new();
+        egui::TopBottomPanel::bottom("tasks").show(ctx, |ui| {
+            for task in self.tasks.running() {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label(task.kind.label());
+                    if let Some(fraction) = task.fraction() {
+                        ui.add(egui::ProgressBar::new(fraction).desired_width(200.0).show_percentage());
+                    }
+                    if ui.small_button("Cancel").clicked() {
+                        cancelled.push((task.id, task.kind));
+                    }
+                });
+            }
+        });
+        for (id, kind) in cancelled {
+            self.tasks.cancel(id);
+            if kind == TaskKind::FetchAudio {
+                self.forget_fetches();
+            }
+        }
+    }
 }
     
 
 
 impl App for Gui {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        for result in self.tasks.poll() {
+            self.handle_task_result(result);
+        }
+        self.sync_bar(ctx);
+        if self.tasks.has_running() {
+            // Keep polling while work is in flight so results and progress show up promptly.
+            ctx.request_repaint_after(Duration::from_millis(100));
+            self.task_bar(ctx);
+        }
+        if self.recorder.is_some() {
+            // Keeps the timer and level meter moving
+            ctx.request_repaint_after(Duration::from_millis(50));
+        }
+        self.player.tick();
+        self.advance_queue();
+        if self.queue.as_ref().map_or(false, PlayQueue::is_waiting) || (self.player.is_active() && !self.player.is_paused()) {
+            // Keeps the progress bar moving and the queue advancing
+            ctx.request_repaint_after(Duration::from_millis(100));
+        }
+        if self.player.is_active() || self.queue.is_some() {
+            self.player_bar(ctx);
+        }
+        match self.current_page {
+            // These pages show the error next to their form
+            Page::Login | Page::Signup => {}
+            _ => self.error_bar(ctx),
+        }
+        self.export_window(ctx);
+
         egui::CentralPanel::default().show(ctx, |ui| {
             self.window_style.visuals.override_text_color = Some(egui::Color32::from_rgb(200, 200, 200));            
             ctx.set_cursor_icon(egui::CursorIcon::Alias);
@@ -969,70 +1781,49 @@ impl App for Gui {
                 }
             }
         });
+        self.request_fetches();
     }
 
 }
 
 
-fn count_voicenotes() -> usize {
-    let directory = "."; // Main directory path
-    let files = fs::read_dir(directory).unwrap();
-    let mut count = 0;
+// Draws the clip's waveform; dragging across it selects a range. Returns true
+// if the selection changed.
+fn draw_waveform(ui: &mut egui::Ui, audio: &PcmAudio, selection: &mut Option<(f32, f32)>) -> bool {
+    let size = Vec2::new(ui.available_width().min(800.0), 80.0);
+    let (rect, response) = ui.allocate_exact_size(size, egui::Sense::click_and_drag());
+    let before = *selection;
 
-    for file in files {
-        if let Ok(file) = file {
-            if let Some(extension) = file.path().extension() {
-                if extension == "wav" {
-                    count += 1;
-                }
-            }
+    if let Some(pointer) = response.interact_pointer_pos() {
+        let x = ((pointer.x - rect.left()) / rect.width()).clamp(0.0, 1.0);
+        if response.drag_started() {
+            ui.data_mut(|data| data.insert_temp(response.id, x));
+        }
+        if response.dragged() {
+            let anchor = ui.data(|data| data.get_temp::<f32>(response.id)).unwrap_or(x);
+            *selection = Some((anchor.min(x), anchor.max(x)));
+        } else if response.clicked() {
+            *selection = None;
         }
     }
 
-    count
-}
-
-use rodio::{source::Source};
-
-use rodio::{Decoder};
-
-
-fn play_audio(filename: &str) {
-    let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-    let sink = Sink::try_new(&stream_handle).unwrap();
-    let file = File::open(filename).unwrap();
-    let source = Decoder::new(BufReader::new(file)).unwrap();
-
-    sink.append(source);
-    sink.sleep_until_end();
-}
-
-
-fn pause_audio(sink: &Sink) {
-    sink.pause();
-}
-
-
-fn stop_audio(sink: &Sink) {
-    sink.stop();
-}
-
-
-fn delete_wav_files() -> io::Result<()> {
-    let current_dir = std::env::current_dir()?;
-
-    let entries = fs::read_dir(current_dir)?;
-
-    for entry in entries {
-        if let Ok(entry) = entry {
-            let file_path = entry.path();
-            if let Some(extension) = file_path.extension() {
-                if extension == "wav" {
-                    fs::remove_file(file_path)?;
-                }
-            }
-        }
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 2.0, ui.visuals().extreme_bg_color);
+    if let Some((start, end)) = *selection {
+        let selected = egui::Rect::from_x_y_ranges(rect.left() + start * rect.width()..=rect.left() + end * rect.width(), rect.y_range());
+        painter.rect_filled(selected, 0.0, Color32::from_rgba_unmultiplied(100, 150, 255, 60));
+    }
+    let stroke = egui::Stroke::new(1.0, ui.visuals().text_color());
+    let peaks = audio.peaks(rect.width().max(1.0) as usize);
+    for (i, peak) in peaks.iter().enumerate() {
+        let x = rect.left() + i as f32 + 0.5;
+        let half = (peak * rect.height() / 2.0).max(0.5);
+        painter.line_segment([egui::pos2(x, rect.center().y - half), egui::pos2(x, rect.center().y + half)], stroke);
     }
+    *selection != before
+}
 
-    Ok(())
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
 }
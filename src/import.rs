@@ -0,0 +1,112 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use rodio::{Decoder, Source};
+
+use crate::audio_codec::{AudioFormat, PcmAudio, SampleFormat};
+use crate::backend;
+use crate::error::{VoicerError, VoicerResult};
+
+pub const SUPPORTED_EXTENSIONS: [&str; 5] = ["wav", "flac", "ogg", "oga", "mp3"];
+// Files are decoded whole, so refuse anything absurdly long before it eats the memory.
+const MAX_IMPORT: Duration = Duration::from_secs(30 * 60);
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_ascii_lowercase())
+}
+
+pub fn is_supported(path: &Path) -> bool {
+    extension(path).map_or(false, |ext| SUPPORTED_EXTENSIONS.contains(&ext.as_str()))
+}
+
+// Decodes an audio file from disk into samples ready to become a draft.
+pub async fn import_audio_file(path: PathBuf) -> VoicerResult<(PcmAudio, AudioFormat)> {
+    if !is_supported(&path) {
+        return Err(VoicerError::Audio(format!("Can't import {}: use a WAV, FLAC, OGG or MP3 file", path.display())));
+    }
+    let (audio, format) = if extension(&path).as_deref() == Some("wav") {
+        // hound reads every WAV sample format, and tells us which one it was
+        let filename = path.to_str().ok_or_else(|| VoicerError::Audio("File name is not valid UTF-8".to_string()))?;
+        backend::convert_audio_to_vec(filename).await?
+    } else {
+        tokio::task::spawn_blocking(move || decode(&path))
+            .await
+            .map_err(|err| VoicerError::Audio(err.to_string()))??
+    };
+    if audio.frames() == 0 {
+        return Err(VoicerError::Audio("The file has no audio in it".to_string()));
+    }
+    Ok((audio, format))
+}
+
+// FLAC, Vorbis and MP3 go through rodio's decoders, which hand back 16-bit samples.
+fn decode(path: &Path) -> VoicerResult<(PcmAudio, AudioFormat)> {
+    let file = File::open(path)?;
+    let decoder = Decoder::new(BufReader::new(file)).map_err(|err| VoicerError::Audio(format!("Could not decode {}: {}", path.display(), err)))?;
+    let channels = decoder.channels();
+    let sample_rate = decoder.sample_rate();
+    let limit = (MAX_IMPORT.as_secs() * sample_rate as u64 * channels as u64) as usize;
+
+    let samples: Vec<i16> = decoder.take(limit + 1).collect();
+    if samples.len() > limit {
+        return Err(VoicerError::TooLong { what: "File", length: MAX_IMPORT + Duration::from_secs(1), limit: MAX_IMPORT });
+    }
+    let format = AudioFormat { channels, sample_rate, bits_per_sample: 16, sample_format: SampleFormat::Int };
+    Ok((PcmAudio { samples, channels, sample_rate }, format))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_codec;
+
+    fn temp_path(extension: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("voicer-import-{}.{}", mongodb::bson::oid::ObjectId::new().to_hex(), extension))
+    }
+
+    fn stereo() -> PcmAudio {
+        let samples = (0..2000).map(|i| ((i * 37) % 2000 - 1000) as i16).collect();
+        PcmAudio { samples, channels: 2, sample_rate: 22050 }
+    }
+
+    #[test]
+    fn only_known_extensions_are_supported() {
+        assert!(is_supported(Path::new("note.WAV")));
+        assert!(is_supported(Path::new("/tmp/note.mp3")));
+        assert!(!is_supported(Path::new("note.txt")));
+        assert!(!is_supported(Path::new("note")));
+    }
+
+    #[tokio::test]
+    async fn wav_files_keep_their_samples_and_format() {
+        let path = temp_path("wav");
+        backend::convert_vec_to_audio(path.to_str().unwrap(), stereo()).await.unwrap();
+        let (audio, format) = import_audio_file(path.clone()).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(audio.samples, stereo().samples);
+        assert_eq!((format.channels, format.sample_rate, format.bits_per_sample), (2, 22050, 16));
+    }
+
+    #[tokio::test]
+    async fn flac_files_keep_their_samples_and_format() {
+        let path = temp_path("flac");
+        std::fs::write(&path, audio_codec::encode_flac(&stereo()).unwrap()).unwrap();
+        let (audio, format) = import_audio_file(path.clone()).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(audio.samples, stereo().samples);
+        assert_eq!((format.channels, format.sample_rate), (2, 22050));
+    }
+
+    #[tokio::test]
+    async fn unsupported_and_empty_files_are_refused() {
+        assert!(import_audio_file(PathBuf::from("note.txt")).await.is_err());
+
+        let path = temp_path("wav");
+        backend::convert_vec_to_audio(path.to_str().unwrap(), PcmAudio { samples: Vec::new(), channels: 1, sample_rate: 44100 }).await.unwrap();
+        let result = import_audio_file(path.clone()).await;
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(VoicerError::Audio(_))));
+    }
+}
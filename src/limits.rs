@@ -0,0 +1,86 @@
+use std::time::Duration;
+
+use crate::error::{VoicerError, VoicerResult};
+
+const DEFAULT_MAX_POST: Duration = Duration::from_secs(300);
+const DEFAULT_MAX_REPLY: Duration = Duration::from_secs(120);
+
+// How long quotes and replies may be. Set with `VOICER_MAX_POST_SECS` and
+// `VOICER_MAX_REPLY_SECS`.
+#[derive(Debug, Clone, Copy)]
+pub struct LengthLimits {
+    pub max_post: Duration,
+    pub max_reply: Duration,
+}
+
+impl Default for LengthLimits {
+    fn default() -> Self {
+        LengthLimits { max_post: DEFAULT_MAX_POST, max_reply: DEFAULT_MAX_REPLY }
+    }
+}
+
+impl LengthLimits {
+    pub fn from_env() -> VoicerResult<Self> {
+        Ok(LengthLimits {
+            max_post: secs_from_env("VOICER_MAX_POST_SECS", DEFAULT_MAX_POST)?,
+            max_reply: secs_from_env("VOICER_MAX_REPLY_SECS", DEFAULT_MAX_REPLY)?,
+        })
+    }
+
+    pub fn max_length(&self, is_post: bool) -> Duration {
+        if is_post { self.max_post } else { self.max_reply }
+    }
+
+    pub fn check(&self, length: Duration, is_post: bool) -> VoicerResult<()> {
+        let limit = self.max_length(is_post);
+        if length > limit {
+            return Err(VoicerError::TooLong { what: if is_post { "Quote" } else { "Reply" }, length, limit });
+        }
+        Ok(())
+    }
+}
+
+fn secs_from_env(name: &str, default: Duration) -> VoicerResult<Duration> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse::<u64>()
+            .ok()
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+            .ok_or_else(|| VoicerError::Config(format!("{} must be a whole number of seconds", name))),
+        Err(_) => Ok(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_and_replies_have_their_own_limits() {
+        let limits = LengthLimits::default();
+        assert!(limits.check(DEFAULT_MAX_POST, true).is_ok());
+        assert!(limits.check(DEFAULT_MAX_REPLY + Duration::from_millis(1), false).is_err());
+        assert!(limits.check(DEFAULT_MAX_REPLY + Duration::from_millis(1), true).is_ok());
+        match limits.check(DEFAULT_MAX_POST * 2, true) {
+            Err(VoicerError::TooLong { what, limit, .. }) => assert_eq!((what, limit), ("Quote", DEFAULT_MAX_POST)),
+            other => panic!("expected TooLong, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn limits_come_from_the_environment() {
+        // Only this test touches these variables
+        std::env::set_var("VOICER_MAX_POST_SECS", " 60 ");
+        std::env::remove_var("VOICER_MAX_REPLY_SECS");
+        let limits = LengthLimits::from_env().unwrap();
+        assert_eq!((limits.max_post, limits.max_reply), (Duration::from_secs(60), DEFAULT_MAX_REPLY));
+
+        for bad in ["0", "-5", "1.5", "two"] {
+            std::env::set_var("VOICER_MAX_POST_SECS", bad);
+            assert!(matches!(LengthLimits::from_env(), Err(VoicerError::Config(_))), "{}", bad);
+        }
+        std::env::remove_var("VOICER_MAX_POST_SECS");
+    }
+}
@@ -0,0 +1,284 @@
+use std::f64::consts::PI;
+
+use crate::audio_codec::PcmAudio;
+
+// Notes are played back at this integrated loudness (LUFS).
+pub const TARGET_LUFS: f64 = -16.0;
+// The limiter keeps sample peaks below this (dBFS).
+pub const PEAK_CEILING_DB: f64 = -1.0;
+// Quiet recordings are boosted at most this much, so hiss doesn't become a roar.
+const MAX_GAIN_DB: f64 = 20.0;
+
+// BS.1770 gating: 400 ms blocks every 100 ms, absolute and relative gates.
+const BLOCK_SECS: f64 = 0.4;
+const BLOCK_STEP_SECS: f64 = 0.1;
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+// Limiter gain ramps: quick to duck ahead of a peak, slower to recover.
+const ATTACK_SECS: f64 = 0.005;
+const RELEASE_SECS: f64 = 0.05;
+
+// Direct form I biquad.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.b[1] * self.x[0] + self.b[2] * self.x[1] - self.a[1] * self.y[0] - self.a[2] * self.y[1];
+        self.x = [input, self.x[0]];
+        self.y = [output, self.y[0]];
+        output
+    }
+}
+
+// The two-stage K-weighting filter from BS.1770, designed for any sample rate.
+fn k_weighting(sample_rate: u32) -> (Biquad, Biquad) {
+    let rate = sample_rate as f64;
+
+    // High shelf modelling the head
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        x: [0.0; 2],
+        y: [0.0; 2],
+    };
+
+    // High pass dropping the lowest rumble
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        x: [0.0; 2],
+        y: [0.0; 2],
+    };
+    (shelf, high_pass)
+}
+
+fn to_lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+// Integrated loudness in LUFS, or None if the clip is silent.
+pub fn integrated_loudness(audio: &PcmAudio) -> Option<f64> {
+    let channels = audio.channels.max(1) as usize;
+    let frames = audio.frames();
+    if frames == 0 || audio.sample_rate == 0 {
+        return None;
+    }
+
+    // K-weighted energy per frame, summed over channels
+    let mut filters: Vec<(Biquad, Biquad)> = (0..channels).map(|_| k_weighting(audio.sample_rate)).collect();
+    let mut energy = Vec::with_capacity(frames);
+    for frame in audio.samples.chunks_exact(channels) {
+        let mut sum = 0.0;
+        for (sample, (shelf, high_pass)) in frame.iter().zip(filters.iter_mut()) {
+            let weighted = high_pass.process(shelf.process(*sample as f64 / 32768.0));
+            sum += weighted * weighted;
+        }
+        energy.push(sum);
+    }
+
+    // Mean power of each gating block; short clips are measured as one block
+    let block = ((BLOCK_SECS * audio.sample_rate as f64) as usize).min(frames);
+    let step = ((BLOCK_STEP_SECS * audio.sample_rate as f64) as usize).max(1);
+    let mut blocks = Vec::new();
+    let mut start = 0;
+    while start + block <= frames {
+        blocks.push(energy[start..start + block].iter().sum::<f64>() / block as f64);
+        start += step;
+    }
+
+    let gated = |threshold: f64| -> Option<f64> {
+        let passing: Vec<f64> = blocks.iter().copied().filter(|&power| power > 0.0 && to_lufs(power) > threshold).collect();
+        if passing.is_empty() {
+            None
+        } else {
+            Some(passing.iter().sum::<f64>() / passing.len() as f64)
+        }
+    };
+    let ungated = to_lufs(gated(ABSOLUTE_GATE_LUFS)?);
+    gated(ungated + RELATIVE_GATE_LU).map(to_lufs)
+}
+
+// Brings a note to the target loudness, measuring it first if `loudness` isn't
+// known, then limits peaks so the gain never clips.
+pub fn normalize(audio: &mut PcmAudio, loudness: Option<f64>) {
+    let loudness = match loudness.or_else(|| integrated_loudness(audio)) {
+        Some(loudness) => loudness,
+        None => return,
+    };
+    let mut normalizer = Normalizer::new(loudness, audio.channels, audio.sample_rate);
+    let mut samples = normalizer.process(&audio.samples);
+    samples.extend(normalizer.finish());
+    audio.samples = samples;
+}
+
+// `normalize` for audio that arrives in pieces, when its loudness is already
+// known. The limiter has to see a peak coming to duck ahead of it, so the last
+// few milliseconds of each piece are held back until the next one arrives.
+pub struct Normalizer {
+    gain: f64,
+    ceiling: f64,
+    channels: usize,
+    attack: f64,
+    release: f64,
+    // How many frames ahead a peak can pull the gain down
+    lookahead: usize,
+    // Frames not yet let out, with the limiter gain each could take
+    held: Vec<i16>,
+    limits: Vec<f64>,
+    // Limiter gain of the last frame let out
+    last: f64,
+}
+
+impl Normalizer {
+    pub fn new(loudness: f64, channels: u16, sample_rate: u32) -> Self {
+        let rate = sample_rate.max(1) as f64;
+        Normalizer {
+            gain: 10f64.powf((TARGET_LUFS - loudness).min(MAX_GAIN_DB) / 20.0),
+            ceiling: 10f64.powf(PEAK_CEILING_DB / 20.0) * i16::MAX as f64,
+            channels: channels.max(1) as usize,
+            attack: 1.0 / (ATTACK_SECS * rate),
+            release: 1.0 / (RELEASE_SECS * rate),
+            lookahead: (ATTACK_SECS * rate).ceil() as usize,
+            held: Vec::new(),
+            limits: Vec::new(),
+            last: 1.0,
+        }
+    }
+
+    // Takes the next piece and returns the frames that are ready, which lag
+    // the input by the limiter's look-ahead.
+    pub fn process(&mut self, samples: &[i16]) -> Vec<i16> {
+        let (gain, ceiling) = (self.gain, self.ceiling);
+        // Gain each frame can take without crossing the ceiling
+        self.limits.extend(samples.chunks_exact(self.channels).map(|frame| {
+            let peak = frame.iter().map(|s| (*s as f64).abs()).fold(0.0, f64::max) * gain;
+            if peak > ceiling { ceiling / peak } else { 1.0 }
+        }));
+        self.held.extend_from_slice(samples);
+        self.release_frames(self.limits.len().saturating_sub(self.lookahead))
+    }
+
+    // The frames still held back, once nothing more is coming.
+    pub fn finish(&mut self) -> Vec<i16> {
+        self.release_frames(self.limits.len())
+    }
+
+    fn release_frames(&mut self, count: usize) -> Vec<i16> {
+        // Smooth the gain: ramp down ahead of each peak, recover gradually after
+        for i in (0..self.limits.len().saturating_sub(1)).rev() {
+            self.limits[i] = self.limits[i].min(self.limits[i + 1] + self.attack);
+        }
+        let mut samples: Vec<i16> = self.held.drain(..count * self.channels).collect();
+        let mut previous = self.last;
+        for (frame, limit) in samples.chunks_exact_mut(self.channels).zip(self.limits.drain(..count)) {
+            let limit = limit.min(previous + self.release);
+            previous = limit;
+            for sample in frame {
+                *sample = (*sample as f64 * self.gain * limit).round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+            }
+        }
+        self.last = previous;
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two seconds of 1 kHz at `amplitude` (0.0 to 1.0 of full scale)
+    fn sine(amplitude: f64) -> PcmAudio {
+        let samples = (0..96000)
+            .map(|i| ((i as f64 * 1000.0 * 2.0 * PI / 48000.0).sin() * amplitude * 32767.0) as i16)
+            .collect();
+        PcmAudio { samples, channels: 1, sample_rate: 48000 }
+    }
+
+    fn peak(audio: &PcmAudio) -> i16 {
+        audio.samples.iter().map(|sample| sample.saturating_abs()).max().unwrap_or(0)
+    }
+
+    #[test]
+    fn a_full_scale_sine_measures_about_minus_three() {
+        // The BS.1770 reference: a 0 dBFS 1 kHz sine reads -3.01 LUFS
+        let loudness = integrated_loudness(&sine(1.0)).unwrap();
+        assert!((loudness + 3.01).abs() < 0.2, "{} LUFS", loudness);
+        let quieter = integrated_loudness(&sine(0.5)).unwrap();
+        assert!((quieter - loudness + 6.02).abs() < 0.2, "{} LUFS", quieter);
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        assert_eq!(integrated_loudness(&PcmAudio { samples: vec![0; 48000], channels: 1, sample_rate: 48000 }), None);
+        assert_eq!(integrated_loudness(&PcmAudio { samples: Vec::new(), channels: 1, sample_rate: 48000 }), None);
+    }
+
+    #[test]
+    fn quiet_notes_are_brought_up_to_the_target() {
+        let mut audio = sine(0.05);
+        normalize(&mut audio, None);
+        let loudness = integrated_loudness(&audio).unwrap();
+        assert!((loudness - TARGET_LUFS).abs() < 0.5, "{} LUFS", loudness);
+    }
+
+    #[test]
+    fn loud_notes_are_limited_below_the_ceiling() {
+        let mut audio = sine(1.0);
+        // Claiming it is very quiet asks for the full boost
+        normalize(&mut audio, Some(-60.0));
+        let ceiling = 10f64.powf(PEAK_CEILING_DB / 20.0) * i16::MAX as f64;
+        assert!(peak(&audio) as f64 <= ceiling + 1.0, "peak {}", peak(&audio));
+    }
+
+    #[test]
+    fn the_boost_is_capped() {
+        let mut audio = sine(0.001);
+        let before = peak(&audio) as f64;
+        normalize(&mut audio, None);
+        let gain_db = 20.0 * (peak(&audio) as f64 / before).log10();
+        assert!(gain_db <= MAX_GAIN_DB + 0.5, "{} dB", gain_db);
+    }
+
+    #[test]
+    fn streamed_pieces_duck_ahead_of_a_peak_in_the_next_piece() {
+        // Quiet, then a full scale burst starting exactly where the second piece does
+        let mut audio = sine(0.05);
+        let boundary = 48000;
+        for sample in &mut audio.samples[boundary..boundary + 4800] {
+            *sample = sample.saturating_mul(20);
+        }
+        let mut whole = audio.clone();
+        normalize(&mut whole, Some(-30.0));
+
+        let mut normalizer = Normalizer::new(-30.0, 1, 48000);
+        let mut streamed = normalizer.process(&audio.samples[..boundary]);
+        assert!(streamed.len() < boundary);
+        streamed.extend(normalizer.process(&audio.samples[boundary..]));
+        streamed.extend(normalizer.finish());
+        assert_eq!(streamed, whole.samples);
+
+        // The ramp down started in the first piece
+        let ceiling = 10f64.powf(PEAK_CEILING_DB / 20.0) * i16::MAX as f64;
+        assert!(peak(&whole) as f64 <= ceiling + 1.0, "peak {}", peak(&whole));
+        let gain = 10f64.powf((TARGET_LUFS + 30.0) / 20.0);
+        let before = boundary - 1;
+        assert!((whole.samples[before] as f64).abs() < (audio.samples[before] as f64 * gain).abs() - 1.0);
+    }
+}
@@ -9,9 +9,38 @@ pub use eframe::{run_native, App, egui::{self}};
 
 pub mod frontend;
 pub mod backend;
+pub mod audio_cache;
+pub mod audio_codec;
+pub mod audio_storage;
+pub mod audio_stream;
+pub mod cleanup;
+pub mod context;
+pub mod draft;
+pub mod error;
+pub mod export;
+pub mod import;
+pub mod limits;
+pub mod loudness;
+pub mod password;
+pub mod player;
+pub mod queue;
+pub mod recorder;
+pub mod replica_store;
+pub mod session;
+pub mod store;
+pub mod memory_store;
+pub mod tasks;
+pub mod time_stretch;
+pub mod waveform;
 
 fn main() {
-    let app = Gui::new();
+    let app = match Gui::new() {
+        Ok(app) => app,
+        Err(err) => {
+            println!("Could not start Voicer: {}", err);
+            return;
+        }
+    };
     let mut win_options = eframe::NativeOptions::default();
     win_options.initial_window_size = Some(egui::Vec2::new(1200.0, 800.0));
     win_options.centered = true;
@@ -0,0 +1,693 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+
+use crate::audio_codec::{AudioFormat, PcmAudio, CANONICAL_CHANNELS, CANONICAL_SAMPLE_RATE};
+use crate::audio_storage::{self, InlineStorage, StoredAudio};
+use crate::backend::{conversation, publicUser, replies, to_public_user, FeedCursor, FeedItem, FeedPage, FollowResult, Reaction, ReactionType, UnfollowResult, Users, VoiceNote};
+use crate::error::{VoicerError, VoicerResult};
+use crate::limits::LengthLimits;
+use crate::password::{self, PasswordCheck};
+use crate::session::{self, Session};
+use crate::store::VoicerStore;
+
+#[derive(Default)]
+struct MemoryData {
+    users: HashMap<ObjectId, Users>,
+    voice_notes: HashMap<ObjectId, VoiceNote>,
+    // Keyed by token hash
+    sessions: HashMap<String, Session>,
+}
+
+impl MemoryData {
+    fn user(&self, user_id: &ObjectId) -> VoicerResult<&Users> {
+        self.users.get(user_id).ok_or(VoicerError::NotFound("User"))
+    }
+
+    fn user_mut(&mut self, user_id: &ObjectId) -> VoicerResult<&mut Users> {
+        self.users.get_mut(user_id).ok_or(VoicerError::NotFound("User"))
+    }
+
+    fn user_by_username_mut(&mut self, username: &str) -> VoicerResult<&mut Users> {
+        self.users.values_mut().find(|user| user.username == username).ok_or(VoicerError::NotFound("User"))
+    }
+
+    fn voice_note_mut(&mut self, v_id: &ObjectId) -> VoicerResult<&mut VoiceNote> {
+        self.voice_notes.get_mut(v_id).ok_or(VoicerError::NotFound("Quote"))
+    }
+
+    // Unfollowing a deleted account still tidies up the follower's side.
+    fn remove_follow(&mut self, follower: ObjectId, followed: ObjectId) -> VoicerResult<UnfollowResult> {
+        let user = self.user_mut(&follower)?;
+        let before = user.following.len();
+        user.following.retain(|id| *id != followed);
+        let changed = user.following.len() != before;
+        if let Some(followed) = self.users.get_mut(&followed) {
+            followed.followers.retain(|id| *id != follower);
+        }
+        Ok(if changed { UnfollowResult::Unfollowed } else { UnfollowResult::NotFollowing })
+    }
+
+    fn profiles(&self, ids: &[ObjectId], user_id: ObjectId) -> Vec<publicUser> {
+        let mut users = Vec::new();
+        let mut var = 0;
+        for id in ids {
+            if let Some(user) = self.users.get(id) {
+                if user._id != user_id {
+                    users.push(to_public_user(user.clone(), var));
+                    var = var + 1;
+                }
+            }
+        }
+        users
+    }
+
+    fn new_voice_note(&self, user_id: ObjectId, voice_id: ObjectId, stored: StoredAudio, format: AudioFormat, is_post: bool) -> VoicerResult<VoiceNote> {
+        let user = self.user(&user_id)?;
+        Ok(VoiceNote {
+            _id: voice_id,
+            user_id: user_id,
+            name: user.name.clone(),
+            is_post: is_post,
+            data: Vec::new(),
+            audio: Some(stored.blob),
+            source_format: Some(format),
+            loudness: stored.loudness,
+            waveform: Some(stored.waveform),
+            duration_ms: Some(stored.duration.as_millis() as u64),
+            replies: Vec::new(),
+            reactions: Vec::new(),
+            timestamp: Utc::now(),
+        })
+    }
+}
+
+// Everything in a memory store, in a form that can be written to disk.
+#[derive(Serialize, Deserialize, Default)]
+pub struct MemorySnapshot {
+    pub users: Vec<Users>,
+    pub voice_notes: Vec<VoiceNote>,
+    pub sessions: Vec<Session>,
+}
+
+// Keeps everything in process memory; nothing survives a restart unless the
+// owner saves a snapshot.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: Mutex<MemoryData>,
+    limits: LengthLimits,
+}
+
+impl MemoryStore {
+    pub fn new(limits: LengthLimits) -> Self {
+        MemoryStore { data: Mutex::default(), limits }
+    }
+
+    pub fn from_snapshot(snapshot: MemorySnapshot, limits: LengthLimits) -> Self {
+        let data = MemoryData {
+            users: snapshot.users.into_iter().map(|user| (user._id, user)).collect(),
+            voice_notes: snapshot.voice_notes.into_iter().map(|note| (note._id, note)).collect(),
+            sessions: snapshot.sessions.into_iter().map(|session| (session.token_hash.clone(), session)).collect(),
+        };
+        MemoryStore { data: Mutex::new(data), limits }
+    }
+
+    // Swaps in everything another store holds, dropping what was here.
+    pub fn replace(&self, other: MemoryStore) {
+        *self.data.lock().unwrap() = other.data.into_inner().unwrap();
+    }
+
+    pub fn snapshot(&self) -> MemorySnapshot {
+        let data = self.data.lock().unwrap();
+        MemorySnapshot {
+            users: data.users.values().cloned().collect(),
+            voice_notes: data.voice_notes.values().cloned().collect(),
+            sessions: data.sessions.values().filter(|session| !session.is_expired()).cloned().collect(),
+        }
+    }
+
+    // Copies in a user as another store has them.
+    pub fn remember_user(&self, user: Users) {
+        self.data.lock().unwrap().users.insert(user._id, user);
+    }
+
+    // Copies in what a profile shows of a user, keeping anything else already known.
+    pub fn remember_profile(&self, profile: &publicUser) {
+        let mut data = self.data.lock().unwrap();
+        let user = data.users.entry(profile._id).or_insert_with(|| Users {
+            _id: profile._id,
+            username: String::new(),
+            password: None,
+            name: String::new(),
+            description: String::new(),
+            followers: Vec::new(),
+            following: Vec::new(),
+            voice_notes: Vec::new(),
+            playback_speed: 1.0,
+        });
+        user.username = profile.username.clone();
+        user.name = profile.name.clone();
+        user.description = profile.description.clone();
+        user.followers = profile.followers.clone();
+        user.following = profile.following.clone();
+        user.voice_notes = profile.voice_notes.clone();
+    }
+
+    // Copies in a note as another store has it. Audio already held here is
+    // kept when the copy comes without any.
+    pub fn remember_note(&self, mut note: VoiceNote) {
+        let mut data = self.data.lock().unwrap();
+        if note.audio.is_none() {
+            if let Some(existing) = data.voice_notes.get(&note._id) {
+                note.audio = existing.audio.clone();
+                note.loudness = note.loudness.or(existing.loudness);
+                note.source_format = note.source_format.or(existing.source_format);
+            }
+        }
+        data.voice_notes.insert(note._id, note);
+    }
+
+    pub fn username(&self, user_id: ObjectId) -> Option<String> {
+        self.data.lock().unwrap().users.get(&user_id).map(|user| user.username.clone())
+    }
+
+    // Who the user follows, or nobody if they aren't known here.
+    pub fn following(&self, user_id: ObjectId) -> Vec<ObjectId> {
+        self.data.lock().unwrap().users.get(&user_id).map(|user| user.following.clone()).unwrap_or_default()
+    }
+
+    pub fn remember_session(&self, session: Session) {
+        self.data.lock().unwrap().sessions.insert(session.token_hash.clone(), session);
+    }
+
+    pub fn forget_session(&self, token_hash: &str) {
+        self.data.lock().unwrap().sessions.remove(token_hash);
+    }
+
+    // Drops notes that no longer exist elsewhere, along with references to them.
+    pub fn forget_notes(&self, gone: impl Fn(&VoiceNote) -> bool) {
+        let mut data = self.data.lock().unwrap();
+        let removed: Vec<ObjectId> = data.voice_notes.values().filter(|note| gone(note)).map(|note| note._id).collect();
+        if removed.is_empty() {
+            return;
+        }
+        data.voice_notes.retain(|id, _| !removed.contains(id));
+        for note in data.voice_notes.values_mut() {
+            note.replies.retain(|id| !removed.contains(id));
+        }
+        for user in data.users.values_mut() {
+            user.voice_notes.retain(|id| !removed.contains(id));
+        }
+    }
+}
+
+// What a feed card shows of a note.
+fn feed_item(note: &VoiceNote) -> FeedItem {
+    FeedItem {
+        _id: note._id,
+        user_id: note.user_id,
+        name: note.name.clone(),
+        replies: note.replies.clone(),
+        reactions: note.reactions.clone(),
+        timestamp: note.timestamp,
+        waveform: note.waveform.clone(),
+        duration_ms: note.duration_ms,
+    }
+}
+
+#[async_trait]
+impl VoicerStore for MemoryStore {
+    async fn create_user(&self, username: String, password: String, name: String) -> VoicerResult<ObjectId> {
+        let taken = |data: &MemoryData| data.users.values().any(|user| user.username == username);
+        // Checked before hashing too, so a taken name doesn't cost an Argon2 run
+        if taken(&self.data.lock().unwrap()) {
+            return Err(VoicerError::DuplicateUsername);
+        }
+        let hash = password::hash_password(&password).await?;
+        let mut data = self.data.lock().unwrap();
+        if taken(&data) {
+            return Err(VoicerError::DuplicateUsername);
+        }
+        println!("Creating new user");
+        let user_id = ObjectId::new();
+        data.users.insert(user_id, Users {
+            _id: user_id,
+            username: username,
+            password: Some(hash),
+            name: name,
+            description: String::from(""),
+            followers: Vec::new(),
+            following: Vec::new(),
+            voice_notes: Vec::new(),
+            playback_speed: 1.0,
+        });
+        Ok(user_id)
+    }
+
+    async fn get_user_by_username(&self, username: String, password: String) -> VoicerResult<Users> {
+        // Argon2 runs without the lock held, so other calls aren't kept waiting
+        let stored = self.data.lock().unwrap().user_by_username_mut(&username)?.password.clone();
+        let stored = stored.ok_or(VoicerError::InvalidCredentials)?;
+        match password::verify_password(&password, &stored).await {
+            PasswordCheck::Valid => {}
+            PasswordCheck::ValidNeedsRehash => {
+                let hash = password::hash_password(&password).await?;
+                let mut data = self.data.lock().unwrap();
+                let user = data.user_by_username_mut(&username)?;
+                // Unless the password was changed in the meantime
+                if user.password.as_ref() == Some(&stored) {
+                    user.password = Some(hash);
+                }
+            }
+            PasswordCheck::Invalid => return Err(VoicerError::InvalidCredentials),
+        }
+        Ok(self.data.lock().unwrap().user_by_username_mut(&username)?.clone())
+    }
+
+    async fn find_users_by_names(&self, username: &str, user_id: ObjectId) -> VoicerResult<publicUser> {
+        let data = self.data.lock().unwrap();
+        data.users
+            .values()
+            .find(|user| user.username == username && user._id != user_id)
+            .map(|user| to_public_user(user.clone(), 0))
+            .ok_or(VoicerError::NotFound("User"))
+    }
+
+    async fn update_user_name_by_username(&self, username: &str, new_name: &str) -> VoicerResult<()> {
+        self.data.lock().unwrap().user_by_username_mut(username)?.name = new_name.to_string();
+        Ok(())
+    }
+
+    async fn update_password_by_username(&self, username: &str, new_password: &str) -> VoicerResult<()> {
+        let hash = password::hash_password(new_password).await?;
+        self.data.lock().unwrap().user_by_username_mut(username)?.password = Some(hash);
+        Ok(())
+    }
+
+    async fn update_description_by_username(&self, username: &str, new_desc: &str) -> VoicerResult<()> {
+        self.data.lock().unwrap().user_by_username_mut(username)?.description = new_desc.to_string();
+        Ok(())
+    }
+
+    async fn update_playback_speed(&self, user_id: ObjectId, speed: f32) -> VoicerResult<()> {
+        self.data.lock().unwrap().user_mut(&user_id)?.playback_speed = speed;
+        Ok(())
+    }
+
+    async fn create_session(&self, user_id: ObjectId) -> VoicerResult<String> {
+        let mut data = self.data.lock().unwrap();
+        data.sessions.retain(|_, existing| !existing.is_expired());
+        let (new_session, token) = Session::issue(user_id);
+        data.sessions.insert(new_session.token_hash.clone(), new_session);
+        Ok(token)
+    }
+
+    async fn get_user_by_session(&self, token: &str) -> VoicerResult<Users> {
+        let data = self.data.lock().unwrap();
+        let found = data.sessions.get(&session::hash_token(token)).ok_or(VoicerError::InvalidSession)?;
+        if found.is_expired() {
+            return Err(VoicerError::InvalidSession);
+        }
+        data.users.get(&found.user_id).cloned().ok_or(VoicerError::InvalidSession)
+    }
+
+    async fn revoke_session(&self, token: &str) -> VoicerResult<()> {
+        self.data.lock().unwrap().sessions.remove(&session::hash_token(token));
+        Ok(())
+    }
+
+    async fn revoke_all_sessions(&self, user_id: ObjectId) -> VoicerResult<u64> {
+        let mut data = self.data.lock().unwrap();
+        let before = data.sessions.len();
+        data.sessions.retain(|_, existing| existing.user_id != user_id);
+        Ok((before - data.sessions.len()) as u64)
+    }
+
+    async fn create_post(&self, user_id: ObjectId, recording: PcmAudio, format: AudioFormat, voice_id: ObjectId) -> VoicerResult<()> {
+        self.limits.check(recording.duration(), true)?;
+        let stored = audio_storage::store_samples(&InlineStorage, voice_id, recording).await?;
+        let mut store = self.data.lock().unwrap();
+        let note = store.new_voice_note(user_id, voice_id, stored, format, true)?;
+        store.voice_notes.insert(voice_id, note);
+        store.user_mut(&user_id)?.voice_notes.push(voice_id);
+        Ok(())
+    }
+
+    async fn delete_post(&self, voice_note_id: ObjectId, user_id: ObjectId) -> VoicerResult<()> {
+        let mut data = self.data.lock().unwrap();
+        // Only the author can delete a note; anyone else is told it doesn't exist
+        if data.voice_notes.get(&voice_note_id).map_or(true, |note| note.user_id != user_id) {
+            return Err(VoicerError::NotFound("Quote"));
+        }
+        data.voice_notes.remove(&voice_note_id);
+        data.user_mut(&user_id)?.voice_notes.retain(|id| *id != voice_note_id);
+        Ok(())
+    }
+
+    async fn get_voice_note(&self, v_id: ObjectId) -> VoicerResult<VoiceNote> {
+        self.data.lock().unwrap().voice_notes.get(&v_id).cloned().ok_or(VoicerError::NotFound("Quote"))
+    }
+
+    async fn get_voice_audio(&self, v_id: ObjectId) -> VoicerResult<PcmAudio> {
+        let note = self.get_voice_note(v_id).await?;
+        match &note.audio {
+            Some(audio) => audio_storage::load_samples(&InlineStorage, audio).await,
+            None => Ok(PcmAudio { samples: note.data, channels: CANONICAL_CHANNELS, sample_rate: CANONICAL_SAMPLE_RATE }),
+        }
+    }
+
+    async fn get_feed_page(&self, user_id: ObjectId, after: Option<FeedCursor>, limit: i64) -> VoicerResult<FeedPage> {
+        let data = self.data.lock().unwrap();
+        let following = &data.user(&user_id)?.following;
+        let mut items: Vec<FeedItem> = data.voice_notes
+            .values()
+            .filter(|note| note.is_post && following.contains(&note.user_id))
+            .map(feed_item)
+            .collect();
+
+        // Same order and cursor semantics as the Mongo query, compared at second precision
+        items.sort_by(|a, b| (b.timestamp.timestamp(), b._id).cmp(&(a.timestamp.timestamp(), a._id)));
+        if let Some(cursor) = after {
+            let position = (cursor.timestamp.timestamp(), cursor._id);
+            items.retain(|item| (item.timestamp.timestamp(), item._id) < position);
+        }
+        items.truncate((limit + 1).max(0) as usize);
+        Ok(FeedPage::from_items(items, limit))
+    }
+
+    async fn get_note_summaries(&self, ids: Vec<ObjectId>) -> VoicerResult<Vec<FeedItem>> {
+        let data = self.data.lock().unwrap();
+        Ok(ids.iter().filter_map(|id| data.voice_notes.get(id)).map(feed_item).collect())
+    }
+
+    // Both lists are edited under one lock, after checking both users exist,
+    // so a follow is never half applied.
+    async fn follow(&self, user_id: ObjectId, fuser_id: ObjectId) -> VoicerResult<FollowResult> {
+        if user_id == fuser_id {
+            return Err(VoicerError::SelfFollow);
+        }
+        let mut data = self.data.lock().unwrap();
+        data.user(&fuser_id)?;
+        let user = data.user_mut(&user_id)?;
+        let changed = !user.following.contains(&fuser_id);
+        if changed {
+            user.following.push(fuser_id);
+        }
+        let fuser = data.user_mut(&fuser_id)?;
+        if !fuser.followers.contains(&user_id) {
+            fuser.followers.push(user_id);
+        }
+        Ok(if changed { FollowResult::Followed } else { FollowResult::AlreadyFollowing })
+    }
+
+    async fn unfollow(&self, user_id: ObjectId, fuser_id: ObjectId) -> VoicerResult<UnfollowResult> {
+        self.data.lock().unwrap().remove_follow(user_id, fuser_id)
+    }
+
+    async fn remove_follower(&self, user_id: ObjectId, follower_id: ObjectId) -> VoicerResult<UnfollowResult> {
+        self.data.lock().unwrap().remove_follow(follower_id, user_id)
+    }
+
+    async fn get_all_following_profile(&self, user_id: ObjectId) -> VoicerResult<Vec<publicUser>> {
+        let data = self.data.lock().unwrap();
+        let following = data.user(&user_id)?.following.clone();
+        Ok(data.profiles(&following, user_id))
+    }
+
+    async fn get_all_followers_profile(&self, user_id: ObjectId) -> VoicerResult<Vec<publicUser>> {
+        let data = self.data.lock().unwrap();
+        let followers = data.user(&user_id)?.followers.clone();
+        Ok(data.profiles(&followers, user_id))
+    }
+
+    async fn react_to_quote(&self, v_id: ObjectId, user_id: ObjectId, reaction: ReactionType) -> VoicerResult<()> {
+        let mut data = self.data.lock().unwrap();
+        let note = data.voice_note_mut(&v_id)?;
+        match note.reactions.iter_mut().find(|r| r.user_id == user_id) {
+            Some(existing) => {
+                existing.reaction = reaction;
+                println!("Reaction updated");
+            }
+            None => {
+                note.reactions.push(Reaction { user_id: user_id, reaction: reaction });
+                println!("Reaction inserted");
+            }
+        }
+        Ok(())
+    }
+
+    async fn create_comment(&self, user_id: ObjectId, voice_id: String, comment_id: ObjectId, recording: PcmAudio, format: AudioFormat) -> VoicerResult<()> {
+        self.limits.check(recording.duration(), false)?;
+        let parent_id = ObjectId::parse_str(voice_id).map_err(|_| VoicerError::NotFound("Quote"))?;
+        let stored = audio_storage::store_samples(&InlineStorage, comment_id, recording).await?;
+        let mut store = self.data.lock().unwrap();
+        let note = store.new_voice_note(user_id, comment_id, stored, format, false)?;
+        store.voice_note_mut(&parent_id)?.replies.push(comment_id);
+        store.voice_notes.insert(comment_id, note);
+        Ok(())
+    }
+
+    fn length_limits(&self) -> LengthLimits {
+        self.limits
+    }
+
+    async fn create_conversation(&self, v_id: ObjectId) -> VoicerResult<conversation> {
+        let (post, con_replies) = {
+            let data = self.data.lock().unwrap();
+            let post = data.voice_notes.get(&v_id).cloned().ok_or(VoicerError::NotFound("Quote"))?;
+            let con_replies: Vec<replies> = post.replies.iter()
+                .filter_map(|id| data.voice_notes.get(id))
+                .map(|reply| replies { _id: reply._id, user_id: (reply.user_id, reply.name.clone()), waveform: reply.waveform.clone(), duration_ms: reply.duration_ms })
+                .collect();
+            (post, con_replies)
+        };
+
+        Ok(conversation {
+            v_id: post._id,
+            v_user_id: post.user_id,
+            reactions: post.reactions,
+            replies: con_replies,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_codec::SampleFormat;
+
+    fn store() -> MemoryStore {
+        MemoryStore::new(LengthLimits::default())
+    }
+
+    async fn add_user(store: &MemoryStore, username: &str) -> ObjectId {
+        store.create_user(username.to_string(), "secret".to_string(), username.to_string()).await.unwrap()
+    }
+
+    fn recording() -> (PcmAudio, AudioFormat) {
+        let samples = (0..4410).map(|i| ((i as f32 * 0.05).sin() * 8000.0) as i16).collect();
+        let audio = PcmAudio { samples, channels: CANONICAL_CHANNELS, sample_rate: CANONICAL_SAMPLE_RATE };
+        let format = AudioFormat { channels: CANONICAL_CHANNELS, sample_rate: CANONICAL_SAMPLE_RATE, bits_per_sample: 16, sample_format: SampleFormat::Int };
+        (audio, format)
+    }
+
+    fn user(store: &MemoryStore, user_id: ObjectId) -> Users {
+        store.data.lock().unwrap().users[&user_id].clone()
+    }
+
+    async fn add_post(store: &MemoryStore, user_id: ObjectId) -> ObjectId {
+        let (audio, format) = recording();
+        let voice_id = ObjectId::new();
+        store.create_post(user_id, audio, format, voice_id).await.unwrap();
+        voice_id
+    }
+
+    #[tokio::test]
+    async fn create_user_rejects_duplicate_usernames() {
+        let store = store();
+        add_user(&store, "ada").await;
+        let duplicate = store.create_user("ada".to_string(), "other".to_string(), "Ada".to_string()).await;
+        assert!(matches!(duplicate, Err(VoicerError::DuplicateUsername)));
+    }
+
+    #[tokio::test]
+    async fn login_checks_the_password() {
+        let store = store();
+        let user_id = add_user(&store, "ada").await;
+        let user = store.get_user_by_username("ada".to_string(), "secret".to_string()).await.unwrap();
+        assert_eq!(user._id, user_id);
+        let wrong = store.get_user_by_username("ada".to_string(), "wrong".to_string()).await;
+        assert!(matches!(wrong, Err(VoicerError::InvalidCredentials)));
+        let missing = store.get_user_by_username("bob".to_string(), "secret".to_string()).await;
+        assert!(matches!(missing, Err(VoicerError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn copied_profiles_cannot_be_logged_into() {
+        let elsewhere = store();
+        add_user(&elsewhere, "ada").await;
+        let profile = elsewhere.find_users_by_names("ada", ObjectId::new()).await.unwrap();
+
+        let store = store();
+        store.remember_profile(&profile);
+        for password in ["", "secret"] {
+            let login = store.get_user_by_username("ada".to_string(), password.to_string()).await;
+            assert!(matches!(login, Err(VoicerError::InvalidCredentials)));
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_resolve_until_revoked() {
+        let store = store();
+        let user_id = add_user(&store, "ada").await;
+        let first = store.create_session(user_id).await.unwrap();
+        let second = store.create_session(user_id).await.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(store.get_user_by_session(&first).await.unwrap()._id, user_id);
+
+        store.revoke_session(&first).await.unwrap();
+        assert!(matches!(store.get_user_by_session(&first).await, Err(VoicerError::InvalidSession)));
+        assert!(store.get_user_by_session(&second).await.is_ok());
+
+        assert_eq!(store.revoke_all_sessions(user_id).await.unwrap(), 1);
+        assert!(matches!(store.get_user_by_session(&second).await, Err(VoicerError::InvalidSession)));
+        assert!(matches!(store.get_user_by_session("not a token").await, Err(VoicerError::InvalidSession)));
+    }
+
+    #[tokio::test]
+    async fn feed_pages_newest_first_without_gaps() {
+        let store = store();
+        let reader = add_user(&store, "ada").await;
+        let author = add_user(&store, "bob").await;
+        let stranger = add_user(&store, "cy").await;
+        store.follow(reader, author).await.unwrap();
+        let mut posted = Vec::new();
+        for _ in 0..5 {
+            posted.push(add_post(&store, author).await);
+        }
+        add_post(&store, stranger).await;
+
+        let mut seen = Vec::new();
+        let mut after = None;
+        loop {
+            let page = store.get_feed_page(reader, after, 2).await.unwrap();
+            assert!(page.items.len() <= 2);
+            seen.extend(page.items.iter().map(|item| item._id));
+            match page.next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        // Posted in the same second, so the ids decide the order
+        posted.reverse();
+        assert_eq!(seen, posted);
+    }
+
+    #[tokio::test]
+    async fn follows_show_up_on_both_profiles() {
+        let store = store();
+        let ada = add_user(&store, "ada").await;
+        let bob = add_user(&store, "bob").await;
+        store.follow(ada, bob).await.unwrap();
+
+        let following = store.get_all_following_profile(ada).await.unwrap();
+        assert_eq!(following.iter().map(|user| user._id).collect::<Vec<_>>(), vec![bob]);
+        let followers = store.get_all_followers_profile(bob).await.unwrap();
+        assert_eq!(followers.iter().map(|user| user._id).collect::<Vec<_>>(), vec![ada]);
+
+        store.unfollow(ada, bob).await.unwrap();
+        assert!(store.get_all_following_profile(ada).await.unwrap().is_empty());
+        assert!(store.get_all_followers_profile(bob).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reacting_again_replaces_the_reaction() {
+        let store = store();
+        let ada = add_user(&store, "ada").await;
+        let bob = add_user(&store, "bob").await;
+        let post = add_post(&store, bob).await;
+
+        store.react_to_quote(post, ada, ReactionType::SpeakUp).await.unwrap();
+        store.react_to_quote(post, ada, ReactionType::ShutUp).await.unwrap();
+        let reactions = store.get_voice_note(post).await.unwrap().reactions;
+        assert_eq!(reactions.len(), 1);
+        assert!(matches!(reactions[0].reaction, ReactionType::ShutUp));
+
+        let missing = store.react_to_quote(ObjectId::new(), ada, ReactionType::SpeakUp).await;
+        assert!(matches!(missing, Err(VoicerError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn only_the_author_can_delete_a_post() {
+        let store = store();
+        let ada = add_user(&store, "ada").await;
+        let bob = add_user(&store, "bob").await;
+        let post = add_post(&store, bob).await;
+
+        assert!(matches!(store.delete_post(post, ada).await, Err(VoicerError::NotFound(_))));
+        assert!(store.get_voice_note(post).await.is_ok());
+
+        store.delete_post(post, bob).await.unwrap();
+        assert!(matches!(store.get_voice_note(post).await, Err(VoicerError::NotFound(_))));
+        assert!(matches!(store.delete_post(post, bob).await, Err(VoicerError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn note_summaries_carry_waveform_and_length() {
+        let store = store();
+        let ada = add_user(&store, "ada").await;
+        let post = add_post(&store, ada).await;
+
+        let summaries = store.get_note_summaries(vec![post, ObjectId::new()]).await.unwrap();
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0]._id, post);
+        assert_eq!(summaries[0].duration_ms, Some(100));
+        assert!(summaries[0].waveform.as_ref().map_or(false, |waveform| waveform.len() > 0));
+    }
+
+    #[tokio::test]
+    async fn follow_reports_whether_anything_changed() {
+        let store = store();
+        let ada = add_user(&store, "ada").await;
+        let bob = add_user(&store, "bob").await;
+
+        assert!(matches!(store.follow(ada, bob).await, Ok(FollowResult::Followed)));
+        assert!(matches!(store.follow(ada, bob).await, Ok(FollowResult::AlreadyFollowing)));
+        assert_eq!(user(&store, ada).following, vec![bob]);
+        assert_eq!(user(&store, bob).followers, vec![ada]);
+
+        assert!(matches!(store.unfollow(ada, bob).await, Ok(UnfollowResult::Unfollowed)));
+        assert!(matches!(store.unfollow(ada, bob).await, Ok(UnfollowResult::NotFollowing)));
+    }
+
+    #[tokio::test]
+    async fn follow_refuses_yourself_and_missing_users() {
+        let store = store();
+        let ada = add_user(&store, "ada").await;
+        let missing = ObjectId::new();
+
+        assert!(matches!(store.follow(ada, ada).await, Err(VoicerError::SelfFollow)));
+        assert!(matches!(store.follow(ada, missing).await, Err(VoicerError::NotFound(_))));
+        assert!(matches!(store.follow(missing, ada).await, Err(VoicerError::NotFound(_))));
+        // Nothing is half applied
+        let user = user(&store, ada);
+        assert!(user.following.is_empty() && user.followers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn removing_a_follower_updates_both_users() {
+        let store = store();
+        let ada = add_user(&store, "ada").await;
+        let bob = add_user(&store, "bob").await;
+        store.follow(bob, ada).await.unwrap();
+
+        assert!(matches!(store.remove_follower(ada, bob).await, Ok(UnfollowResult::Unfollowed)));
+        assert!(user(&store, ada).followers.is_empty());
+        assert!(user(&store, bob).following.is_empty());
+        assert!(matches!(store.remove_follower(ada, bob).await, Ok(UnfollowResult::NotFollowing)));
+    }
+}
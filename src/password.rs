@@ -0,0 +1,97 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+
+use crate::error::{VoicerError, VoicerResult};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PasswordCheck {
+    Valid,
+    // Correct, but stored in plaintext from before hashing was introduced
+    ValidNeedsRehash,
+    Invalid,
+}
+
+// Salted Argon2id hash in PHC string format (`$argon2id$v=19$...`). Argon2 is
+// deliberately slow, so it runs on the blocking pool rather than an async worker.
+pub async fn hash_password(password: &str) -> VoicerResult<String> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|err| VoicerError::Hashing(err.to_string()))
+    })
+    .await
+    .map_err(|err| VoicerError::Hashing(err.to_string()))?
+}
+
+pub async fn verify_password(password: &str, stored: &str) -> PasswordCheck {
+    let (password, stored) = (password.to_string(), stored.to_string());
+    tokio::task::spawn_blocking(move || check_password(&password, &stored)).await.unwrap_or(PasswordCheck::Invalid)
+}
+
+fn check_password(password: &str, stored: &str) -> PasswordCheck {
+    // An account without a stored password can't be logged into
+    if stored.is_empty() {
+        return PasswordCheck::Invalid;
+    }
+    match PasswordHash::new(stored) {
+        Ok(hash) => {
+            if Argon2::default().verify_password(password.as_bytes(), &hash).is_ok() {
+                PasswordCheck::Valid
+            } else {
+                PasswordCheck::Invalid
+            }
+        }
+        // Anything that isn't a PHC string is a legacy plaintext password
+        Err(_) => {
+            if !password.is_empty() && constant_time_eq(password.as_bytes(), stored.as_bytes()) {
+                PasswordCheck::ValidNeedsRehash
+            } else {
+                PasswordCheck::Invalid
+            }
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn hashes_verify_only_the_right_password() {
+        let hash = hash_password("correct horse").await.unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert_eq!(verify_password("correct horse", &hash).await, PasswordCheck::Valid);
+        assert_eq!(verify_password("wrong horse", &hash).await, PasswordCheck::Invalid);
+    }
+
+    #[tokio::test]
+    async fn hashes_are_salted() {
+        assert_ne!(hash_password("same").await.unwrap(), hash_password("same").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn plaintext_passwords_ask_for_a_rehash() {
+        assert_eq!(verify_password("legacy", "legacy").await, PasswordCheck::ValidNeedsRehash);
+        assert_eq!(verify_password("legacy", "legacz").await, PasswordCheck::Invalid);
+        assert_eq!(verify_password("legacy", "legacy2").await, PasswordCheck::Invalid);
+    }
+
+    #[tokio::test]
+    async fn empty_passwords_never_match() {
+        assert_eq!(verify_password("", "").await, PasswordCheck::Invalid);
+        assert_eq!(verify_password("anything", "").await, PasswordCheck::Invalid);
+        let hash = hash_password("secret").await.unwrap();
+        assert_eq!(verify_password("", &hash).await, PasswordCheck::Invalid);
+    }
+}
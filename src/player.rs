@@ -0,0 +1,354 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use mongodb::bson::oid::ObjectId;
+use rodio::{OutputStream, OutputStreamHandle, Sink, Source};
+
+use crate::audio_codec::PcmAudio;
+use crate::audio_stream::{AudioStream, SampleRead, StreamRead};
+use crate::error::{VoicerError, VoicerResult};
+use crate::time_stretch::{self, TimeStretch};
+
+// What the audio thread has actually played since the last (re)start. The
+// position is counted from this rather than from the wall clock, so time spent
+// buffering or paused doesn't count.
+#[derive(Default)]
+struct PlaybackClock {
+    // Samples of the clip handed to the output, not counting buffering silence
+    played: AtomicUsize,
+    // Set while the output is being fed silence waiting for the stream
+    stalled: AtomicBool,
+}
+
+// The clip currently loaded into the player.
+struct Track {
+    // None for audio that isn't a saved note, like a draft preview
+    note: Option<ObjectId>,
+    // May still be arriving while it plays
+    stream: AudioStream,
+    // Position when playback last (re)started
+    offset: Duration,
+    clock: Arc<PlaybackClock>,
+    paused: bool,
+    speed: f32,
+}
+
+impl Track {
+    fn position(&self) -> Duration {
+        let frames = self.clock.played.load(Ordering::Relaxed) / self.stream.channels() as usize;
+        let played = Duration::from_secs_f64(frames as f64 / self.stream.sample_rate().max(1) as f64);
+        // Each output frame covers `speed` frames of the clip
+        (self.offset + played.mul_f32(self.speed)).min(self.stream.duration())
+    }
+}
+
+// One audio output shared by the whole app. Playing something new replaces
+// whatever was playing before.
+pub struct Player {
+    // Opened on first use; the stream has to outlive every sink
+    output: Option<(OutputStream, OutputStreamHandle)>,
+    sink: Option<Sink>,
+    track: Option<Track>,
+    speed: f32,
+}
+
+impl Player {
+    pub fn new() -> Self {
+        Player { output: None, sink: None, track: None, speed: 1.0 }
+    }
+
+    pub fn play(&mut self, note: Option<ObjectId>, audio: PcmAudio) -> VoicerResult<()> {
+        self.play_stream(note, AudioStream::from_audio(audio))
+    }
+
+    // Starts playing straight away, however much of the stream has arrived.
+    pub fn play_stream(&mut self, note: Option<ObjectId>, stream: AudioStream) -> VoicerResult<()> {
+        self.stop();
+        self.track = Some(Track {
+            note,
+            stream,
+            offset: Duration::ZERO,
+            clock: Arc::default(),
+            paused: false,
+            speed: self.speed,
+        });
+        self.start_at(Duration::ZERO)
+    }
+
+    pub fn pause(&mut self) {
+        if let (Some(track), Some(sink)) = (self.track.as_mut(), self.sink.as_ref()) {
+            if !track.paused {
+                track.paused = true;
+                sink.pause();
+            }
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if let (Some(track), Some(sink)) = (self.track.as_mut(), self.sink.as_ref()) {
+            if track.paused {
+                track.paused = false;
+                sink.play();
+            }
+        }
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(sink) = self.sink.take() {
+            sink.stop();
+        }
+        self.track = None;
+    }
+
+    // Jumps to `position` in the current clip, keeping it paused if it was.
+    pub fn seek(&mut self, position: Duration) -> VoicerResult<()> {
+        let paused = match &self.track {
+            Some(track) => track.paused,
+            None => return Ok(()),
+        };
+        self.start_at(position)?;
+        if paused {
+            self.pause();
+        }
+        Ok(())
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    // Changes the playback speed, picking up from the same spot if something is playing.
+    pub fn set_speed(&mut self, speed: f32) -> VoicerResult<()> {
+        let speed = speed.clamp(time_stretch::MIN_SPEED, time_stretch::MAX_SPEED);
+        if speed == self.speed {
+            return Ok(());
+        }
+        self.speed = speed;
+        if self.track.is_some() {
+            let position = self.position();
+            self.seek(position)?;
+        }
+        Ok(())
+    }
+
+    // Forgets the clip once it has played to the end. Call once per frame.
+    pub fn tick(&mut self) {
+        let finished = match (&self.track, &self.sink) {
+            (Some(track), Some(sink)) => !track.paused && sink.empty(),
+            _ => false,
+        };
+        if finished {
+            self.stop();
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.track.is_some()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.track.as_ref().map_or(false, |track| track.paused)
+    }
+
+    // Playing, but waiting for more of the note to arrive.
+    pub fn is_buffering(&self) -> bool {
+        match &self.track {
+            Some(track) => !track.paused && track.clock.stalled.load(Ordering::Relaxed),
+            None => false,
+        }
+    }
+
+    pub fn now_playing(&self) -> Option<ObjectId> {
+        self.track.as_ref().and_then(|track| track.note)
+    }
+
+    pub fn position(&self) -> Duration {
+        self.track.as_ref().map_or(Duration::ZERO, Track::position)
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.track.as_ref().map_or(Duration::ZERO, |track| track.stream.duration())
+    }
+
+    // rodio 0.17 sinks can't seek, so every start gets a fresh sink fed from `position`.
+    fn start_at(&mut self, position: Duration) -> VoicerResult<()> {
+        if let Some(sink) = self.sink.take() {
+            sink.stop();
+        }
+        if self.output.is_none() {
+            let output = OutputStream::try_default().map_err(|err| VoicerError::Audio(format!("Could not open audio output: {}", err)))?;
+            self.output = Some(output);
+        }
+        let (_, handle) = self.output.as_ref().unwrap();
+        let sink = Sink::try_new(handle).map_err(|err| VoicerError::Audio(format!("Could not open audio output: {}", err)))?;
+
+        let track = match self.track.as_mut() {
+            Some(track) => track,
+            None => return Ok(()),
+        };
+        let position = position.min(track.stream.duration());
+        let frame = (position.as_secs_f64() * track.stream.sample_rate() as f64) as usize;
+        // Normal speed plays the samples as they are
+        let stretch = if self.speed == 1.0 {
+            None
+        } else {
+            Some(TimeStretch::new(track.stream.clone(), frame, self.speed))
+        };
+        let clock = Arc::new(PlaybackClock::default());
+        sink.append(TrackSource {
+            stream: track.stream.clone(),
+            next: frame * track.stream.channels() as usize,
+            block: Vec::new(),
+            block_position: 0,
+            stretch,
+            clock: clock.clone(),
+        });
+        track.speed = self.speed;
+        track.offset = position;
+        track.clock = clock;
+        track.paused = false;
+        self.sink = Some(sink);
+        Ok(())
+    }
+}
+
+// Samples read from the stream at a time, so the audio thread rarely takes its lock
+const SOURCE_BLOCK: usize = 4096;
+
+// Plays a stream from an offset, straight into the sink.
+struct TrackSource {
+    stream: AudioStream,
+    // Stream position of the sample after `block`
+    next: usize,
+    block: Vec<i16>,
+    block_position: usize,
+    stretch: Option<TimeStretch>,
+    clock: Arc<PlaybackClock>,
+}
+
+impl TrackSource {
+    fn read(&mut self) -> SampleRead {
+        if let Some(stretch) = self.stretch.as_mut() {
+            return stretch.read();
+        }
+        if self.block_position >= self.block.len() {
+            match self.stream.read(self.next, SOURCE_BLOCK) {
+                StreamRead::Samples(block) => {
+                    self.next += block.len();
+                    self.block = block;
+                    self.block_position = 0;
+                }
+                StreamRead::Pending => return SampleRead::Pending,
+                StreamRead::End => return SampleRead::End,
+            }
+        }
+        let sample = self.block[self.block_position];
+        self.block_position += 1;
+        SampleRead::Sample(sample)
+    }
+}
+
+impl Iterator for TrackSource {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        match self.read() {
+            SampleRead::Sample(sample) => {
+                self.clock.played.fetch_add(1, Ordering::Relaxed);
+                self.clock.stalled.store(false, Ordering::Relaxed);
+                Some(sample)
+            }
+            // Still buffering; play silence rather than ending
+            SampleRead::Pending => {
+                self.clock.stalled.store(true, Ordering::Relaxed);
+                Some(0)
+            }
+            SampleRead::End => None,
+        }
+    }
+}
+
+impl Source for TrackSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.stream.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.stream.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(stream: &AudioStream, speed: f32) -> (TrackSource, Arc<PlaybackClock>) {
+        let clock = Arc::new(PlaybackClock::default());
+        let stretch = if speed == 1.0 { None } else { Some(TimeStretch::new(stream.clone(), 0, speed)) };
+        let source = TrackSource { stream: stream.clone(), next: 0, block: Vec::new(), block_position: 0, stretch, clock: clock.clone() };
+        (source, clock)
+    }
+
+    fn track(stream: &AudioStream, clock: Arc<PlaybackClock>, speed: f32) -> Track {
+        Track { note: None, stream: stream.clone(), offset: Duration::ZERO, clock, paused: false, speed }
+    }
+
+    #[test]
+    fn stalls_do_not_advance_the_position() {
+        let stream = AudioStream::new(1, 1000);
+        stream.push(&[1; 500]);
+        let (mut source, clock) = source(&stream, 1.0);
+        let track = track(&stream, clock.clone(), 1.0);
+
+        assert_eq!(source.by_ref().take(500).filter(|sample| *sample == 1).count(), 500);
+        assert_eq!(track.position(), Duration::from_millis(500));
+        assert!(!clock.stalled.load(Ordering::Relaxed));
+
+        // The output is fed silence while the stream catches up
+        assert!(source.by_ref().take(300).all(|sample| sample == 0));
+        assert!(clock.stalled.load(Ordering::Relaxed));
+        assert_eq!(track.position(), Duration::from_millis(500));
+
+        stream.push(&[2; 100]);
+        stream.finish();
+        assert_eq!(source.by_ref().filter(|sample| *sample == 2).count(), 100);
+        assert!(!clock.stalled.load(Ordering::Relaxed));
+        assert_eq!(track.position(), Duration::from_millis(600));
+    }
+
+    #[test]
+    fn stretched_playback_counts_clip_time() {
+        let stream = AudioStream::from_audio(PcmAudio { samples: vec![100; 8000], channels: 1, sample_rate: 8000 });
+        let (source, clock) = source(&stream, 2.0);
+        let played = source.count();
+        // Twice as fast takes about half as many output samples
+        assert!((3500..=4500).contains(&played), "{} samples", played);
+        let position = track(&stream, clock, 2.0).position();
+        assert!(position >= Duration::from_millis(900) && position <= Duration::from_secs(1), "{:?}", position);
+    }
+
+    #[test]
+    fn seeking_plays_the_rest_from_the_offset() {
+        let samples = (0..2000).map(|i| i as i16).collect();
+        let stream = AudioStream::from_audio(PcmAudio { samples, channels: 2, sample_rate: 1000 });
+        let (mut source, clock) = source(&stream, 1.0);
+        // What start_at does for a seek to 0.25 s
+        source.next = 250 * 2;
+        let mut track = track(&stream, clock, 1.0);
+        track.offset = Duration::from_millis(250);
+
+        assert_eq!(source.by_ref().take(2).collect::<Vec<_>>(), vec![500, 501]);
+        assert_eq!(source.count(), 1498);
+        // Stereo samples are counted as frames
+        assert_eq!(track.position(), Duration::from_secs(1));
+    }
+}
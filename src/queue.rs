@@ -0,0 +1,233 @@
+use std::time::{Duration, Instant};
+
+use mongodb::bson::oid::ObjectId;
+use rand::seq::SliceRandom;
+
+pub const DEFAULT_GAP: Duration = Duration::from_millis(500);
+// How many notes after the current one are downloaded ahead of time
+pub const PREFETCH_AHEAD: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueSource {
+    Feed,
+    Profile,
+    Conversation,
+}
+
+impl QueueSource {
+    pub fn label(&self) -> &'static str {
+        match self {
+            QueueSource::Feed => "Feed",
+            QueueSource::Profile => "Your quotes",
+            QueueSource::Conversation => "Conversation",
+        }
+    }
+}
+
+// Notes to play back to back. Lives in the Gui, so it keeps its place while
+// the user moves between pages.
+pub struct PlayQueue {
+    pub source: QueueSource,
+    notes: Vec<ObjectId>,
+    // Play order as indexes into `notes`
+    order: Vec<usize>,
+    position: usize,
+    shuffled: bool,
+    pub gap: Duration,
+    // Set while the queue's current note is (or was last) in the player
+    playing: bool,
+    // When the current note ended, while waiting out the gap
+    ended_at: Option<Instant>,
+}
+
+impl PlayQueue {
+    pub fn new(source: QueueSource, notes: Vec<ObjectId>, gap: Duration) -> Self {
+        let order = (0..notes.len()).collect();
+        PlayQueue { source, notes, order, position: 0, shuffled: false, gap, playing: false, ended_at: None }
+    }
+
+    pub fn len(&self) -> usize {
+        self.notes.len()
+    }
+
+    // 1-based place in the play order, for display
+    pub fn place(&self) -> usize {
+        self.position + 1
+    }
+
+    pub fn current(&self) -> Option<ObjectId> {
+        self.order.get(self.position).map(|&i| self.notes[i])
+    }
+
+    // The next `count` notes after the current one, in play order.
+    pub fn upcoming(&self, count: usize) -> Vec<ObjectId> {
+        self.order.iter().skip(self.position + 1).take(count).map(|&i| self.notes[i]).collect()
+    }
+
+    pub fn contains(&self, note: ObjectId) -> bool {
+        self.notes.contains(&note)
+    }
+
+    // Adds notes that showed up later, e.g. another feed page.
+    pub fn extend(&mut self, notes: impl IntoIterator<Item = ObjectId>) {
+        let mut added: Vec<usize> = Vec::new();
+        for note in notes {
+            if !self.notes.contains(&note) {
+                added.push(self.notes.len());
+                self.notes.push(note);
+            }
+        }
+        if self.shuffled {
+            added.shuffle(&mut rand::thread_rng());
+        }
+        self.order.extend(added);
+    }
+
+    // Marks the current note as started by the queue and returns it.
+    pub fn start(&mut self) -> Option<ObjectId> {
+        self.playing = self.current().is_some();
+        self.ended_at = None;
+        self.current()
+    }
+
+    // Jumps to `note` and starts it.
+    pub fn start_at(&mut self, note: ObjectId) -> Option<ObjectId> {
+        let index = self.notes.iter().position(|&n| n == note)?;
+        self.position = self.order.iter().position(|&i| i == index)?;
+        self.start()
+    }
+
+    pub fn next(&mut self) -> Option<ObjectId> {
+        if self.position + 1 >= self.order.len() {
+            return None;
+        }
+        self.position += 1;
+        self.start()
+    }
+
+    pub fn previous(&mut self) -> Option<ObjectId> {
+        self.position = self.position.saturating_sub(1);
+        self.start()
+    }
+
+    pub fn is_shuffled(&self) -> bool {
+        self.shuffled
+    }
+
+    // Reorders what's left around the current note, which keeps playing.
+    pub fn set_shuffled(&mut self, shuffled: bool) {
+        if shuffled == self.shuffled {
+            return;
+        }
+        self.shuffled = shuffled;
+        let current = self.order.get(self.position).copied();
+        if shuffled {
+            let mut rest: Vec<usize> = (0..self.notes.len()).filter(|&i| Some(i) != current).collect();
+            rest.shuffle(&mut rand::thread_rng());
+            self.order = current.into_iter().chain(rest).collect();
+            self.position = 0;
+        } else {
+            self.order = (0..self.notes.len()).collect();
+            self.position = current.unwrap_or(0);
+        }
+    }
+
+    // The user played or stopped something else; don't advance on our own.
+    pub fn interrupt(&mut self) {
+        self.playing = false;
+        self.ended_at = None;
+    }
+
+    pub fn is_waiting(&self) -> bool {
+        self.ended_at.is_some()
+    }
+
+    // Called while the player is idle. Returns the next note once the gap has passed.
+    pub fn on_idle(&mut self) -> Option<ObjectId> {
+        if !self.playing {
+            return None;
+        }
+        let ended_at = *self.ended_at.get_or_insert_with(Instant::now);
+        if ended_at.elapsed() < self.gap {
+            return None;
+        }
+        let next = self.next();
+        if next.is_none() {
+            self.interrupt();
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(count: usize) -> (PlayQueue, Vec<ObjectId>) {
+        let notes: Vec<ObjectId> = (0..count).map(|_| ObjectId::new()).collect();
+        (PlayQueue::new(QueueSource::Feed, notes.clone(), Duration::ZERO), notes)
+    }
+
+    #[test]
+    fn plays_in_order_and_stops_at_the_end() {
+        let (mut queue, notes) = queue(3);
+        assert_eq!(queue.start(), Some(notes[0]));
+        assert_eq!(queue.upcoming(5), vec![notes[1], notes[2]]);
+        assert_eq!(queue.next(), Some(notes[1]));
+        assert_eq!(queue.previous(), Some(notes[0]));
+        assert_eq!(queue.start_at(notes[2]), Some(notes[2]));
+        assert_eq!(queue.place(), 3);
+        assert_eq!(queue.next(), None);
+    }
+
+    #[test]
+    fn idle_player_advances_only_while_the_queue_is_playing() {
+        let (mut queue, notes) = queue(2);
+        assert_eq!(queue.on_idle(), None);
+        queue.start();
+        assert_eq!(queue.on_idle(), Some(notes[1]));
+        assert_eq!(queue.on_idle(), None);
+        assert!(!queue.is_waiting());
+
+        queue.start_at(notes[0]);
+        queue.interrupt();
+        assert_eq!(queue.on_idle(), None);
+    }
+
+    #[test]
+    fn the_gap_is_waited_out() {
+        let (mut queue, _) = queue(2);
+        queue.gap = Duration::from_secs(60);
+        queue.start();
+        assert_eq!(queue.on_idle(), None);
+        assert!(queue.is_waiting());
+    }
+
+    #[test]
+    fn shuffling_keeps_the_current_note_and_every_other() {
+        let (mut queue, notes) = queue(20);
+        queue.start_at(notes[5]);
+        queue.set_shuffled(true);
+        assert_eq!(queue.current(), Some(notes[5]));
+        let mut order = queue.upcoming(usize::MAX);
+        order.push(notes[5]);
+        order.sort();
+        let mut expected = notes.clone();
+        expected.sort();
+        assert_eq!(order, expected);
+
+        queue.set_shuffled(false);
+        assert_eq!(queue.current(), Some(notes[5]));
+        assert_eq!(queue.upcoming(1), vec![notes[6]]);
+    }
+
+    #[test]
+    fn extending_skips_notes_already_queued() {
+        let (mut queue, notes) = queue(2);
+        let extra = ObjectId::new();
+        queue.extend(vec![notes[1], extra]);
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.upcoming(5), vec![notes[1], extra]);
+        assert!(queue.contains(extra));
+    }
+}
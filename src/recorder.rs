@@ -0,0 +1,237 @@
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, Sample, SizedSample};
+
+use crate::audio_codec::{AudioFormat, PcmAudio, SampleFormat};
+use crate::error::{VoicerError, VoicerResult};
+
+pub const DEFAULT_MAX_DURATION: Duration = Duration::from_secs(120);
+
+// Written by the input callback, read by the UI every frame.
+struct Capture {
+    samples: Vec<i16>,
+    channels: u16,
+    sample_rate: u32,
+    max_frames: usize,
+    paused: bool,
+    // Peak of the most recent callback, 0.0 to 1.0
+    level: f32,
+    error: Option<String>,
+}
+
+impl Capture {
+    fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+
+    fn push<T: Sample>(&mut self, data: &[T])
+    where
+        i16: FromSample<T>,
+    {
+        let mut peak = 0i32;
+        for sample in data {
+            peak = peak.max((sample.to_sample::<i16>() as i32).abs());
+        }
+        self.level = peak as f32 / i16::MAX as f32;
+
+        if self.paused {
+            return;
+        }
+        let room = (self.max_frames - self.frames().min(self.max_frames)) * self.channels as usize;
+        self.samples.extend(data.iter().take(room).map(|sample| sample.to_sample::<i16>()));
+    }
+}
+
+// Records from the default input device on its own thread until stopped.
+// cpal streams can't be moved between threads, so the stream lives and dies there.
+pub struct Recorder {
+    capture: Arc<Mutex<Capture>>,
+    format: AudioFormat,
+    stop: mpsc::Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Recorder {
+    pub fn start(max_duration: Duration) -> VoicerResult<Recorder> {
+        let (stop, stopped) = mpsc::channel();
+        let (ready, started) = mpsc::channel();
+        let capture = Arc::new(Mutex::new(Capture {
+            samples: Vec::new(),
+            channels: 0,
+            sample_rate: 0,
+            max_frames: 0,
+            paused: false,
+            level: 0.0,
+            error: None,
+        }));
+
+        let stream_capture = capture.clone();
+        let thread = thread::spawn(move || {
+            let stream = match open_input(stream_capture, max_duration) {
+                Ok((stream, format)) => {
+                    let _ = ready.send(Ok(format));
+                    stream
+                }
+                Err(err) => {
+                    let _ = ready.send(Err(err));
+                    return;
+                }
+            };
+            // Either an explicit stop or the Recorder being dropped ends the recording
+            let _ = stopped.recv();
+            drop(stream);
+        });
+
+        let format = match started.recv() {
+            Ok(result) => result?,
+            Err(_) => return Err(VoicerError::Audio("Recording thread exited".to_string())),
+        };
+        Ok(Recorder { capture, format, stop, thread: Some(thread) })
+    }
+
+    pub fn pause(&self) {
+        self.capture.lock().unwrap().paused = true;
+    }
+
+    pub fn resume(&self) {
+        self.capture.lock().unwrap().paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.capture.lock().unwrap().paused
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        let capture = self.capture.lock().unwrap();
+        Duration::from_secs_f64(capture.frames() as f64 / capture.sample_rate as f64)
+    }
+
+    pub fn max_duration(&self) -> Duration {
+        let capture = self.capture.lock().unwrap();
+        Duration::from_secs_f64(capture.max_frames as f64 / capture.sample_rate as f64)
+    }
+
+    pub fn level(&self) -> f32 {
+        self.capture.lock().unwrap().level
+    }
+
+    pub fn reached_limit(&self) -> bool {
+        let capture = self.capture.lock().unwrap();
+        capture.frames() >= capture.max_frames
+    }
+
+    // Stops the input stream and hands back everything captured.
+    pub fn stop(mut self) -> VoicerResult<(PcmAudio, AudioFormat)> {
+        self.finish();
+        let mut capture = self.capture.lock().unwrap();
+        if let Some(err) = capture.error.take() {
+            return Err(VoicerError::Audio(err));
+        }
+        let audio = PcmAudio {
+            samples: std::mem::take(&mut capture.samples),
+            channels: capture.channels,
+            sample_rate: capture.sample_rate,
+        };
+        Ok((audio, self.format))
+    }
+
+    fn finish(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+fn open_input(capture: Arc<Mutex<Capture>>, max_duration: Duration) -> VoicerResult<(cpal::Stream, AudioFormat)> {
+    let device = cpal::default_host()
+        .default_input_device()
+        .ok_or_else(|| VoicerError::Audio("No microphone found".to_string()))?;
+    let config = device.default_input_config().map_err(|err| VoicerError::Audio(err.to_string()))?;
+    let sample_format = config.sample_format();
+    let config: cpal::StreamConfig = config.into();
+
+    {
+        let mut capture = capture.lock().unwrap();
+        capture.channels = config.channels;
+        capture.sample_rate = config.sample_rate.0;
+        capture.max_frames = (max_duration.as_secs_f64() * config.sample_rate.0 as f64) as usize;
+    }
+
+    let format = AudioFormat {
+        channels: config.channels,
+        sample_rate: config.sample_rate.0,
+        bits_per_sample: (sample_format.sample_size() * 8) as u16,
+        sample_format: if sample_format.is_float() { SampleFormat::Float } else { SampleFormat::Int },
+    };
+    let stream = match sample_format {
+        cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config, capture),
+        cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config, capture),
+        cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config, capture),
+        cpal::SampleFormat::I32 => build_stream::<i32>(&device, &config, capture),
+        other => return Err(VoicerError::Audio(format!("Unsupported microphone format {:?}", other))),
+    }?;
+    stream.play().map_err(|err| VoicerError::Audio(err.to_string()))?;
+    Ok((stream, format))
+}
+
+fn build_stream<T>(device: &cpal::Device, config: &cpal::StreamConfig, capture: Arc<Mutex<Capture>>) -> VoicerResult<cpal::Stream>
+where
+    T: SizedSample,
+    i16: FromSample<T>,
+{
+    let error_capture = capture.clone();
+    device
+        .build_input_stream(
+            config,
+            move |data: &[T], _: &cpal::InputCallbackInfo| capture.lock().unwrap().push(data),
+            move |err| error_capture.lock().unwrap().error = Some(err.to_string()),
+            None,
+        )
+        .map_err(|err| VoicerError::Audio(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capture(max_frames: usize) -> Capture {
+        Capture { samples: Vec::new(), channels: 2, sample_rate: 44100, max_frames, paused: false, level: 0.0, error: None }
+    }
+
+    #[test]
+    fn recording_stops_at_the_limit() {
+        let mut capture = capture(3);
+        capture.push(&[1i16, 2, 3, 4]);
+        capture.push(&[5i16, 6, 7, 8]);
+        assert_eq!(capture.samples, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(capture.frames(), 3);
+    }
+
+    #[test]
+    fn paused_input_still_moves_the_meter() {
+        let mut capture = capture(100);
+        capture.paused = true;
+        capture.push(&[0i16, -16384]);
+        assert!(capture.samples.is_empty());
+        assert!((capture.level - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn float_input_is_converted() {
+        let mut capture = capture(100);
+        capture.push(&[1.0f32, -1.0]);
+        assert_eq!(capture.samples, vec![i16::MAX, i16::MIN]);
+        assert!((capture.level - 1.0).abs() < 0.01);
+    }
+}
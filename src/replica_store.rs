@@ -0,0 +1,1156 @@
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use mongodb::bson::{self, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::runtime::Handle;
+use tokio::sync::OnceCell;
+
+use crate::audio_codec::{AudioFormat, PcmAudio};
+use crate::audio_storage::AudioBlob;
+use crate::audio_stream::AudioStream;
+use crate::backend::{conversation, publicUser, FeedCursor, FeedItem, FeedPage, FollowResult, ReactionType, UnfollowResult, Users, VoiceNote};
+use crate::error::{VoicerError, VoicerResult};
+use crate::limits::LengthLimits;
+use crate::memory_store::{MemorySnapshot, MemoryStore};
+use crate::session::{self, Session};
+use crate::store::{MongoStore, SyncStatus, VoicerStore};
+
+const REPLICA_DIR: &str = "replicas";
+const REPLICA_EXTENSION: &str = "bson";
+
+// A change made offline, waiting to be replayed against MongoDB.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum PendingOp {
+    React { v_id: ObjectId, user_id: ObjectId, reaction: ReactionType },
+    Follow { user_id: ObjectId, fuser_id: ObjectId },
+    Unfollow { user_id: ObjectId, fuser_id: ObjectId },
+    RemoveFollower { user_id: ObjectId, follower_id: ObjectId },
+    // The recordings themselves are kept with their notes in the replica
+    CreatePost { user_id: ObjectId, voice_id: ObjectId, format: AudioFormat },
+    CreateComment { user_id: ObjectId, parent_id: ObjectId, comment_id: ObjectId, format: AudioFormat },
+    SetPlaybackSpeed { user_id: ObjectId, speed: f32 },
+    StartSession { session: Session },
+    EndSession { token_hash: String },
+}
+
+impl PendingOp {
+    // The note whose recording is still waiting to be uploaded, if any.
+    fn upload(&self) -> Option<ObjectId> {
+        match self {
+            PendingOp::CreatePost { voice_id, .. } => Some(*voice_id),
+            PendingOp::CreateComment { comment_id, .. } => Some(*comment_id),
+            _ => None,
+        }
+    }
+
+    fn description(&self) -> &'static str {
+        match self {
+            PendingOp::React { .. } => "Your reaction",
+            PendingOp::Follow { .. } => "Your follow",
+            PendingOp::Unfollow { .. } => "Your unfollow",
+            PendingOp::RemoveFollower { .. } => "Removing a follower",
+            PendingOp::CreatePost { .. } => "Your new quote",
+            PendingOp::CreateComment { .. } => "Your reply",
+            PendingOp::SetPlaybackSpeed { .. } => "Your playback speed",
+            PendingOp::StartSession { .. } => "Your offline login",
+            PendingOp::EndSession { .. } => "Your logout",
+        }
+    }
+}
+
+// What is saved to disk: the replica's contents and the changes not yet synced.
+#[derive(Serialize, Deserialize, Default)]
+struct ReplicaFile {
+    snapshot: MemorySnapshot,
+    pending: Vec<PendingOp>,
+    conflicts: Vec<String>,
+}
+
+// An account's replica as read back from disk.
+struct SavedAccount {
+    owner: ObjectId,
+    replica: MemoryStore,
+    pending: Vec<PendingOp>,
+    conflicts: Vec<String>,
+}
+
+// The parts of the store that are saved to disk, shared with the blocking
+// pool where saves are serialized, hashed and written. Each account that logs
+// in on this device gets its own file, and only one is loaded at a time.
+#[derive(Clone)]
+struct ReplicaState {
+    dir: PathBuf,
+    // The account whose replica is loaded; nothing is saved until someone logs in
+    owner: Arc<Mutex<Option<ObjectId>>>,
+    replica: Arc<MemoryStore>,
+    pending: Arc<Mutex<Vec<PendingOp>>>,
+    conflicts: Arc<Mutex<Vec<String>>>,
+    // Held while a save is written or accounts are switched, with the digest
+    // of the last file written so saves that change nothing are skipped
+    written: Arc<Mutex<Option<Vec<u8>>>>,
+    // Set while a save is waiting to start, so a burst of changes costs one write
+    save_queued: Arc<AtomicBool>,
+    limits: LengthLimits,
+}
+
+impl ReplicaState {
+    fn path(&self, owner: ObjectId) -> PathBuf {
+        self.dir.join(format!("{}.{}", owner.to_hex(), REPLICA_EXTENSION))
+    }
+
+    fn file(&self, owner: ObjectId) -> ReplicaFile {
+        let pending = self.pending.lock().unwrap().clone();
+        let uploads: Vec<ObjectId> = pending.iter().filter_map(PendingOp::upload).collect();
+        let mut snapshot = self.replica.snapshot();
+        // Audio that has reached MongoDB plays from the audio cache, so only
+        // recordings still waiting to be uploaded are worth keeping on disk
+        for note in &mut snapshot.voice_notes {
+            if !uploads.contains(&note._id) {
+                note.audio = None;
+                note.data.clear();
+            }
+        }
+        // Only the owner's own password hash and sessions are ever written out
+        for user in &mut snapshot.users {
+            if user._id != owner {
+                user.password = None;
+            }
+        }
+        snapshot.sessions.retain(|session| session.user_id == owner);
+        // Sorted so the same contents always serialize the same way
+        snapshot.users.sort_by_key(|user| user._id);
+        snapshot.voice_notes.sort_by_key(|note| note._id);
+        snapshot.sessions.sort_by(|a, b| a.token_hash.cmp(&b.token_hash));
+        ReplicaFile { snapshot, pending, conflicts: self.conflicts.lock().unwrap().clone() }
+    }
+
+    // Writes out the current contents unless they are what was last written.
+    // Blocks, so it runs on the blocking pool.
+    fn write(&self) {
+        let mut written = self.written.lock().unwrap();
+        self.write_locked(&mut written);
+    }
+
+    fn write_locked(&self, written: &mut Option<Vec<u8>>) {
+        let owner = match *self.owner.lock().unwrap() {
+            Some(owner) => owner,
+            None => return,
+        };
+        let bytes = match bson::to_vec(&self.file(owner)) {
+            Ok(bytes) => bytes,
+            Err(err) => return println!("Could not save the offline replica: {}", err),
+        };
+        let digest = Sha256::digest(&bytes).to_vec();
+        if written.as_ref() == Some(&digest) {
+            return;
+        }
+        // Written under a temporary name so a crash never leaves half a replica behind
+        let path = self.path(owner);
+        let partial = path.with_extension("part");
+        match fs::write(&partial, bytes).and_then(|_| fs::rename(&partial, &path)) {
+            Ok(()) => *written = Some(digest),
+            Err(err) => println!("Could not save the offline replica: {}", err),
+        }
+    }
+
+    fn read(&self, owner: ObjectId) -> SavedAccount {
+        let saved: ReplicaFile = match fs::read(self.path(owner)) {
+            Ok(bytes) => bson::from_slice(&bytes).unwrap_or_else(|err| {
+                println!("Offline replica is unreadable, starting afresh: {}", err);
+                ReplicaFile::default()
+            }),
+            Err(_) => ReplicaFile::default(),
+        };
+        SavedAccount {
+            owner,
+            replica: MemoryStore::from_snapshot(saved.snapshot, self.limits),
+            pending: saved.pending,
+            conflicts: saved.conflicts,
+        }
+    }
+
+    // The replicas of every account but the loaded one.
+    fn read_others(&self) -> Vec<SavedAccount> {
+        let loaded = *self.owner.lock().unwrap();
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+        entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != REPLICA_EXTENSION {
+                    return None;
+                }
+                ObjectId::parse_str(path.file_stem()?.to_str()?).ok()
+            })
+            .filter(|owner| Some(*owner) != loaded)
+            .map(|owner| self.read(owner))
+            .collect()
+    }
+
+    // Writes out the loaded replica, then loads `owner`'s in its place.
+    fn switch_to(&self, owner: ObjectId, saved: Option<SavedAccount>) {
+        let mut written = self.written.lock().unwrap();
+        if *self.owner.lock().unwrap() == Some(owner) {
+            return;
+        }
+        self.write_locked(&mut written);
+        let saved = saved.unwrap_or_else(|| self.read(owner));
+        self.replica.replace(saved.replica);
+        *self.pending.lock().unwrap() = saved.pending;
+        *self.conflicts.lock().unwrap() = saved.conflicts;
+        *self.owner.lock().unwrap() = Some(owner);
+        *written = None;
+    }
+}
+
+// MongoDB backed by a local replica of what this device has seen: the feed,
+// profiles, conversations and logins. While MongoDB is reachable every result
+// is copied into the replica; when it isn't, reads are served from the replica
+// and reactions, follows and new recordings are applied to it and queued. The
+// health check replays the queue in order once MongoDB is back, dropping
+// changes whose target was deleted in the meantime and reporting them as
+// conflicts. Kept under the OS data directory, next to the saved session, one
+// file per account.
+pub struct ReplicaStore {
+    // None until a client could be made from MongoDB's URL
+    remote: RwLock<Option<Arc<MongoStore>>>,
+    online: AtomicBool,
+    // Whether the first health check found MongoDB
+    first_check: OnceCell<bool>,
+    state: ReplicaState,
+    runtime: Handle,
+    limits: LengthLimits,
+}
+
+impl ReplicaStore {
+    pub async fn open(limits: LengthLimits) -> VoicerResult<Self> {
+        let dir = dirs::data_local_dir().unwrap_or_else(std::env::temp_dir).join("voicer").join(REPLICA_DIR);
+        fs::create_dir_all(&dir)?;
+        // The replicas hold password hashes and sessions, so only this OS user may read them
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+        }
+
+        let remote = match MongoStore::connect(limits).await {
+            Ok(remote) => Some(Arc::new(remote)),
+            Err(err) if err.is_unreachable() => {
+                println!("MongoDB is unreachable, starting offline: {}", err);
+                None
+            }
+            Err(err) => return Err(err),
+        };
+
+        Ok(ReplicaStore {
+            remote: RwLock::new(remote),
+            online: AtomicBool::new(false),
+            first_check: OnceCell::new(),
+            state: ReplicaState {
+                dir,
+                owner: Arc::default(),
+                replica: Arc::new(MemoryStore::new(limits)),
+                pending: Arc::default(),
+                conflicts: Arc::default(),
+                written: Arc::default(),
+                save_queued: Arc::default(),
+                limits,
+            },
+            runtime: Handle::current(),
+            limits,
+        })
+    }
+
+    // Loads the replica of the account that just logged in, unless it already
+    // is. Changes it has left over from last time are synced straight away.
+    async fn switch_account(&self, owner: ObjectId, saved: Option<SavedAccount>) {
+        if *self.state.owner.lock().unwrap() == Some(owner) {
+            return;
+        }
+        let state = self.state.clone();
+        if let Err(err) = tokio::task::spawn_blocking(move || state.switch_to(owner, saved)).await {
+            return println!("Could not load the offline replica: {}", err);
+        }
+        if let Some(remote) = self.remote().await {
+            if let Err(err) = self.sync(&remote).await {
+                println!("Sync stopped, will retry: {}", err);
+                self.online.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+
+    // Offline, only accounts that have logged in on this device can log in,
+    // against the password hash kept in their own replica.
+    async fn offline_login(&self, username: String, password: String) -> VoicerResult<Users> {
+        let loaded = *self.state.owner.lock().unwrap();
+        if loaded.and_then(|owner| self.state.replica.username(owner)).as_ref() == Some(&username) {
+            return self.state.replica.get_user_by_username(username, password).await;
+        }
+        for account in self.saved_accounts().await {
+            if account.replica.username(account.owner).as_ref() == Some(&username) {
+                let user = account.replica.get_user_by_username(username, password).await?;
+                self.switch_account(account.owner, Some(account)).await;
+                return Ok(user);
+            }
+        }
+        Err(VoicerError::NotFound("User"))
+    }
+
+    async fn offline_session(&self, token: &str) -> VoicerResult<Users> {
+        match self.state.replica.get_user_by_session(token).await {
+            Err(VoicerError::InvalidSession) => {}
+            result => return result,
+        }
+        for account in self.saved_accounts().await {
+            if let Ok(user) = account.replica.get_user_by_session(token).await {
+                self.switch_account(account.owner, Some(account)).await;
+                return Ok(user);
+            }
+        }
+        Err(VoicerError::InvalidSession)
+    }
+
+    async fn saved_accounts(&self) -> Vec<SavedAccount> {
+        let state = self.state.clone();
+        tokio::task::spawn_blocking(move || state.read_others()).await.unwrap_or_default()
+    }
+
+    // MongoDB, while it is reachable. Until the first health check has pinged
+    // it, callers wait for that rather than guess.
+    async fn remote(&self) -> Option<Arc<MongoStore>> {
+        self.first_check.get_or_init(|| self.check()).await;
+        if !self.online.load(Ordering::SeqCst) {
+            return None;
+        }
+        self.remote.read().unwrap().clone()
+    }
+
+    // Reconnects if MongoDB was lost, then syncs anything changed offline
+    // before going back online.
+    async fn check(&self) -> bool {
+        let existing = self.remote.read().unwrap().clone();
+        let remote = match existing {
+            Some(remote) => remote,
+            None => match MongoStore::connect(self.limits).await {
+                Ok(remote) => {
+                    let remote = Arc::new(remote);
+                    *self.remote.write().unwrap() = Some(remote.clone());
+                    remote
+                }
+                Err(err) => {
+                    println!("MongoDB is still unreachable: {}", err);
+                    return false;
+                }
+            },
+        };
+        if !remote.health_check().await {
+            self.online.store(false, Ordering::SeqCst);
+            return false;
+        }
+        if let Err(err) = self.sync(&remote).await {
+            println!("Sync stopped, will retry: {}", err);
+            return false;
+        }
+        if !self.online.swap(true, Ordering::SeqCst) {
+            println!("Back online");
+        }
+        true
+    }
+
+    // Errors meaning MongoDB couldn't be reached switch the store offline and
+    // are swallowed so the caller can fall back to the replica. Anything else
+    // is passed on.
+    fn fall_back(&self, err: VoicerError) -> VoicerResult<()> {
+        if !err.is_unreachable() {
+            return Err(err);
+        }
+        if self.online.swap(false, Ordering::SeqCst) {
+            println!("Lost MongoDB, working offline: {}", err);
+        }
+        Ok(())
+    }
+
+    // For changes that can't be made offline.
+    fn online_only<T>(&self, result: VoicerResult<T>) -> VoicerResult<T> {
+        match result {
+            Err(err) => {
+                self.fall_back(err)?;
+                Err(VoicerError::Offline)
+            }
+            result => result,
+        }
+    }
+
+    // Applies a change MongoDB has accepted to the replica too. The replica
+    // may not hold everything involved, so failures there are only logged.
+    fn mirrored<T>(&self, result: VoicerResult<T>) {
+        if let Err(err) = result {
+            println!("Offline replica not updated: {}", err);
+        }
+        self.save();
+    }
+
+    fn queue(&self, op: PendingOp) {
+        self.state.pending.lock().unwrap().push(op);
+        self.save();
+    }
+
+    fn save(&self) {
+        if self.state.save_queued.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let state = self.state.clone();
+        self.runtime.spawn_blocking(move || {
+            // Cleared before writing, so changes made during the write queue another save
+            state.save_queued.store(false, Ordering::SeqCst);
+            state.write();
+        });
+    }
+
+    // Offline, only notes recorded on this device have their audio here;
+    // everything else plays from the audio cache.
+    async fn check_local_audio(&self, v_id: ObjectId) -> VoicerResult<()> {
+        match self.state.replica.get_voice_note(v_id).await?.audio {
+            Some(AudioBlob::Inline { .. }) => Ok(()),
+            _ => Err(VoicerError::Offline),
+        }
+    }
+
+    // Copies a feed page into the replica, dropping posts MongoDB no longer
+    // has from the stretch of the feed the page covers.
+    fn mirror_feed(&self, user_id: ObjectId, after: Option<FeedCursor>, page: &FeedPage) {
+        let following = self.state.replica.following(user_id);
+        let newest = after.map(|cursor| (cursor.timestamp.timestamp(), cursor._id));
+        let oldest = page.next.map(|cursor| (cursor.timestamp.timestamp(), cursor._id));
+        self.state.replica.forget_notes(|note| {
+            let position = (note.timestamp.timestamp(), note._id);
+            note.is_post
+                && following.contains(&note.user_id)
+                && newest.map_or(true, |newest| position < newest)
+                && oldest.map_or(true, |oldest| position >= oldest)
+                && !page.items.iter().any(|item| item._id == note._id)
+        });
+        for item in &page.items {
+            self.state.replica.remember_note(feed_note(item));
+        }
+        self.save();
+    }
+
+    async fn mirror_conversation(&self, con: &conversation) {
+        if let Ok(mut post) = self.state.replica.get_voice_note(con.v_id).await {
+            post.reactions = con.reactions.clone();
+            post.replies = con.replies.iter().map(|reply| reply._id).collect();
+            self.state.replica.remember_note(post);
+        }
+        for reply in &con.replies {
+            let mut note = match self.state.replica.get_voice_note(reply._id).await {
+                Ok(note) => note,
+                Err(_) => VoiceNote {
+                    _id: reply._id,
+                    user_id: reply.user_id.0,
+                    name: String::new(),
+                    is_post: false,
+                    data: Vec::new(),
+                    audio: None,
+                    source_format: None,
+                    loudness: None,
+                    waveform: None,
+                    duration_ms: None,
+                    replies: Vec::new(),
+                    reactions: Vec::new(),
+                    timestamp: Utc::now(),
+                },
+            };
+            note.name = reply.user_id.1.clone();
+            note.waveform = reply.waveform.clone();
+            note.duration_ms = reply.duration_ms;
+            self.state.replica.remember_note(note);
+        }
+        self.save();
+    }
+
+    // Replays offline changes in the order they were made. Stops at the first
+    // one MongoDB can't be reached for, leaving it and the rest queued.
+    async fn sync(&self, remote: &MongoStore) -> VoicerResult<()> {
+        loop {
+            let op = match self.state.pending.lock().unwrap().first() {
+                Some(op) => op.clone(),
+                None => return Ok(()),
+            };
+            match self.replay(remote, &op).await {
+                Ok(()) => {}
+                Err(err) if err.is_unreachable() => return Err(err),
+                Err(err) => self.conflict(&op, err).await,
+            }
+            self.state.pending.lock().unwrap().remove(0);
+            self.save();
+        }
+    }
+
+    async fn replay(&self, remote: &MongoStore, op: &PendingOp) -> VoicerResult<()> {
+        match op {
+            PendingOp::React { v_id, user_id, reaction } => remote.react_to_quote(*v_id, *user_id, *reaction).await,
+            PendingOp::Follow { user_id, fuser_id } => remote.follow(*user_id, *fuser_id).await.map(|_| ()),
+            PendingOp::Unfollow { user_id, fuser_id } => remote.unfollow(*user_id, *fuser_id).await.map(|_| ()),
+            PendingOp::RemoveFollower { user_id, follower_id } => remote.remove_follower(*user_id, *follower_id).await.map(|_| ()),
+            // An earlier attempt may have stored the note before the connection dropped,
+            // so only the missing link is redone rather than reporting a conflict
+            PendingOp::CreatePost { user_id, voice_id, format } => {
+                if self.published(remote, *voice_id).await? {
+                    return remote.save_voice_note(*user_id, *voice_id).await;
+                }
+                let recording = self.state.replica.get_voice_audio(*voice_id).await?;
+                remote.create_post(*user_id, recording, *format, *voice_id).await
+            }
+            PendingOp::CreateComment { user_id, parent_id, comment_id, format } => {
+                // Checked first so a reply to a deleted quote isn't stored at all
+                remote.get_voice_note(*parent_id).await?;
+                if self.published(remote, *comment_id).await? {
+                    return remote.add_reply(*parent_id, *comment_id).await;
+                }
+                let recording = self.state.replica.get_voice_audio(*comment_id).await?;
+                remote.create_comment(*user_id, parent_id.to_hex(), *comment_id, recording, *format).await
+            }
+            PendingOp::SetPlaybackSpeed { user_id, speed } => remote.update_playback_speed(*user_id, *speed).await,
+            PendingOp::StartSession { session } => remote.insert_session(session.clone()).await,
+            PendingOp::EndSession { token_hash } => remote.revoke_session_by_hash(token_hash).await,
+        }
+    }
+
+    async fn published(&self, remote: &MongoStore, v_id: ObjectId) -> VoicerResult<bool> {
+        match remote.get_voice_note(v_id).await {
+            Ok(_) => Ok(true),
+            Err(VoicerError::NotFound(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Records why an offline change was dropped and takes it back out of the replica.
+    async fn conflict(&self, op: &PendingOp, err: VoicerError) {
+        let deleted = matches!(err, VoicerError::NotFound(_));
+        let message = match &err {
+            VoicerError::NotFound(what) => format!("{} was dropped because the {} has been deleted", op.description(), what.to_lowercase()),
+            err => format!("{} was dropped: {}", op.description(), err),
+        };
+        println!("Sync conflict: {}", message);
+
+        match op {
+            PendingOp::React { v_id, .. } if deleted => self.state.replica.forget_notes(|note| note._id == *v_id),
+            PendingOp::Follow { user_id, fuser_id } => {
+                self.mirrored(self.state.replica.unfollow(*user_id, *fuser_id).await);
+                if deleted {
+                    self.state.replica.forget_notes(|note| note.user_id == *fuser_id);
+                }
+            }
+            PendingOp::CreatePost { voice_id, .. } => self.state.replica.forget_notes(|note| note._id == *voice_id),
+            PendingOp::CreateComment { parent_id, comment_id, .. } => {
+                self.state.replica.forget_notes(|note| note._id == *comment_id || (deleted && note._id == *parent_id))
+            }
+            _ => {}
+        }
+        self.state.conflicts.lock().unwrap().push(message);
+    }
+}
+
+// A feed item as a note without audio.
+fn feed_note(item: &FeedItem) -> VoiceNote {
+    VoiceNote {
+        _id: item._id,
+        user_id: item.user_id,
+        name: item.name.clone(),
+        is_post: true,
+        data: Vec::new(),
+        audio: None,
+        source_format: None,
+        loudness: None,
+        waveform: item.waveform.clone(),
+        duration_ms: item.duration_ms,
+        replies: item.replies.clone(),
+        reactions: item.reactions.clone(),
+        timestamp: item.timestamp,
+    }
+}
+
+#[async_trait]
+impl VoicerStore for ReplicaStore {
+    async fn create_user(&self, username: String, password: String, name: String) -> VoicerResult<ObjectId> {
+        let remote = self.remote().await.ok_or(VoicerError::Offline)?;
+        self.online_only(remote.create_user(username, password, name).await)
+    }
+
+    async fn get_user_by_username(&self, username: String, password: String) -> VoicerResult<Users> {
+        if let Some(remote) = self.remote().await {
+            match remote.get_user_by_username(username.clone(), password.clone()).await {
+                Ok(user) => {
+                    self.switch_account(user._id, None).await;
+                    self.state.replica.remember_user(user.clone());
+                    self.save();
+                    return Ok(user);
+                }
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        self.offline_login(username, password).await
+    }
+
+    async fn find_users_by_names(&self, username: &str, user_id: ObjectId) -> VoicerResult<publicUser> {
+        if let Some(remote) = self.remote().await {
+            match remote.find_users_by_names(username, user_id).await {
+                Ok(profile) => {
+                    self.state.replica.remember_profile(&profile);
+                    self.save();
+                    return Ok(profile);
+                }
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        self.state.replica.find_users_by_names(username, user_id).await
+    }
+
+    async fn update_user_name_by_username(&self, username: &str, new_name: &str) -> VoicerResult<()> {
+        let remote = self.remote().await.ok_or(VoicerError::Offline)?;
+        self.online_only(remote.update_user_name_by_username(username, new_name).await)?;
+        self.mirrored(self.state.replica.update_user_name_by_username(username, new_name).await);
+        Ok(())
+    }
+
+    async fn update_password_by_username(&self, username: &str, new_password: &str) -> VoicerResult<()> {
+        let remote = self.remote().await.ok_or(VoicerError::Offline)?;
+        self.online_only(remote.update_password_by_username(username, new_password).await)?;
+        self.mirrored(self.state.replica.update_password_by_username(username, new_password).await);
+        Ok(())
+    }
+
+    async fn update_description_by_username(&self, username: &str, new_desc: &str) -> VoicerResult<()> {
+        let remote = self.remote().await.ok_or(VoicerError::Offline)?;
+        self.online_only(remote.update_description_by_username(username, new_desc).await)?;
+        self.mirrored(self.state.replica.update_description_by_username(username, new_desc).await);
+        Ok(())
+    }
+
+    async fn update_playback_speed(&self, user_id: ObjectId, speed: f32) -> VoicerResult<()> {
+        if let Some(remote) = self.remote().await {
+            match remote.update_playback_speed(user_id, speed).await {
+                Ok(()) => {
+                    self.mirrored(self.state.replica.update_playback_speed(user_id, speed).await);
+                    return Ok(());
+                }
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        self.state.replica.update_playback_speed(user_id, speed).await?;
+        self.queue(PendingOp::SetPlaybackSpeed { user_id, speed });
+        Ok(())
+    }
+
+    async fn create_session(&self, user_id: ObjectId) -> VoicerResult<String> {
+        if let Some(remote) = self.remote().await {
+            match remote.create_session(user_id).await {
+                Ok(token) => {
+                    self.state.replica.remember_session(Session::for_token(user_id, &token));
+                    self.save();
+                    return Ok(token);
+                }
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        let (new_session, token) = Session::issue(user_id);
+        self.state.replica.remember_session(new_session.clone());
+        self.queue(PendingOp::StartSession { session: new_session });
+        Ok(token)
+    }
+
+    async fn get_user_by_session(&self, token: &str) -> VoicerResult<Users> {
+        if let Some(remote) = self.remote().await {
+            match remote.get_user_by_session(token).await {
+                Ok(user) => {
+                    self.switch_account(user._id, None).await;
+                    self.state.replica.remember_user(user.clone());
+                    self.state.replica.remember_session(Session::for_token(user._id, token));
+                    self.save();
+                    return Ok(user);
+                }
+                Err(VoicerError::InvalidSession) => {
+                    self.state.replica.forget_session(&session::hash_token(token));
+                    self.save();
+                    return Err(VoicerError::InvalidSession);
+                }
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        self.offline_session(token).await
+    }
+
+    async fn revoke_session(&self, token: &str) -> VoicerResult<()> {
+        let token_hash = session::hash_token(token);
+        if let Some(remote) = self.remote().await {
+            match remote.revoke_session(token).await {
+                Ok(()) => {
+                    self.state.replica.forget_session(&token_hash);
+                    self.save();
+                    return Ok(());
+                }
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        self.state.replica.forget_session(&token_hash);
+        self.queue(PendingOp::EndSession { token_hash });
+        Ok(())
+    }
+
+    async fn revoke_all_sessions(&self, user_id: ObjectId) -> VoicerResult<u64> {
+        let remote = self.remote().await.ok_or(VoicerError::Offline)?;
+        let revoked = self.online_only(remote.revoke_all_sessions(user_id).await)?;
+        self.mirrored(self.state.replica.revoke_all_sessions(user_id).await);
+        Ok(revoked)
+    }
+
+    async fn create_post(&self, user_id: ObjectId, recording: PcmAudio, format: AudioFormat, voice_id: ObjectId) -> VoicerResult<()> {
+        if let Some(remote) = self.remote().await {
+            match remote.create_post(user_id, recording.clone(), format, voice_id).await {
+                Ok(()) => return Ok(()),
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        self.state.replica.create_post(user_id, recording, format, voice_id).await?;
+        self.queue(PendingOp::CreatePost { user_id, voice_id, format });
+        Ok(())
+    }
+
+    async fn delete_post(&self, voice_note_id: ObjectId, user_id: ObjectId) -> VoicerResult<()> {
+        let remote = self.remote().await.ok_or(VoicerError::Offline)?;
+        self.online_only(remote.delete_post(voice_note_id, user_id).await)?;
+        self.mirrored(self.state.replica.delete_post(voice_note_id, user_id).await);
+        Ok(())
+    }
+
+    async fn get_voice_note(&self, v_id: ObjectId) -> VoicerResult<VoiceNote> {
+        if let Some(remote) = self.remote().await {
+            match remote.get_voice_note(v_id).await {
+                Ok(note) => {
+                    self.state.replica.remember_note(note.clone());
+                    self.save();
+                    return Ok(note);
+                }
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        self.state.replica.get_voice_note(v_id).await
+    }
+
+    async fn get_voice_audio(&self, v_id: ObjectId) -> VoicerResult<PcmAudio> {
+        if let Some(remote) = self.remote().await {
+            match remote.get_voice_audio(v_id).await {
+                Err(err) => self.fall_back(err)?,
+                result => return result,
+            }
+        }
+        self.check_local_audio(v_id).await?;
+        self.state.replica.get_voice_audio(v_id).await
+    }
+
+    async fn get_feed_page(&self, user_id: ObjectId, after: Option<FeedCursor>, limit: i64) -> VoicerResult<FeedPage> {
+        if let Some(remote) = self.remote().await {
+            match remote.get_feed_page(user_id, after, limit).await {
+                Ok(page) => {
+                    self.mirror_feed(user_id, after, &page);
+                    return Ok(page);
+                }
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        self.state.replica.get_feed_page(user_id, after, limit).await
+    }
+
+    async fn get_note_summaries(&self, ids: Vec<ObjectId>) -> VoicerResult<Vec<FeedItem>> {
+        if let Some(remote) = self.remote().await {
+            match remote.get_note_summaries(ids.clone()).await {
+                Ok(items) => {
+                    for item in &items {
+                        self.state.replica.remember_note(feed_note(item));
+                    }
+                    self.save();
+                    return Ok(items);
+                }
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        self.state.replica.get_note_summaries(ids).await
+    }
+
+    async fn follow(&self, user_id: ObjectId, fuser_id: ObjectId) -> VoicerResult<FollowResult> {
+        if let Some(remote) = self.remote().await {
+            match remote.follow(user_id, fuser_id).await {
+                Ok(result) => {
+                    self.mirrored(self.state.replica.follow(user_id, fuser_id).await);
+                    return Ok(result);
+                }
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        let result = self.state.replica.follow(user_id, fuser_id).await?;
+        if result == FollowResult::Followed {
+            self.queue(PendingOp::Follow { user_id, fuser_id });
+        }
+        Ok(result)
+    }
+
+    async fn unfollow(&self, user_id: ObjectId, fuser_id: ObjectId) -> VoicerResult<UnfollowResult> {
+        if let Some(remote) = self.remote().await {
+            match remote.unfollow(user_id, fuser_id).await {
+                Ok(result) => {
+                    self.mirrored(self.state.replica.unfollow(user_id, fuser_id).await);
+                    return Ok(result);
+                }
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        let result = self.state.replica.unfollow(user_id, fuser_id).await?;
+        if result == UnfollowResult::Unfollowed {
+            self.queue(PendingOp::Unfollow { user_id, fuser_id });
+        }
+        Ok(result)
+    }
+
+    async fn remove_follower(&self, user_id: ObjectId, follower_id: ObjectId) -> VoicerResult<UnfollowResult> {
+        if let Some(remote) = self.remote().await {
+            match remote.remove_follower(user_id, follower_id).await {
+                Ok(result) => {
+                    self.mirrored(self.state.replica.remove_follower(user_id, follower_id).await);
+                    return Ok(result);
+                }
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        let result = self.state.replica.remove_follower(user_id, follower_id).await?;
+        if result == UnfollowResult::Unfollowed {
+            self.queue(PendingOp::RemoveFollower { user_id, follower_id });
+        }
+        Ok(result)
+    }
+
+    async fn get_all_following_profile(&self, user_id: ObjectId) -> VoicerResult<Vec<publicUser>> {
+        if let Some(remote) = self.remote().await {
+            match remote.get_all_following_profile(user_id).await {
+                Ok(profiles) => {
+                    profiles.iter().for_each(|profile| self.state.replica.remember_profile(profile));
+                    self.save();
+                    return Ok(profiles);
+                }
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        self.state.replica.get_all_following_profile(user_id).await
+    }
+
+    async fn get_all_followers_profile(&self, user_id: ObjectId) -> VoicerResult<Vec<publicUser>> {
+        if let Some(remote) = self.remote().await {
+            match remote.get_all_followers_profile(user_id).await {
+                Ok(profiles) => {
+                    profiles.iter().for_each(|profile| self.state.replica.remember_profile(profile));
+                    self.save();
+                    return Ok(profiles);
+                }
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        self.state.replica.get_all_followers_profile(user_id).await
+    }
+
+    async fn react_to_quote(&self, v_id: ObjectId, user_id: ObjectId, reaction: ReactionType) -> VoicerResult<()> {
+        if let Some(remote) = self.remote().await {
+            match remote.react_to_quote(v_id, user_id, reaction).await {
+                Ok(()) => {
+                    self.mirrored(self.state.replica.react_to_quote(v_id, user_id, reaction).await);
+                    return Ok(());
+                }
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        self.state.replica.react_to_quote(v_id, user_id, reaction).await?;
+        self.queue(PendingOp::React { v_id, user_id, reaction });
+        Ok(())
+    }
+
+    async fn create_comment(&self, user_id: ObjectId, voice_id: String, comment_id: ObjectId, recording: PcmAudio, format: AudioFormat) -> VoicerResult<()> {
+        if let Some(remote) = self.remote().await {
+            match remote.create_comment(user_id, voice_id.clone(), comment_id, recording.clone(), format).await {
+                Ok(()) => return Ok(()),
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        let parent_id = ObjectId::parse_str(&voice_id).map_err(|_| VoicerError::NotFound("Quote"))?;
+        self.state.replica.create_comment(user_id, voice_id, comment_id, recording, format).await?;
+        self.queue(PendingOp::CreateComment { user_id, parent_id, comment_id, format });
+        Ok(())
+    }
+
+    async fn create_conversation(&self, v_id: ObjectId) -> VoicerResult<conversation> {
+        if let Some(remote) = self.remote().await {
+            match remote.create_conversation(v_id).await {
+                Ok(con) => {
+                    self.mirror_conversation(&con).await;
+                    return Ok(con);
+                }
+                Err(err) => self.fall_back(err)?,
+            }
+        }
+        self.state.replica.create_conversation(v_id).await
+    }
+
+    fn length_limits(&self) -> LengthLimits {
+        self.limits
+    }
+
+    async fn health_check(&self) -> bool {
+        match self.first_check.get() {
+            Some(_) => self.check().await,
+            // Also what store calls are waiting on, so it only runs once
+            None => *self.first_check.get_or_init(|| self.check()).await,
+        }
+    }
+
+    async fn playback_audio(&self, v_id: ObjectId) -> VoicerResult<PcmAudio> {
+        if let Some(remote) = self.remote().await {
+            match remote.playback_audio(v_id).await {
+                Err(err) => self.fall_back(err)?,
+                result => return result,
+            }
+        }
+        self.check_local_audio(v_id).await?;
+        self.state.replica.playback_audio(v_id).await
+    }
+
+    async fn stream_audio(&self, v_id: ObjectId, stream: AudioStream) -> VoicerResult<()> {
+        // A failed download may already have pushed part of the clip, so there is no falling back here
+        if let Some(remote) = self.remote().await {
+            return self.online_only(remote.stream_audio(v_id, stream).await);
+        }
+        self.check_local_audio(v_id).await?;
+        self.state.replica.stream_audio(v_id, stream).await
+    }
+
+    fn sync_status(&self) -> SyncStatus {
+        SyncStatus { offline: !self.online.load(Ordering::SeqCst), pending: self.state.pending.lock().unwrap().len() }
+    }
+
+    fn take_conflicts(&self) -> Vec<String> {
+        let conflicts: Vec<String> = self.state.conflicts.lock().unwrap().drain(..).collect();
+        if !conflicts.is_empty() {
+            self.save();
+        }
+        conflicts
+    }
+
+    async fn migrate_legacy_audio(&self) -> VoicerResult<u64> {
+        match self.remote().await {
+            Some(remote) => remote.migrate_legacy_audio().await,
+            None => Ok(0),
+        }
+    }
+
+    async fn backfill_durations(&self) -> VoicerResult<u64> {
+        match self.remote().await {
+            Some(remote) => remote.backfill_durations().await,
+            None => Ok(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_codec::{SampleFormat, CANONICAL_CHANNELS, CANONICAL_SAMPLE_RATE};
+    use std::path::Path;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("voicer-replicas-{}", ObjectId::new().to_hex()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // A store that has never reached MongoDB, saving into `dir`.
+    fn offline(dir: &Path) -> ReplicaStore {
+        let limits = LengthLimits::default();
+        ReplicaStore {
+            remote: RwLock::new(None),
+            online: AtomicBool::new(false),
+            first_check: OnceCell::new_with(Some(false)),
+            state: ReplicaState {
+                dir: dir.to_path_buf(),
+                owner: Arc::default(),
+                replica: Arc::new(MemoryStore::new(limits)),
+                pending: Arc::default(),
+                conflicts: Arc::default(),
+                written: Arc::default(),
+                save_queued: Arc::default(),
+                limits,
+            },
+            runtime: Handle::current(),
+            limits,
+        }
+    }
+
+    // Adds an account to the replica, as logging in online would.
+    async fn log_in(store: &ReplicaStore, username: &str) -> ObjectId {
+        let user_id = store.state.replica.create_user(username.to_string(), "secret".to_string(), username.to_string()).await.unwrap();
+        *store.state.owner.lock().unwrap() = Some(user_id);
+        user_id
+    }
+
+    fn recording() -> (PcmAudio, AudioFormat) {
+        let samples = (0..4410).map(|i| ((i as f32 * 0.05).sin() * 8000.0) as i16).collect();
+        let audio = PcmAudio { samples, channels: CANONICAL_CHANNELS, sample_rate: CANONICAL_SAMPLE_RATE };
+        let format = AudioFormat { channels: CANONICAL_CHANNELS, sample_rate: CANONICAL_SAMPLE_RATE, bits_per_sample: 16, sample_format: SampleFormat::Int };
+        (audio, format)
+    }
+
+    // Writes out the current contents, then reads them back.
+    async fn saved(store: &ReplicaStore) -> ReplicaFile {
+        let state = store.state.clone();
+        tokio::task::spawn_blocking(move || state.write()).await.unwrap();
+        let owner = store.state.owner.lock().unwrap().unwrap();
+        bson::from_slice(&fs::read(store.state.path(owner)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn only_new_recordings_wait_for_upload() {
+        let (user_id, note_id) = (ObjectId::new(), ObjectId::new());
+        let format = recording().1;
+        assert_eq!(PendingOp::CreatePost { user_id, voice_id: note_id, format }.upload(), Some(note_id));
+        assert_eq!(PendingOp::CreateComment { user_id, parent_id: ObjectId::new(), comment_id: note_id, format }.upload(), Some(note_id));
+        assert_eq!(PendingOp::React { v_id: note_id, user_id, reaction: ReactionType::SpeakUp }.upload(), None);
+        assert_eq!(PendingOp::Follow { user_id, fuser_id: ObjectId::new() }.upload(), None);
+    }
+
+    #[tokio::test]
+    async fn offline_posts_are_queued_and_saved_with_their_audio() {
+        let dir = temp_dir();
+        let store = offline(&dir);
+        let user_id = log_in(&store, "ada").await;
+        let (audio, format) = recording();
+        let voice_id = ObjectId::new();
+        store.create_post(user_id, audio, format, voice_id).await.unwrap();
+
+        let status = store.sync_status();
+        assert!(status.offline);
+        assert_eq!(status.pending, 1);
+
+        let file = saved(&store).await;
+        assert!(matches!(file.pending[..], [PendingOp::CreatePost { voice_id: id, .. }] if id == voice_id));
+        let note = file.snapshot.voice_notes.iter().find(|note| note._id == voice_id).unwrap();
+        assert!(note.audio.is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn synced_audio_is_left_out_of_the_saved_replica() {
+        let dir = temp_dir();
+        let store = offline(&dir);
+        let user_id = log_in(&store, "ada").await;
+        let (audio, format) = recording();
+        let voice_id = ObjectId::new();
+        // Already on MongoDB, so nothing is queued for it
+        store.state.replica.create_post(user_id, audio, format, voice_id).await.unwrap();
+        store.react_to_quote(voice_id, user_id, ReactionType::SpeakUp).await.unwrap();
+
+        let file = saved(&store).await;
+        assert!(matches!(file.pending[..], [PendingOp::React { v_id, .. }] if v_id == voice_id));
+        let note = file.snapshot.voice_notes.iter().find(|note| note._id == voice_id).unwrap();
+        assert!(note.audio.is_none());
+        assert!(note.data.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unchanged_replicas_are_not_saved_again() {
+        let dir = temp_dir();
+        let store = offline(&dir);
+        let user_id = log_in(&store, "ada").await;
+        saved(&store).await;
+        fs::remove_file(store.state.path(user_id)).unwrap();
+        store.state.write();
+        assert!(!store.state.path(user_id).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn nothing_is_saved_before_anyone_logs_in() {
+        let dir = temp_dir();
+        let store = offline(&dir);
+        store.state.write();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_the_owners_credentials_are_saved() {
+        let dir = temp_dir();
+        let store = offline(&dir);
+        let bob = store.state.replica.create_user("bob".to_string(), "secret".to_string(), "Bob".to_string()).await.unwrap();
+        let ada = log_in(&store, "ada").await;
+        store.create_session(ada).await.unwrap();
+        store.create_session(bob).await.unwrap();
+
+        let file = saved(&store).await;
+        let password = |id| file.snapshot.users.iter().find(|user| user._id == id).unwrap().password.clone();
+        assert!(password(ada).is_some());
+        assert!(password(bob).is_none());
+        assert!(file.snapshot.sessions.iter().all(|session| session.user_id == ada));
+        assert_eq!(file.snapshot.sessions.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn offline_logins_need_an_account_that_logged_in_here() {
+        let dir = temp_dir();
+        let first = offline(&dir);
+        first.state.replica.create_user("bob".to_string(), "secret".to_string(), "Bob".to_string()).await.unwrap();
+        let ada = log_in(&first, "ada").await;
+        let elsewhere = MemoryStore::new(LengthLimits::default());
+        elsewhere.create_user("carol".to_string(), "secret".to_string(), "Carol".to_string()).await.unwrap();
+        first.state.replica.remember_profile(&elsewhere.find_users_by_names("carol", ada).await.unwrap());
+        saved(&first).await;
+
+        // As if the app was restarted without MongoDB
+        let store = offline(&dir);
+        for (username, password) in [("bob", "secret"), ("carol", ""), ("carol", "secret")] {
+            let login = store.get_user_by_username(username.to_string(), password.to_string()).await;
+            assert!(login.is_err(), "{} logged in", username);
+        }
+        let wrong = store.get_user_by_username("ada".to_string(), "wrong".to_string()).await;
+        assert!(matches!(wrong, Err(VoicerError::InvalidCredentials)));
+        assert_eq!(*store.state.owner.lock().unwrap(), None);
+
+        let user = store.get_user_by_username("ada".to_string(), "secret".to_string()).await.unwrap();
+        assert_eq!(user._id, ada);
+        assert_eq!(*store.state.owner.lock().unwrap(), Some(ada));
+        let token = store.create_session(ada).await.unwrap();
+        assert_eq!(store.get_user_by_session(&token).await.unwrap()._id, ada);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn saved_sessions_pick_their_account() {
+        let dir = temp_dir();
+        let first = offline(&dir);
+        let ada = log_in(&first, "ada").await;
+        let token = first.create_session(ada).await.unwrap();
+        saved(&first).await;
+
+        let store = offline(&dir);
+        assert!(matches!(store.get_user_by_session("not a token").await, Err(VoicerError::InvalidSession)));
+        assert_eq!(store.get_user_by_session(&token).await.unwrap()._id, ada);
+        assert_eq!(*store.state.owner.lock().unwrap(), Some(ada));
+        assert_eq!(store.sync_status().pending, 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
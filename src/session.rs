@@ -0,0 +1,115 @@
+use std::fs;
+use std::path::PathBuf;
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::VoicerResult;
+
+pub const SESSION_LIFETIME_DAYS: i64 = 30;
+
+// Only the SHA-256 of the token is stored, so a leaked `sessions` collection
+// can't be replayed as logins.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub _id: ObjectId,
+    pub token_hash: String,
+    pub user_id: ObjectId,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub created_at: DateTime<Utc>,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Session {
+    // Returns the session to store and the raw token to hand to the client.
+    pub fn issue(user_id: ObjectId) -> (Session, String) {
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+        (Session::for_token(user_id, &token), token)
+    }
+
+    // A fresh session for a token that has already been handed out.
+    pub fn for_token(user_id: ObjectId, token: &str) -> Session {
+        let now = Utc::now();
+        Session {
+            _id: ObjectId::new(),
+            token_hash: hash_token(token),
+            user_id: user_id,
+            created_at: now,
+            expires_at: now + Duration::days(SESSION_LIFETIME_DAYS),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= Utc::now()
+    }
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+// Local copy of the token so the app can reopen logged in ("Remember me").
+fn saved_session_path() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|dir| dir.join("voicer").join("session"))
+}
+
+pub fn save_local_token(token: &str) -> VoicerResult<()> {
+    if let Some(path) = saved_session_path() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, token)?;
+    }
+    Ok(())
+}
+
+pub fn load_local_token() -> Option<String> {
+    let token = fs::read_to_string(saved_session_path()?).ok()?;
+    let token = token.trim().to_string();
+    if token.is_empty() {
+        None
+    } else {
+        Some(token)
+    }
+}
+
+pub fn clear_local_token() {
+    if let Some(path) = saved_session_path() {
+        if path.exists() {
+            if let Err(err) = fs::remove_file(path) {
+                println!("Error deleting saved session: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_token_hash_is_stored() {
+        let user_id = ObjectId::new();
+        let (session, token) = Session::issue(user_id);
+        assert_eq!(token.len(), 64);
+        assert_ne!(session.token_hash, token);
+        assert_eq!(session.token_hash, hash_token(&token));
+        assert_eq!(session.user_id, user_id);
+        assert_ne!(Session::issue(user_id).1, token);
+    }
+
+    #[test]
+    fn sessions_expire_after_their_lifetime() {
+        let mut session = Session::for_token(ObjectId::new(), "token");
+        assert_eq!(session.expires_at - session.created_at, Duration::days(SESSION_LIFETIME_DAYS));
+        assert!(!session.is_expired());
+        session.expires_at = Utc::now() - Duration::seconds(1);
+        assert!(session.is_expired());
+    }
+}
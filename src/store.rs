@@ -0,0 +1,407 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use async_trait::async_trait;
+use mongodb::bson::{doc, oid::ObjectId};
+use mongodb::options::GridFsBucketOptions;
+use mongodb::{Client, Collection};
+
+use crate::audio_codec::{AudioFormat, PcmAudio};
+use crate::audio_storage::GridFsStorage;
+use crate::audio_stream::AudioStream;
+use crate::backend::{self, conversation, publicUser, FeedCursor, FeedItem, FeedPage, FollowResult, ReactionType, UnfollowResult, Users, VoiceNote};
+use crate::error::VoicerResult;
+use crate::limits::LengthLimits;
+use crate::session::Session;
+
+// Where the store is getting its data from, for the status bar.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyncStatus {
+    pub offline: bool,
+    // Changes made offline that haven't reached MongoDB yet
+    pub pending: usize,
+}
+
+#[async_trait]
+pub trait VoicerStore: Send + Sync {
+    // Users
+    async fn create_user(&self, username: String, password: String, name: String) -> VoicerResult<ObjectId>;
+    async fn get_user_by_username(&self, username: String, password: String) -> VoicerResult<Users>;
+    async fn find_users_by_names(&self, username: &str, user_id: ObjectId) -> VoicerResult<publicUser>;
+    async fn update_user_name_by_username(&self, username: &str, new_name: &str) -> VoicerResult<()>;
+    async fn update_password_by_username(&self, username: &str, new_password: &str) -> VoicerResult<()>;
+    async fn update_description_by_username(&self, username: &str, new_desc: &str) -> VoicerResult<()>;
+    async fn update_playback_speed(&self, user_id: ObjectId, speed: f32) -> VoicerResult<()>;
+
+    // Sessions
+    async fn create_session(&self, user_id: ObjectId) -> VoicerResult<String>;
+    async fn get_user_by_session(&self, token: &str) -> VoicerResult<Users>;
+    async fn revoke_session(&self, token: &str) -> VoicerResult<()>;
+    async fn revoke_all_sessions(&self, user_id: ObjectId) -> VoicerResult<u64>;
+
+    // Voice notes
+    // Rejects recordings longer than the store's limits
+    async fn create_post(&self, user_id: ObjectId, recording: PcmAudio, format: AudioFormat, voice_id: ObjectId) -> VoicerResult<()>;
+    async fn delete_post(&self, voice_note_id: ObjectId, user_id: ObjectId) -> VoicerResult<()>;
+    async fn get_voice_note(&self, v_id: ObjectId) -> VoicerResult<VoiceNote>;
+    // Decoded samples of a note, whichever way its audio is stored
+    async fn get_voice_audio(&self, v_id: ObjectId) -> VoicerResult<PcmAudio>;
+    async fn get_feed_page(&self, user_id: ObjectId, after: Option<FeedCursor>, limit: i64) -> VoicerResult<FeedPage>;
+    // Card details of each note, without its audio. Missing notes are left out.
+    async fn get_note_summaries(&self, ids: Vec<ObjectId>) -> VoicerResult<Vec<FeedItem>>;
+
+    // Follows
+    // Both users' lists change together or not at all. Following yourself is refused.
+    async fn follow(&self, user_id: ObjectId, fuser_id: ObjectId) -> VoicerResult<FollowResult>;
+    async fn unfollow(&self, user_id: ObjectId, fuser_id: ObjectId) -> VoicerResult<UnfollowResult>;
+    async fn remove_follower(&self, user_id: ObjectId, follower_id: ObjectId) -> VoicerResult<UnfollowResult>;
+    async fn get_all_following_profile(&self, user_id: ObjectId) -> VoicerResult<Vec<publicUser>>;
+    async fn get_all_followers_profile(&self, user_id: ObjectId) -> VoicerResult<Vec<publicUser>>;
+
+    // Reactions
+    async fn react_to_quote(&self, v_id: ObjectId, user_id: ObjectId, reaction: ReactionType) -> VoicerResult<()>;
+
+    // Replies
+    async fn create_comment(&self, user_id: ObjectId, voice_id: String, comment_id: ObjectId, recording: PcmAudio, format: AudioFormat) -> VoicerResult<()>;
+    async fn create_conversation(&self, v_id: ObjectId) -> VoicerResult<conversation>;
+
+    fn length_limits(&self) -> LengthLimits;
+
+    // Returns false if the store is unreachable and could not be recovered.
+    async fn health_check(&self) -> bool {
+        true
+    }
+
+    // The note's audio brought to the playback loudness, ready for the audio cache.
+    async fn playback_audio(&self, v_id: ObjectId) -> VoicerResult<PcmAudio> {
+        let loudness = self.get_voice_note(v_id).await?.loudness;
+        backend::normalize_for_playback(self.get_voice_audio(v_id).await?, loudness).await
+    }
+
+    // Feeds the note's audio, at the playback loudness, into `stream` as it arrives.
+    async fn stream_audio(&self, v_id: ObjectId, stream: AudioStream) -> VoicerResult<()> {
+        stream.push(&self.playback_audio(v_id).await?.samples);
+        stream.finish();
+        Ok(())
+    }
+
+    fn sync_status(&self) -> SyncStatus {
+        SyncStatus::default()
+    }
+
+    // Offline changes that were dropped while syncing because they no longer
+    // apply, e.g. a reaction to a quote that has since been deleted. Each one
+    // is returned once.
+    fn take_conflicts(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    // Re-encodes notes saved before audio was compressed. Returns how many were moved.
+    async fn migrate_legacy_audio(&self) -> VoicerResult<u64> {
+        Ok(0)
+    }
+
+    // Fills in the length of notes saved before it was stored. Returns how many were updated.
+    async fn backfill_durations(&self) -> VoicerResult<u64> {
+        Ok(0)
+    }
+}
+
+struct MongoConnection {
+    client: Client,
+    users: Collection<Users>,
+    voice_notes: Collection<VoiceNote>,
+    sessions: Collection<Session>,
+    audio: Arc<GridFsStorage>,
+}
+
+impl MongoConnection {
+    async fn open() -> VoicerResult<Self> {
+        let (users, voice_notes, db, client) = backend::connect_to_mongodb().await?;
+        let sessions = db.collection::<Session>("sessions");
+        let bucket = db.gridfs_bucket(GridFsBucketOptions::builder().bucket_name("voice_audio".to_string()).build());
+        let audio = Arc::new(GridFsStorage::new(bucket));
+        Ok(MongoConnection { client, users, voice_notes, sessions, audio })
+    }
+}
+
+// One pooled client shared by every page. The collection handles are cheap to
+// clone, so callers take a copy and the lock is only held while swapping in a
+// fresh connection.
+//
+// Connecting doesn't wait for the server, so it never holds up startup. Whether
+// MongoDB is actually there is left to the health check, which also creates the indexes
+// once it first gets through.
+pub struct MongoStore {
+    connection: RwLock<MongoConnection>,
+    // The last health check failed, so the next one tries a fresh connection
+    stale: AtomicBool,
+    indexed: AtomicBool,
+    limits: LengthLimits,
+}
+
+impl MongoStore {
+    pub async fn connect(limits: LengthLimits) -> VoicerResult<Self> {
+        Ok(MongoStore {
+            connection: RwLock::new(MongoConnection::open().await?),
+            stale: AtomicBool::new(false),
+            indexed: AtomicBool::new(false),
+            limits,
+        })
+    }
+
+    fn users(&self) -> Collection<Users> {
+        self.connection.read().unwrap().users.clone()
+    }
+
+    fn voice_notes(&self) -> Collection<VoiceNote> {
+        self.connection.read().unwrap().voice_notes.clone()
+    }
+
+    fn sessions(&self) -> Collection<Session> {
+        self.connection.read().unwrap().sessions.clone()
+    }
+
+    fn audio(&self) -> Arc<GridFsStorage> {
+        self.connection.read().unwrap().audio.clone()
+    }
+
+    fn client(&self) -> Client {
+        self.connection.read().unwrap().client.clone()
+    }
+
+    fn collections(&self) -> (Collection<Users>, Collection<VoiceNote>) {
+        let connection = self.connection.read().unwrap();
+        (connection.users.clone(), connection.voice_notes.clone())
+    }
+
+    async fn ping(&self) -> VoicerResult<()> {
+        ping(&self.client()).await
+    }
+
+    async fn ensure_indexes(&self) {
+        if self.indexed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let (users, voice_notes) = self.collections();
+        let results = [
+            ("feed", backend::ensure_feed_index(voice_notes).await),
+            ("username", backend::ensure_username_index(users).await),
+        ];
+        for (name, result) in results {
+            if let Err(err) = result {
+                println!("Could not create the {} index: {}", name, err);
+                // Tried again by the next health check if MongoDB went away meanwhile
+                if err.is_unreachable() {
+                    self.indexed.store(false, Ordering::SeqCst);
+                }
+            }
+        }
+    }
+
+    // Stores a session issued while offline so its token keeps working.
+    pub async fn insert_session(&self, session: Session) -> VoicerResult<()> {
+        backend::insert_session(self.sessions(), session).await
+    }
+
+    pub async fn revoke_session_by_hash(&self, token_hash: &str) -> VoicerResult<()> {
+        backend::revoke_session_by_hash(self.sessions(), token_hash).await
+    }
+
+    // Links an already stored note to its author or parent; safe to repeat
+    pub async fn save_voice_note(&self, user_id: ObjectId, v_id: ObjectId) -> VoicerResult<()> {
+        backend::save_voice_note(self.users(), user_id, v_id).await
+    }
+
+    pub async fn add_reply(&self, parent_id: ObjectId, comment_id: ObjectId) -> VoicerResult<()> {
+        backend::add_reply(self.voice_notes(), parent_id.to_hex(), comment_id).await
+    }
+
+    // Swaps in a fresh connection, once it has answered a ping.
+    pub async fn reconnect(&self) -> VoicerResult<()> {
+        println!("Reconnecting to MongoDB");
+        let connection = MongoConnection::open().await?;
+        ping(&connection.client).await?;
+        *self.connection.write().unwrap() = connection;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl VoicerStore for MongoStore {
+    async fn create_user(&self, username: String, password: String, name: String) -> VoicerResult<ObjectId> {
+        let user_collection = self.users();
+        backend::create_user(user_collection, username, password, name).await
+    }
+
+    async fn get_user_by_username(&self, username: String, password: String) -> VoicerResult<Users> {
+        let user_collection = self.users();
+        backend::get_user_by_username(user_collection, username, password).await
+    }
+
+    async fn find_users_by_names(&self, username: &str, user_id: ObjectId) -> VoicerResult<publicUser> {
+        let user_collection = self.users();
+        backend::find_users_by_names(user_collection, username, user_id).await
+    }
+
+    async fn update_user_name_by_username(&self, username: &str, new_name: &str) -> VoicerResult<()> {
+        let user_collection = self.users();
+        backend::update_user_name_by_username(user_collection, username, new_name).await
+    }
+
+    async fn update_password_by_username(&self, username: &str, new_password: &str) -> VoicerResult<()> {
+        let user_collection = self.users();
+        backend::update_password_by_username(user_collection, username, new_password).await
+    }
+
+    async fn update_description_by_username(&self, username: &str, new_desc: &str) -> VoicerResult<()> {
+        let user_collection = self.users();
+        backend::update_description_by_username(user_collection, username, new_desc).await
+    }
+
+    async fn update_playback_speed(&self, user_id: ObjectId, speed: f32) -> VoicerResult<()> {
+        let user_collection = self.users();
+        backend::update_playback_speed(user_collection, user_id, speed).await
+    }
+
+    async fn create_session(&self, user_id: ObjectId) -> VoicerResult<String> {
+        backend::create_session(self.sessions(), user_id).await
+    }
+
+    async fn get_user_by_session(&self, token: &str) -> VoicerResult<Users> {
+        backend::get_user_by_session(self.sessions(), self.users(), token).await
+    }
+
+    async fn revoke_session(&self, token: &str) -> VoicerResult<()> {
+        backend::revoke_session(self.sessions(), token).await
+    }
+
+    async fn revoke_all_sessions(&self, user_id: ObjectId) -> VoicerResult<u64> {
+        backend::revoke_all_sessions(self.sessions(), user_id).await
+    }
+
+    async fn create_post(&self, user_id: ObjectId, recording: PcmAudio, format: AudioFormat, voice_id: ObjectId) -> VoicerResult<()> {
+        let (user_collection, voice_collection) = self.collections();
+        backend::create_post(voice_collection, user_collection, self.audio().as_ref(), &self.limits, user_id, recording, format, voice_id).await
+    }
+
+    async fn delete_post(&self, voice_note_id: ObjectId, user_id: ObjectId) -> VoicerResult<()> {
+        let (user_collection, voice_collection) = self.collections();
+        backend::delete_post(voice_collection, user_collection, self.audio().as_ref(), voice_note_id, user_id).await
+    }
+
+    async fn get_voice_note(&self, v_id: ObjectId) -> VoicerResult<VoiceNote> {
+        let voice_collection = self.voice_notes();
+        backend::get_voice_note(voice_collection, v_id).await
+    }
+
+    async fn get_voice_audio(&self, v_id: ObjectId) -> VoicerResult<PcmAudio> {
+        let voice_collection = self.voice_notes();
+        backend::get_voice_audio(voice_collection, self.audio().as_ref(), v_id).await
+    }
+
+    async fn migrate_legacy_audio(&self) -> VoicerResult<u64> {
+        let voice_collection = self.voice_notes();
+        backend::migrate_legacy_audio(voice_collection, self.audio().as_ref()).await
+    }
+
+    async fn backfill_durations(&self) -> VoicerResult<u64> {
+        let voice_collection = self.voice_notes();
+        backend::backfill_durations(voice_collection, self.audio().as_ref()).await
+    }
+
+    async fn get_feed_page(&self, user_id: ObjectId, after: Option<FeedCursor>, limit: i64) -> VoicerResult<FeedPage> {
+        let (user_collection, voice_collection) = self.collections();
+        backend::get_feed_page(user_collection, voice_collection, user_id, after, limit).await
+    }
+
+    async fn get_note_summaries(&self, ids: Vec<ObjectId>) -> VoicerResult<Vec<FeedItem>> {
+        backend::get_note_summaries(self.voice_notes(), ids).await
+    }
+
+    async fn follow(&self, user_id: ObjectId, fuser_id: ObjectId) -> VoicerResult<FollowResult> {
+        let user_collection = self.users();
+        backend::follow(&self.client(), user_collection, user_id, fuser_id).await
+    }
+
+    async fn unfollow(&self, user_id: ObjectId, fuser_id: ObjectId) -> VoicerResult<UnfollowResult> {
+        let user_collection = self.users();
+        backend::unfollow(&self.client(), user_collection, user_id, fuser_id).await
+    }
+
+    async fn remove_follower(&self, user_id: ObjectId, follower_id: ObjectId) -> VoicerResult<UnfollowResult> {
+        let user_collection = self.users();
+        backend::remove_follower(&self.client(), user_collection, user_id, follower_id).await
+    }
+
+    async fn get_all_following_profile(&self, user_id: ObjectId) -> VoicerResult<Vec<publicUser>> {
+        let user_collection = self.users();
+        backend::get_all_following_profile(user_collection, user_id).await
+    }
+
+    async fn get_all_followers_profile(&self, user_id: ObjectId) -> VoicerResult<Vec<publicUser>> {
+        let user_collection = self.users();
+        backend::get_all_followers_profile(user_collection, user_id).await
+    }
+
+    async fn react_to_quote(&self, v_id: ObjectId, user_id: ObjectId, reaction: ReactionType) -> VoicerResult<()> {
+        let voice_collection = self.voice_notes();
+        backend::react_to_quote(voice_collection, v_id, user_id, reaction).await
+    }
+
+    async fn create_comment(&self, user_id: ObjectId, voice_id: String, comment_id: ObjectId, recording: PcmAudio, format: AudioFormat) -> VoicerResult<()> {
+        let (user_collection, voice_collection) = self.collections();
+        backend::create_comment(voice_collection, user_collection, self.audio().as_ref(), &self.limits, user_id, voice_id, comment_id, recording, format).await
+    }
+
+    async fn create_conversation(&self, v_id: ObjectId) -> VoicerResult<conversation> {
+        let voice_collection = self.voice_notes();
+        backend::create_conversation(voice_collection, v_id).await
+    }
+
+    fn length_limits(&self) -> LengthLimits {
+        self.limits
+    }
+
+    // One ping per check, so a dead server costs a single server selection timeout.
+    async fn health_check(&self) -> bool {
+        let result = if self.stale.load(Ordering::SeqCst) { self.reconnect().await } else { self.ping().await };
+        if let Err(err) = result {
+            println!("MongoDB health check failed: {}", err);
+            self.stale.store(true, Ordering::SeqCst);
+            return false;
+        }
+        self.stale.store(false, Ordering::SeqCst);
+        self.ensure_indexes().await;
+        true
+    }
+
+    async fn playback_audio(&self, v_id: ObjectId) -> VoicerResult<PcmAudio> {
+        let voice_collection = self.voice_notes();
+        backend::playback_audio(voice_collection, self.audio().as_ref(), v_id).await
+    }
+
+    async fn stream_audio(&self, v_id: ObjectId, stream: AudioStream) -> VoicerResult<()> {
+        let voice_collection = self.voice_notes();
+        backend::stream_voice_audio(voice_collection, self.audio().as_ref(), v_id, stream).await
+    }
+}
+
+async fn ping(client: &Client) -> VoicerResult<()> {
+    client.database("admin").run_command(doc! {"ping": 1}, None).await?;
+    Ok(())
+}
+
+// Picks the store from `VOICER_STORE` (`memory` or `mongo`, default `mongo`).
+// MongoDB is reached through a local replica so the app keeps working offline.
+pub async fn open_store() -> VoicerResult<Arc<dyn VoicerStore>> {
+    dotenv::dotenv().ok();
+    let limits = LengthLimits::from_env()?;
+    match std::env::var("VOICER_STORE").as_deref() {
+        Ok("memory") => {
+            println!("Using in-memory store");
+            Ok(Arc::new(crate::memory_store::MemoryStore::new(limits)))
+        }
+        _ => Ok(Arc::new(crate::replica_store::ReplicaStore::open(limits).await?)),
+    }
+}
@@ -0,0 +1,617 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use futures_util::future;
+use mongodb::bson::oid::ObjectId;
+use tokio::runtime::Handle;
+use tokio::sync::Semaphore;
+use tokio::task::AbortHandle;
+
+use crate::audio_cache::AudioCache;
+use crate::audio_codec::{AudioFormat, PcmAudio};
+use crate::audio_stream::AudioStream;
+use crate::backend::{conversation, publicUser, FeedCursor, FeedItem, FeedPage, FollowResult, ReactionType, UnfollowResult, Users, FEED_PAGE_SIZE};
+use crate::context::AppContext;
+use crate::error::{VoicerError, VoicerResult};
+use crate::export::{self, ExportFormat};
+use crate::import;
+use crate::store::VoicerStore;
+
+// Most notes downloaded at once, across every running task.
+const MAX_PARALLEL_DOWNLOADS: usize = 4;
+
+// Work the pages hand off to the background so `update()` never blocks.
+// Tasks acting as the logged-in user are authorized by the executor's session token.
+pub enum Task {
+    SignUp { username: String, password: String, name: String },
+    Login { username: String, password: String },
+    ResumeSession { token: String },
+    Logout,
+    LogoutEverywhere,
+    // `after: None` loads the first page
+    LoadFeed { after: Option<FeedCursor> },
+    LoadProfile,
+    ReactToQuote { v_id: ObjectId, reaction: ReactionType },
+    LoadConversation { v_id: ObjectId },
+    ImportAudio { path: PathBuf },
+    CreatePost { recording: PcmAudio, format: AudioFormat },
+    CreateComment { v_id: ObjectId, recording: PcmAudio, format: AudioFormat },
+    DeletePost { v_id: ObjectId },
+    SearchUser { username: String },
+    Follow { fuser_id: ObjectId },
+    Unfollow { fuser_id: ObjectId },
+    RemoveFollower { follower_id: ObjectId },
+    LoadFollowers,
+    LoadFollowing,
+    UpdateBio { description: String },
+    SetPlaybackSpeed { speed: f32 },
+    // Downloads notes' audio into the cache, skipping any already there
+    FetchAudio { notes: Vec<ObjectId> },
+    // Feeds a note that is already playing, then caches it
+    StreamAudio { v_id: ObjectId, stream: AudioStream },
+    ExportNote { v_id: ObjectId, path: PathBuf, format: ExportFormat },
+    // The post followed by its replies
+    ExportThread { v_id: ObjectId, path: PathBuf, format: ExportFormat },
+}
+
+impl Task {
+    pub fn kind(&self) -> TaskKind {
+        match self {
+            Task::SignUp { .. } => TaskKind::SignUp,
+            Task::Login { .. } => TaskKind::Login,
+            Task::ResumeSession { .. } => TaskKind::ResumeSession,
+            Task::Logout => TaskKind::Logout,
+            Task::LogoutEverywhere => TaskKind::LogoutEverywhere,
+            Task::LoadFeed { .. } => TaskKind::LoadFeed,
+            Task::LoadProfile => TaskKind::LoadProfile,
+            Task::ReactToQuote { .. } => TaskKind::ReactToQuote,
+            Task::LoadConversation { .. } => TaskKind::LoadConversation,
+            Task::ImportAudio { .. } => TaskKind::ImportAudio,
+            Task::CreatePost { .. } => TaskKind::CreatePost,
+            Task::CreateComment { .. } => TaskKind::CreateComment,
+            Task::DeletePost { .. } => TaskKind::DeletePost,
+            Task::SearchUser { .. } => TaskKind::SearchUser,
+            Task::Follow { .. } => TaskKind::Follow,
+            Task::Unfollow { .. } => TaskKind::Unfollow,
+            Task::RemoveFollower { .. } => TaskKind::RemoveFollower,
+            Task::LoadFollowers => TaskKind::LoadFollowers,
+            Task::LoadFollowing => TaskKind::LoadFollowing,
+            Task::UpdateBio { .. } => TaskKind::UpdateBio,
+            Task::SetPlaybackSpeed { .. } => TaskKind::SetPlaybackSpeed,
+            Task::FetchAudio { .. } => TaskKind::FetchAudio,
+            Task::StreamAudio { .. } => TaskKind::StreamAudio,
+            Task::ExportNote { .. } | Task::ExportThread { .. } => TaskKind::Export,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskKind {
+    SignUp,
+    Login,
+    ResumeSession,
+    Logout,
+    LogoutEverywhere,
+    LoadFeed,
+    LoadProfile,
+    ReactToQuote,
+    LoadConversation,
+    ImportAudio,
+    CreatePost,
+    CreateComment,
+    DeletePost,
+    SearchUser,
+    Follow,
+    Unfollow,
+    RemoveFollower,
+    LoadFollowers,
+    LoadFollowing,
+    UpdateBio,
+    SetPlaybackSpeed,
+    FetchAudio,
+    StreamAudio,
+    Export,
+}
+
+impl TaskKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            TaskKind::SignUp => "Creating account",
+            TaskKind::Login => "Logging in",
+            TaskKind::ResumeSession => "Restoring session",
+            TaskKind::Logout => "Logging out",
+            TaskKind::LogoutEverywhere => "Logging out everywhere",
+            TaskKind::LoadFeed => "Loading feed",
+            TaskKind::LoadProfile => "Loading profile",
+            TaskKind::ReactToQuote => "Reacting",
+            TaskKind::LoadConversation => "Loading conversation",
+            TaskKind::ImportAudio => "Importing audio",
+            TaskKind::CreatePost => "Publishing quote",
+            TaskKind::CreateComment => "Publishing reply",
+            TaskKind::DeletePost => "Deleting quote",
+            TaskKind::SearchUser => "Searching",
+            TaskKind::Follow => "Following",
+            TaskKind::Unfollow => "Unfollowing",
+            TaskKind::RemoveFollower => "Removing follower",
+            TaskKind::LoadFollowers => "Loading followers",
+            TaskKind::LoadFollowing => "Loading following",
+            TaskKind::UpdateBio => "Updating bio",
+            TaskKind::SetPlaybackSpeed => "Saving playback speed",
+            TaskKind::FetchAudio => "Downloading audio",
+            TaskKind::StreamAudio => "Streaming audio",
+            TaskKind::Export => "Exporting audio",
+        }
+    }
+}
+
+pub enum TaskResult {
+    SignedUp(ObjectId),
+    // The user and the session token issued (or resumed) for them
+    LoggedIn(Users, String),
+    // Logout is applied locally straight away, so there is nothing left to do
+    SessionRevoked,
+    LoggedOut,
+    // `append` is false for a first page, which replaces whatever was loaded
+    FeedLoaded { page: FeedPage, append: bool },
+    ProfileLoaded(Users, Vec<FeedItem>),
+    Reacted,
+    ConversationLoaded(conversation),
+    AudioImported(PcmAudio, AudioFormat),
+    PostCreated,
+    PostDeleted(ObjectId),
+    UserFound(publicUser),
+    Followed(FollowResult),
+    FollowersLoaded(Vec<publicUser>),
+    FollowingLoaded(Vec<publicUser>),
+    BioUpdated,
+    PlaybackSpeedSaved,
+    // Notes whose audio is now cached, and those that could not be downloaded
+    AudioFetched { fetched: Vec<ObjectId>, failed: Vec<ObjectId> },
+    Exported(PathBuf),
+    Failed(TaskKind, VoicerError),
+}
+
+pub type TaskId = u64;
+
+// Shared between a running task and the UI; `total == 0` means indeterminate.
+#[derive(Default)]
+pub struct Progress {
+    pub done: usize,
+    pub total: usize,
+}
+
+pub struct RunningTask {
+    pub id: TaskId,
+    pub kind: TaskKind,
+    pub progress: Arc<Mutex<Progress>>,
+    abort: AbortHandle,
+}
+
+impl RunningTask {
+    pub fn fraction(&self) -> Option<f32> {
+        let progress = self.progress.lock().unwrap();
+        if progress.total == 0 {
+            None
+        } else {
+            Some(progress.done as f32 / progress.total as f32)
+        }
+    }
+}
+
+pub struct TaskExecutor {
+    runtime: Handle,
+    store: Arc<dyn VoicerStore>,
+    sender: Sender<(TaskId, TaskResult)>,
+    receiver: Receiver<(TaskId, TaskResult)>,
+    running: HashMap<TaskId, RunningTask>,
+    next_id: TaskId,
+    session: Option<String>,
+    // The logged-in user's audio cache, where downloaded notes are kept
+    cache: Option<Arc<AudioCache>>,
+    downloads: Arc<Semaphore>,
+}
+
+impl TaskExecutor {
+    pub fn new(context: &AppContext) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        TaskExecutor {
+            runtime: context.handle(),
+            store: context.store.clone(),
+            sender,
+            receiver,
+            running: HashMap::new(),
+            next_id: 0,
+            session: None,
+            cache: None,
+            downloads: Arc::new(Semaphore::new(MAX_PARALLEL_DOWNLOADS)),
+        }
+    }
+
+    // Token sent with every task spawned from now on.
+    pub fn set_session(&mut self, token: Option<String>) {
+        self.session = token;
+    }
+
+    pub fn session(&self) -> Option<&str> {
+        self.session.as_deref()
+    }
+
+    pub fn set_cache(&mut self, cache: Option<Arc<AudioCache>>) {
+        self.cache = cache;
+    }
+
+    pub fn spawn(&mut self, task: Task) -> TaskId {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let kind = task.kind();
+        let progress = Arc::new(Mutex::new(Progress::default()));
+        let store = self.store.clone();
+        let sender = self.sender.clone();
+        let session = self.session.clone();
+        let cache = self.cache.clone();
+        let downloads = self.downloads.clone();
+        let task_progress = progress.clone();
+        // A cancelled stream must still end, or the player buffers forever
+        let finish = match &task {
+            Task::StreamAudio { stream, .. } => Some(FinishStream(stream.clone())),
+            _ => None,
+        };
+        let handle = self.runtime.spawn(async move {
+            let result = match run(store, session, cache, downloads, task, task_progress).await {
+                Ok(result) => result,
+                Err(err) => TaskResult::Failed(kind, err),
+            };
+            drop(finish);
+            // The receiver only goes away when the window closes.
+            let _ = sender.send((id, result));
+        });
+
+        self.running.insert(id, RunningTask { id, kind, progress, abort: handle.abort_handle() });
+        id
+    }
+
+    // Results of tasks that finished since the last call. Call once per frame.
+    pub fn poll(&mut self) -> Vec<TaskResult> {
+        let mut results = Vec::new();
+        while let Ok((id, result)) = self.receiver.try_recv() {
+            // Drop results of tasks that were cancelled after they had already sent.
+            if self.running.remove(&id).is_some() {
+                results.push(result);
+            }
+        }
+        results
+    }
+
+    pub fn cancel(&mut self, id: TaskId) {
+        if let Some(task) = self.running.remove(&id) {
+            task.abort.abort();
+        }
+    }
+
+    pub fn cancel_all(&mut self) {
+        for (_, task) in self.running.drain() {
+            task.abort.abort();
+        }
+    }
+
+    pub fn is_running(&self, kind: TaskKind) -> bool {
+        self.running.values().any(|task| task.kind == kind)
+    }
+
+    pub fn has_running(&self) -> bool {
+        !self.running.is_empty()
+    }
+
+    pub fn running(&self) -> Vec<&RunningTask> {
+        let mut tasks: Vec<&RunningTask> = self.running.values().collect();
+        tasks.sort_by_key(|task| task.id);
+        tasks
+    }
+}
+
+async fn run(store: Arc<dyn VoicerStore>, session: Option<String>, cache: Option<Arc<AudioCache>>, downloads: Arc<Semaphore>, task: Task, progress: Arc<Mutex<Progress>>) -> VoicerResult<TaskResult> {
+    let result = match task {
+        Task::SignUp { username, password, name } => {
+            TaskResult::SignedUp(store.create_user(username, password, name).await?)
+        }
+        Task::Login { username, password } => {
+            let user = store.get_user_by_username(username, password).await?;
+            let token = store.create_session(user._id).await?;
+            TaskResult::LoggedIn(user, token)
+        }
+        Task::ResumeSession { token } => {
+            TaskResult::LoggedIn(store.get_user_by_session(&token).await?, token)
+        }
+        Task::Logout => {
+            if let Some(token) = &session {
+                store.revoke_session(token).await?;
+            }
+            TaskResult::SessionRevoked
+        }
+        Task::LogoutEverywhere => {
+            let user = session_user(&store, &session).await?;
+            let revoked = store.revoke_all_sessions(user._id).await?;
+            println!("Revoked {} sessions", revoked);
+            TaskResult::LoggedOut
+        }
+        Task::LoadFeed { after } => {
+            let user = session_user(&store, &session).await?;
+            let page = store.get_feed_page(user._id, after, FEED_PAGE_SIZE).await?;
+            TaskResult::FeedLoaded { page, append: after.is_some() }
+        }
+        Task::LoadProfile => {
+            let user = session_user(&store, &session).await?;
+            let notes = store.get_note_summaries(user.voice_notes.clone()).await?;
+            TaskResult::ProfileLoaded(user, notes)
+        }
+        Task::ReactToQuote { v_id, reaction } => {
+            let user = session_user(&store, &session).await?;
+            store.react_to_quote(v_id, user._id, reaction).await?;
+            TaskResult::Reacted
+        }
+        Task::LoadConversation { v_id } => {
+            TaskResult::ConversationLoaded(store.create_conversation(v_id).await?)
+        }
+        Task::ImportAudio { path } => {
+            let (audio, format) = import::import_audio_file(path).await?;
+            TaskResult::AudioImported(audio, format)
+        }
+        Task::CreatePost { recording, format } => {
+            let user = session_user(&store, &session).await?;
+            store.create_post(user._id, recording, format, ObjectId::new()).await?;
+            TaskResult::PostCreated
+        }
+        Task::CreateComment { v_id, recording, format } => {
+            let user = session_user(&store, &session).await?;
+            store.create_comment(user._id, v_id.to_hex(), ObjectId::new(), recording, format).await?;
+            TaskResult::ConversationLoaded(store.create_conversation(v_id).await?)
+        }
+        Task::DeletePost { v_id } => {
+            let user = session_user(&store, &session).await?;
+            store.delete_post(v_id, user._id).await?;
+            TaskResult::PostDeleted(v_id)
+        }
+        Task::SearchUser { username } => {
+            let user = session_user(&store, &session).await?;
+            TaskResult::UserFound(store.find_users_by_names(&username, user._id).await?)
+        }
+        Task::Follow { fuser_id } => {
+            let user = session_user(&store, &session).await?;
+            TaskResult::Followed(store.follow(user._id, fuser_id).await?)
+        }
+        Task::Unfollow { fuser_id } => {
+            let user = session_user(&store, &session).await?;
+            if store.unfollow(user._id, fuser_id).await? == UnfollowResult::NotFollowing {
+                println!("Was not following {}", fuser_id);
+            }
+            TaskResult::FollowingLoaded(store.get_all_following_profile(user._id).await?)
+        }
+        Task::RemoveFollower { follower_id } => {
+            let user = session_user(&store, &session).await?;
+            if store.remove_follower(user._id, follower_id).await? == UnfollowResult::NotFollowing {
+                println!("{} was not a follower", follower_id);
+            }
+            TaskResult::FollowersLoaded(store.get_all_followers_profile(user._id).await?)
+        }
+        Task::LoadFollowers => {
+            let user = session_user(&store, &session).await?;
+            TaskResult::FollowersLoaded(store.get_all_followers_profile(user._id).await?)
+        }
+        Task::LoadFollowing => {
+            let user = session_user(&store, &session).await?;
+            TaskResult::FollowingLoaded(store.get_all_following_profile(user._id).await?)
+        }
+        Task::UpdateBio { description } => {
+            let user = session_user(&store, &session).await?;
+            store.update_description_by_username(&user.username, &description).await?;
+            TaskResult::BioUpdated
+        }
+        Task::SetPlaybackSpeed { speed } => {
+            let user = session_user(&store, &session).await?;
+            store.update_playback_speed(user._id, speed).await?;
+            TaskResult::PlaybackSpeedSaved
+        }
+        Task::FetchAudio { notes } => {
+            let cache = cache.ok_or(VoicerError::InvalidSession)?;
+            progress.lock().unwrap().total = notes.len();
+            let downloads = notes.iter().map(|&v_id| {
+                let (store, cache, downloads, progress) = (store.clone(), cache.clone(), downloads.clone(), progress.clone());
+                async move {
+                    let result = match downloads.acquire().await {
+                        Ok(_permit) => cache_note(&store, &cache, v_id).await,
+                        Err(_) => Err(VoicerError::Audio("Downloads were shut down".to_string())),
+                    };
+                    progress.lock().unwrap().done += 1;
+                    (v_id, result)
+                }
+            });
+            let mut fetched = Vec::new();
+            let mut failed = Vec::new();
+            for (v_id, result) in future::join_all(downloads).await {
+                match result {
+                    Ok(()) => fetched.push(v_id),
+                    Err(err) => {
+                        println!("Could not download note {}: {}", v_id, err);
+                        failed.push(v_id);
+                    }
+                }
+            }
+            TaskResult::AudioFetched { fetched, failed }
+        }
+        Task::StreamAudio { v_id, stream } => {
+            let streamed = store.stream_audio(v_id, stream.clone()).await;
+            // Whatever happened, the player stops waiting for more
+            stream.finish();
+            streamed?;
+            if let Some(cache) = cache {
+                let audio = stream.to_audio();
+                tokio::task::spawn_blocking(move || cache.insert(v_id, &audio))
+                    .await
+                    .map_err(|err| VoicerError::Audio(err.to_string()))??;
+            }
+            TaskResult::AudioFetched { fetched: vec![v_id], failed: Vec::new() }
+        }
+        Task::ExportNote { v_id, path, format } => {
+            export::export_note(store.as_ref(), v_id, path.clone(), format).await?;
+            TaskResult::Exported(path)
+        }
+        Task::ExportThread { v_id, path, format } => {
+            export::export_thread(store.as_ref(), v_id, path.clone(), format).await?;
+            TaskResult::Exported(path)
+        }
+    };
+    Ok(result)
+}
+
+// Finishes a stream when dropped. Aborting a task drops everything it holds,
+// whether or not it had started running.
+struct FinishStream(AudioStream);
+
+impl Drop for FinishStream {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
+}
+
+// Downloads a note's playback audio into the cache unless it is already there.
+async fn cache_note(store: &Arc<dyn VoicerStore>, cache: &Arc<AudioCache>, v_id: ObjectId) -> VoicerResult<()> {
+    if cache.contains(v_id) {
+        return Ok(());
+    }
+    let audio = store.playback_audio(v_id).await?;
+    let cache = cache.clone();
+    tokio::task::spawn_blocking(move || cache.insert(v_id, &audio))
+        .await
+        .map_err(|err| VoicerError::Audio(err.to_string()))??;
+    Ok(())
+}
+
+// The user behind the executor's session token.
+async fn session_user(store: &Arc<dyn VoicerStore>, session: &Option<String>) -> VoicerResult<Users> {
+    match session {
+        Some(token) => store.get_user_by_session(token).await,
+        None => Err(VoicerError::InvalidSession),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_codec::{SampleFormat, CANONICAL_CHANNELS, CANONICAL_SAMPLE_RATE};
+    use crate::limits::LengthLimits;
+    use crate::memory_store::MemoryStore;
+    use std::time::{Duration, Instant};
+    use tokio::runtime::Runtime;
+
+    fn executor(runtime: &Runtime, store: Arc<dyn VoicerStore>) -> TaskExecutor {
+        let (sender, receiver) = mpsc::channel();
+        TaskExecutor {
+            runtime: runtime.handle().clone(),
+            store,
+            sender,
+            receiver,
+            running: HashMap::new(),
+            next_id: 0,
+            session: None,
+            cache: None,
+            downloads: Arc::new(Semaphore::new(MAX_PARALLEL_DOWNLOADS)),
+        }
+    }
+
+    // Polls like the UI does until a result arrives.
+    fn wait_for_result(tasks: &mut TaskExecutor) -> TaskResult {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            if let Some(result) = tasks.poll().pop() {
+                return result;
+            }
+            assert!(Instant::now() < deadline, "no result");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn results_arrive_through_poll() {
+        let runtime = Runtime::new().unwrap();
+        let mut tasks = executor(&runtime, Arc::new(MemoryStore::new(LengthLimits::default())));
+
+        tasks.spawn(Task::SignUp { username: "ada".to_string(), password: "secret".to_string(), name: "Ada".to_string() });
+        // Stays running until the UI has picked up its result
+        assert!(tasks.is_running(TaskKind::SignUp));
+        let user_id = match wait_for_result(&mut tasks) {
+            TaskResult::SignedUp(user_id) => user_id,
+            _ => panic!("expected SignedUp"),
+        };
+        assert!(!tasks.has_running());
+
+        tasks.spawn(Task::Login { username: "ada".to_string(), password: "secret".to_string() });
+        match wait_for_result(&mut tasks) {
+            TaskResult::LoggedIn(user, token) => {
+                assert_eq!(user._id, user_id);
+                assert!(!token.is_empty());
+            }
+            _ => panic!("expected LoggedIn"),
+        }
+    }
+
+    #[test]
+    fn failures_report_their_task() {
+        let runtime = Runtime::new().unwrap();
+        let mut tasks = executor(&runtime, Arc::new(MemoryStore::new(LengthLimits::default())));
+
+        tasks.spawn(Task::LoadProfile);
+        assert!(matches!(wait_for_result(&mut tasks), TaskResult::Failed(TaskKind::LoadProfile, VoicerError::InvalidSession)));
+    }
+
+    #[test]
+    fn cancelling_a_stream_finishes_it() {
+        // A current-thread runtime only runs tasks inside block_on, so the
+        // task is cancelled before it ever starts
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let mut tasks = executor(&runtime, Arc::new(MemoryStore::new(LengthLimits::default())));
+
+        let stream = AudioStream::for_note();
+        let id = tasks.spawn(Task::StreamAudio { v_id: ObjectId::new(), stream: stream.clone() });
+        assert!(!stream.is_finished());
+        tasks.cancel(id);
+        runtime.block_on(tokio::task::yield_now());
+        assert!(stream.is_finished());
+        assert!(tasks.poll().is_empty());
+    }
+
+    #[test]
+    fn fetched_notes_land_in_the_cache() {
+        let runtime = Runtime::new().unwrap();
+        let store = Arc::new(MemoryStore::new(LengthLimits::default()));
+        let (user_id, v_id) = runtime.block_on(async {
+            let user_id = store.create_user("ada".to_string(), "secret".to_string(), "Ada".to_string()).await.unwrap();
+            let samples = (0..4410).map(|i| ((i as f32 * 0.05).sin() * 8000.0) as i16).collect();
+            let audio = PcmAudio { samples, channels: CANONICAL_CHANNELS, sample_rate: CANONICAL_SAMPLE_RATE };
+            let format = AudioFormat { channels: CANONICAL_CHANNELS, sample_rate: CANONICAL_SAMPLE_RATE, bits_per_sample: 16, sample_format: SampleFormat::Int };
+            let v_id = ObjectId::new();
+            store.create_post(user_id, audio, format, v_id).await.unwrap();
+            (user_id, v_id)
+        });
+        let root = std::env::temp_dir().join(format!("voicer-cache-{}", ObjectId::new().to_hex()));
+        let cache = Arc::new(AudioCache::open_in(&root, user_id).unwrap());
+        let mut tasks = executor(&runtime, store);
+        tasks.set_cache(Some(cache.clone()));
+
+        let missing = ObjectId::new();
+        tasks.spawn(Task::FetchAudio { notes: vec![v_id, missing] });
+        match wait_for_result(&mut tasks) {
+            TaskResult::AudioFetched { fetched, failed } => {
+                assert_eq!(fetched, vec![v_id]);
+                assert_eq!(failed, vec![missing]);
+            }
+            _ => panic!("expected AudioFetched"),
+        }
+        assert!(cache.contains(v_id));
+
+        drop((tasks, cache));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::VecDeque;
+
+use crate::audio_stream::{AudioStream, SampleRead};
+
+pub const MIN_SPEED: f32 = 0.5;
+pub const MAX_SPEED: f32 = 3.0;
+
+// WSOLA analysis window and how far either side of the nominal position to
+// look for the best-matching segment.
+const WINDOW_SECS: f64 = 0.04;
+const SEARCH_SECS: f64 = 0.005;
+// Only every Nth frame is compared when searching, which is plenty for speech
+const CORRELATION_STEP: usize = 8;
+
+// Plays interleaved samples faster or slower without changing their pitch,
+// using waveform-similarity overlap-add (WSOLA). Reads from a shared stream so
+// it can start anywhere, even before the rest of the clip has arrived.
+pub struct TimeStretch {
+    stream: AudioStream,
+    channels: usize,
+    // The frames the current step works on, copied out of the stream
+    region: Vec<i16>,
+    region_start: usize,
+    speed: f64,
+    window: Vec<f32>,
+    // Output hop in frames; the analysis hop is this times the speed
+    hop: usize,
+    search: usize,
+    // Where the next segment should nominally be taken from
+    position: f64,
+    // Start of the previous segment, None before the first one
+    previous: Option<usize>,
+    // Second half of the previous windowed segment, waiting to be overlapped
+    tail: Vec<f32>,
+    output: VecDeque<i16>,
+    done: bool,
+}
+
+impl TimeStretch {
+    pub fn new(stream: AudioStream, start_frame: usize, speed: f32) -> Self {
+        let channels = stream.channels() as usize;
+        let sample_rate = stream.sample_rate();
+        let hop = ((WINDOW_SECS * sample_rate as f64) as usize / 2).max(1);
+        let window = (0..hop * 2)
+            .map(|i| 0.5 - 0.5 * (std::f32::consts::PI * i as f32 / hop as f32).cos())
+            .collect();
+        TimeStretch {
+            stream,
+            channels,
+            region: Vec::new(),
+            region_start: 0,
+            speed: speed.clamp(MIN_SPEED, MAX_SPEED) as f64,
+            window,
+            hop,
+            search: (SEARCH_SECS * sample_rate as f64) as usize,
+            position: start_frame as f64,
+            previous: None,
+            tail: vec![0.0; hop * channels],
+            output: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    fn sample(&self, frame: usize, channel: usize) -> f32 {
+        match frame.checked_sub(self.region_start) {
+            Some(offset) => self.region.get(offset * self.channels + channel).map_or(0.0, |s| *s as f32),
+            None => 0.0,
+        }
+    }
+
+    fn mono(&self, frame: usize) -> f32 {
+        (0..self.channels).map(|c| self.sample(frame, c)).sum()
+    }
+
+    // Picks the segment near `nominal` that best continues the previous one.
+    fn best_start(&self, nominal: usize) -> usize {
+        let previous = match self.previous {
+            Some(previous) => previous,
+            None => return nominal,
+        };
+        // What would naturally have followed the previous segment
+        let target = previous + self.hop;
+        let lowest = nominal.saturating_sub(self.search);
+        let mut best = (f32::MIN, nominal);
+        for candidate in lowest..=nominal + self.search {
+            let mut score = 0.0;
+            for i in (0..self.hop).step_by(CORRELATION_STEP) {
+                score += self.mono(candidate + i) * self.mono(target + i);
+            }
+            if score > best.0 {
+                best = (score, candidate);
+            }
+        }
+        best.1
+    }
+
+    // Produces the next `hop` frames of output. Returns false while the audio
+    // it needs is still arriving.
+    fn step(&mut self) -> bool {
+        let nominal = self.position as usize;
+        if self.stream.total_frames().map_or(false, |frames| nominal >= frames) {
+            // Let the last segment fade out, then stop
+            self.output.extend(self.tail.drain(..).map(|s| s as i16));
+            self.done = true;
+            return true;
+        }
+        // Everything the search, the previous segment's continuation and the
+        // new segment can touch
+        let lowest = match self.previous {
+            Some(previous) => (previous + self.hop).min(nominal.saturating_sub(self.search)),
+            None => nominal,
+        };
+        self.region = match self.stream.frames(lowest, nominal + self.search + self.hop * 2) {
+            Some(region) => region,
+            None => return false,
+        };
+        self.region_start = lowest;
+
+        let start = self.best_start(nominal);
+        let channels = self.channels;
+        for i in 0..self.hop {
+            for c in 0..channels {
+                let mixed = self.tail[i * channels + c] + self.sample(start + i, c) * self.window[i];
+                self.output.push_back(mixed.clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+            }
+        }
+        for i in 0..self.hop {
+            for c in 0..channels {
+                self.tail[i * channels + c] = self.sample(start + self.hop + i, c) * self.window[self.hop + i];
+            }
+        }
+        self.previous = Some(start);
+        self.position += self.hop as f64 * self.speed;
+        true
+    }
+}
+
+impl TimeStretch {
+    // The next output sample; Pending while the audio it needs is still arriving.
+    pub fn read(&mut self) -> SampleRead {
+        while self.output.is_empty() && !self.done {
+            if !self.step() {
+                return SampleRead::Pending;
+            }
+        }
+        match self.output.pop_front() {
+            Some(sample) => SampleRead::Sample(sample),
+            None => SampleRead::End,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_codec::PcmAudio;
+
+    const RATE: u32 = 8000;
+
+    // `secs` of a 200 Hz tone
+    fn tone(secs: f32) -> AudioStream {
+        let samples = (0..(secs * RATE as f32) as usize)
+            .map(|i| ((i as f32 * 200.0 * std::f32::consts::TAU / RATE as f32).sin() * 10000.0) as i16)
+            .collect();
+        AudioStream::from_audio(PcmAudio { samples, channels: 1, sample_rate: RATE })
+    }
+
+    fn read_all(stretch: &mut TimeStretch) -> Vec<i16> {
+        let mut out = Vec::new();
+        while let SampleRead::Sample(sample) = stretch.read() {
+            out.push(sample);
+        }
+        out
+    }
+
+    // Upward zero crossings per second, i.e. the pitch
+    fn frequency(samples: &[i16]) -> f32 {
+        let crossings = samples.windows(2).filter(|pair| pair[0] < 0 && pair[1] >= 0).count();
+        crossings as f32 * RATE as f32 / samples.len() as f32
+    }
+
+    #[test]
+    fn slowing_down_lengthens_without_lowering_the_pitch() {
+        let out = read_all(&mut TimeStretch::new(tone(1.0), 0, 0.5));
+        assert!((15000..=17000).contains(&out.len()), "{} samples", out.len());
+        let pitch = frequency(&out[1000..out.len() - 1000]);
+        assert!((190.0..=210.0).contains(&pitch), "{} Hz", pitch);
+    }
+
+    #[test]
+    fn speeding_up_shortens_without_raising_the_pitch() {
+        let out = read_all(&mut TimeStretch::new(tone(1.0), 0, 2.0));
+        assert!((3500..=4500).contains(&out.len()), "{} samples", out.len());
+        let pitch = frequency(&out[500..out.len() - 500]);
+        assert!((190.0..=210.0).contains(&pitch), "{} Hz", pitch);
+    }
+
+    #[test]
+    fn starting_later_plays_less() {
+        let out = read_all(&mut TimeStretch::new(tone(1.0), RATE as usize / 2, 1.5));
+        assert!((2300..=3000).contains(&out.len()), "{} samples", out.len());
+    }
+
+    #[test]
+    fn waits_for_audio_that_hasnt_arrived() {
+        let stream = AudioStream::new(1, RATE);
+        let mut stretch = TimeStretch::new(stream.clone(), 0, 1.5);
+        assert!(matches!(stretch.read(), SampleRead::Pending));
+        stream.finish();
+        // An empty clip only fades out an empty tail
+        assert!(read_all(&mut stretch).iter().all(|sample| *sample == 0));
+        assert!(matches!(stretch.read(), SampleRead::End));
+    }
+}
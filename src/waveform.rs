@@ -0,0 +1,65 @@
+use mongodb::bson::{spec::BinarySubtype, Binary};
+use serde::{Deserialize, Serialize};
+
+use crate::audio_codec::PcmAudio;
+
+pub const WAVEFORM_BUCKETS: usize = 200;
+
+// A small min/max summary of a note's audio, enough to draw it on a card
+// without downloading the audio itself.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Waveform {
+    // Each bucket's minimum then maximum, scaled to i8 and stored as bytes
+    peaks: Binary,
+}
+
+impl Waveform {
+    // Never more buckets than the clip has frames, so none of them is padding.
+    pub fn from_audio(audio: &PcmAudio, buckets: usize) -> Waveform {
+        let peaks = audio
+            .ranges(buckets.min(audio.frames()))
+            .into_iter()
+            .flat_map(|(min, max)| [to_byte(min), to_byte(max)])
+            .collect();
+        Waveform { peaks: Binary { subtype: BinarySubtype::Generic, bytes: peaks } }
+    }
+
+    // (min, max) of each bucket, from -1.0 to 1.0
+    pub fn buckets(&self) -> impl Iterator<Item = (f32, f32)> + '_ {
+        self.peaks.bytes.chunks_exact(2).map(|pair| (pair[0] as i8 as f32 / 127.0, pair[1] as i8 as f32 / 127.0))
+    }
+
+    pub fn len(&self) -> usize {
+        self.peaks.bytes.len() / 2
+    }
+}
+
+fn to_byte(sample: i16) -> u8 {
+    ((sample as i32 * 127) / i16::MAX as i32).clamp(-127, 127) as i8 as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_hold_each_stretch_of_the_clip() {
+        let mut samples = vec![i16::MAX; 50];
+        samples.extend(vec![i16::MIN / 2; 50]);
+        let audio = PcmAudio { samples, channels: 1, sample_rate: 1000 };
+        let waveform = Waveform::from_audio(&audio, 2);
+        assert_eq!(waveform.len(), 2);
+        let buckets: Vec<(f32, f32)> = waveform.buckets().collect();
+        assert_eq!(buckets[0], (1.0, 1.0));
+        assert!((buckets[1].0 + 0.5).abs() < 0.01 && (buckets[1].1 + 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn clips_shorter_than_the_buckets_get_a_bucket_per_frame() {
+        let audio = PcmAudio { samples: vec![i16::MAX, i16::MIN, 0], channels: 1, sample_rate: 1000 };
+        let waveform = Waveform::from_audio(&audio, WAVEFORM_BUCKETS);
+        assert_eq!(waveform.buckets().collect::<Vec<_>>(), vec![(1.0, 1.0), (-1.0, -1.0), (0.0, 0.0)]);
+        let empty = Waveform::from_audio(&PcmAudio { samples: Vec::new(), channels: 1, sample_rate: 1000 }, 10);
+        assert_eq!(empty.len(), 0);
+    }
+}